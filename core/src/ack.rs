@@ -0,0 +1,114 @@
+use crate::packet::PacketError;
+
+// First byte of a fragment acknowledgement, distinct from the packet header and time sync magic.
+const ACK_MAGIC:u8 = 0xC0;
+const ACK_VERSION:u8 = 1;
+const MAGIC_MASK:u8 = 0xF0;
+const ACK_HEADER_LENGTH:usize = 9;
+const LINK_ENTRY_LENGTH:usize = 18;
+const COMPLETE_FLAG:u8 = 0x01;
+const EARLY_FLAG:u8 = 0x02;
+
+/// What rx saw of one frame on one link.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct LinkArrival {
+    pub received: u16,  // fragments received on the link
+    pub send_time: f64, // tx send time echoed from the latest fragment on the link, 0 without timestamps
+    pub hold: f64,      // time between that fragment's arrival and this ack leaving rx
+}
+
+/// Fragment-level acknowledgement of a frame, sent by rx to the pong port.
+///
+/// Layout (little-endian):
+/// `| magic|ver (1) | flags (1) | seq (4) | count (2) | link_num (1) | bitmap (count / 8, rounded up) | links |`
+///
+/// Bit `i` of the bitmap (LSB first) is set once fragment `i` arrived; `count` covers the highest
+/// offset seen, or the whole frame once its last fragment arrived. Each link entry is
+/// `| received (2) | send_time (8, f64) | hold (8, f64) |`. Flag bit 0 marks a complete frame, bit 1
+/// an early ack sent as soon as a gap showed, while fragments may still be in flight on other links.
+#[derive(Debug, Clone, PartialEq)]
+pub struct FragmentAck {
+    pub seq: u32,
+    pub complete: bool,
+    pub early: bool,
+    pub received: Vec<bool>,      // indexed by fragment offset
+    pub links: Vec<LinkArrival>,  // indexed by link id
+}
+
+impl FragmentAck {
+    pub fn is_fragment_ack(buf: &[u8]) -> bool {
+        buf.first().is_some_and(|b| b & MAGIC_MASK == ACK_MAGIC)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        ACK_HEADER_LENGTH + self.received.len().div_ceil(8) + self.links.len() * LINK_ENTRY_LENGTH
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, PacketError> {
+        let total = self.encoded_len();
+        if buf.len() < total || self.received.len() > u16::MAX as usize || self.links.len() > u8::MAX as usize {
+            return Err(PacketError::TooShort { expected: total, actual: buf.len() });
+        }
+        buf[0] = ACK_MAGIC | ACK_VERSION;
+        buf[1] = if self.complete { COMPLETE_FLAG } else { 0 } | if self.early { EARLY_FLAG } else { 0 };
+        buf[2..6].copy_from_slice(&self.seq.to_le_bytes());
+        buf[6..8].copy_from_slice(&(self.received.len() as u16).to_le_bytes());
+        buf[8] = self.links.len() as u8;
+
+        let bitmap_len = self.received.len().div_ceil(8);
+        let bitmap = &mut buf[ACK_HEADER_LENGTH..ACK_HEADER_LENGTH + bitmap_len];
+        bitmap.fill(0);
+        self.received.iter().enumerate().filter(|(_, &received)| received).for_each(|(i, _)| {
+            bitmap[i / 8] |= 1 << (i % 8);
+        });
+
+        let mut pos = ACK_HEADER_LENGTH + bitmap_len;
+        for link in &self.links {
+            buf[pos..pos + 2].copy_from_slice(&link.received.to_le_bytes());
+            buf[pos + 2..pos + 10].copy_from_slice(&link.send_time.to_le_bytes());
+            buf[pos + 10..pos + 18].copy_from_slice(&link.hold.to_le_bytes());
+            pos += LINK_ENTRY_LENGTH;
+        }
+        Ok(total)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, PacketError> {
+        let first = *buf.first().ok_or(PacketError::TooShort { expected: 1, actual: 0 })?;
+        if first & MAGIC_MASK != ACK_MAGIC {
+            return Err(PacketError::BadMagic(first));
+        }
+        if first & !MAGIC_MASK != ACK_VERSION {
+            return Err(PacketError::UnsupportedVersion(first & !MAGIC_MASK));
+        }
+        if buf.len() < ACK_HEADER_LENGTH {
+            return Err(PacketError::TooShort { expected: ACK_HEADER_LENGTH, actual: buf.len() });
+        }
+        let complete = buf[1] & COMPLETE_FLAG != 0;
+        let early = buf[1] & EARLY_FLAG != 0;
+        let seq = u32::from_le_bytes(buf[2..6].try_into().unwrap());
+        let count = u16::from_le_bytes(buf[6..8].try_into().unwrap()) as usize;
+        let link_num = buf[8] as usize;
+        let bitmap_len = count.div_ceil(8);
+        let total = ACK_HEADER_LENGTH + bitmap_len + link_num * LINK_ENTRY_LENGTH;
+        if buf.len() < total {
+            return Err(PacketError::TooShort { expected: total, actual: buf.len() });
+        }
+
+        let bitmap = &buf[ACK_HEADER_LENGTH..ACK_HEADER_LENGTH + bitmap_len];
+        let received = (0..count).map(|i| bitmap[i / 8] & (1 << (i % 8)) != 0).collect();
+        let links = (0..link_num).map(|i| {
+            let pos = ACK_HEADER_LENGTH + bitmap_len + i * LINK_ENTRY_LENGTH;
+            LinkArrival {
+                received: u16::from_le_bytes(buf[pos..pos + 2].try_into().unwrap()),
+                send_time: f64::from_le_bytes(buf[pos + 2..pos + 10].try_into().unwrap()),
+                hold: f64::from_le_bytes(buf[pos + 10..pos + 18].try_into().unwrap()),
+            }
+        }).collect();
+        Ok(FragmentAck { seq, complete, early, received, links })
+    }
+
+    /// Offsets below `num` not yet received, counting those beyond the bitmap.
+    pub fn missing(&self, num: usize) -> impl Iterator<Item = usize> + '_ {
+        (0..num).filter(|&i| !self.received.get(i).copied().unwrap_or(false))
+    }
+}
@@ -0,0 +1,102 @@
+use crate::packet::PacketError;
+
+pub fn now_secs_f64() -> f64 {
+    std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64()
+}
+
+// First byte of a time sync message, distinct from the packet header magic.
+const SYNC_MAGIC:u8 = 0xB0;
+const SYNC_VERSION:u8 = 1;
+const MAGIC_MASK:u8 = 0xF0;
+pub const TIME_SYNC_LENGTH:usize = 46;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncKind {
+    Request,
+    Response,
+}
+
+/// NTP-style exchange carried between the tx pong socket and the rx data port.
+///
+/// Layout (46 bytes, little-endian):
+/// `| magic|ver (1) | kind (1) | seq (4) | t1 (8) | t2 (8) | t3 (8) | offset (8) | drift (8) |`
+///
+/// `t1` is stamped by tx on send, `t2`/`t3` by rx on receive/reply. Requests also carry the tx's
+/// current estimate of the rx clock offset (at `t1`) and drift, NaN while unknown.
+#[derive(Debug, Clone, Copy)]
+pub struct TimeSync {
+    pub kind: SyncKind,
+    pub seq: u32,
+    pub t1: f64,
+    pub t2: f64,
+    pub t3: f64,
+    pub offset: f64,
+    pub drift: f64,
+}
+
+impl TimeSync {
+    pub fn request(seq: u32, t1: f64, estimate: Option<(f64, f64)>) -> Self {
+        let (offset, drift) = estimate.unwrap_or((f64::NAN, f64::NAN));
+        TimeSync { kind: SyncKind::Request, seq, t1, t2: 0.0, t3: 0.0, offset, drift }
+    }
+
+    pub fn response(&self, t2: f64, t3: f64) -> Self {
+        TimeSync { kind: SyncKind::Response, t2, t3, ..*self }
+    }
+
+    pub fn estimate(&self) -> Option<(f64, f64)> {
+        (self.offset.is_finite() && self.drift.is_finite()).then_some((self.offset, self.drift))
+    }
+
+    pub fn is_time_sync(buf: &[u8]) -> bool {
+        buf.first().is_some_and(|b| b & MAGIC_MASK == SYNC_MAGIC)
+    }
+
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, PacketError> {
+        if buf.len() < TIME_SYNC_LENGTH {
+            return Err(PacketError::TooShort { expected: TIME_SYNC_LENGTH, actual: buf.len() });
+        }
+        buf[0] = SYNC_MAGIC | SYNC_VERSION;
+        buf[1] = match self.kind { SyncKind::Request => 0, SyncKind::Response => 1 };
+        buf[2..6].copy_from_slice(&self.seq.to_le_bytes());
+        [self.t1, self.t2, self.t3, self.offset, self.drift].iter().enumerate().for_each(|(i, v)| {
+            buf[6 + i * 8..14 + i * 8].copy_from_slice(&v.to_le_bytes());
+        });
+        Ok(TIME_SYNC_LENGTH)
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self, PacketError> {
+        let first = *buf.first().ok_or(PacketError::TooShort { expected: 1, actual: 0 })?;
+        if first & MAGIC_MASK != SYNC_MAGIC {
+            return Err(PacketError::BadMagic(first));
+        }
+        if first & !MAGIC_MASK != SYNC_VERSION {
+            return Err(PacketError::UnsupportedVersion(first & !MAGIC_MASK));
+        }
+        if buf.len() < TIME_SYNC_LENGTH {
+            return Err(PacketError::TooShort { expected: TIME_SYNC_LENGTH, actual: buf.len() });
+        }
+        let kind = if buf[1] == 0 { SyncKind::Request } else { SyncKind::Response };
+        let seq = u32::from_le_bytes(buf[2..6].try_into().unwrap());
+        let field = |i: usize| f64::from_le_bytes(buf[6 + i * 8..14 + i * 8].try_into().unwrap());
+        Ok(TimeSync { kind, seq, t1: field(0), t2: field(1), t3: field(2), offset: field(3), drift: field(4) })
+    }
+}
+
+/// Maps rx timestamps into the tx clock domain from an offset/drift estimate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ClockCorrection {
+    offset: f64,       // rx clock - tx clock at `ref_rx_time`
+    drift: f64,        // seconds of offset gained per second
+    ref_rx_time: f64,
+}
+
+impl ClockCorrection {
+    pub fn new(offset: f64, drift: f64, ref_rx_time: f64) -> Self {
+        ClockCorrection { offset, drift, ref_rx_time }
+    }
+
+    pub fn to_tx_clock(&self, rx_time: f64) -> f64 {
+        rx_time - (self.offset + self.drift * (rx_time - self.ref_rx_time))
+    }
+}
@@ -0,0 +1,444 @@
+use std::cmp::{Ordering, Reverse};
+use std::collections::{BinaryHeap, VecDeque};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use log::trace;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+
+use crate::clock::now_secs_f64;
+use crate::transport::Transport;
+
+const fn _default_queue_packets() -> usize { 64 }
+const fn _default_loss_bad() -> f64 { 1.0 }
+
+// Bytes delivered per opportunity in a Mahimahi trace
+const TRACE_SLOT_BYTES: usize = 1500;
+
+// How long the delivery thread waits when idle or when the inner link would block
+const IDLE_POLL: Duration = Duration::from_millis(100);
+const BLOCKED_POLL: Duration = Duration::from_micros(100);
+
+/// One-way propagation delay added to every packet (seconds).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum DelayDistribution {
+    Constant { delay: f64 },
+    Uniform { min: f64, max: f64 },
+    Normal { mean: f64, std: f64 },   // truncated at zero
+    Exponential { base: f64, mean: f64 }, // `base` plus an exponential tail
+}
+
+impl DelayDistribution {
+    fn sample(&self, rng: &mut StdRng) -> f64 {
+        let delay = match *self {
+            Self::Constant { delay } => delay,
+            Self::Uniform { min, max } => if max > min { rng.gen_range(min..max) } else { min },
+            Self::Normal { mean, std } => {
+                // Box-Muller transform
+                let (u1, u2): (f64, f64) = (1.0 - rng.gen::<f64>(), rng.gen());
+                mean + std * (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos()
+            }
+            Self::Exponential { base, mean } => base - mean * (1.0 - rng.gen::<f64>()).ln(),
+        };
+        delay.max(0.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "type")]
+pub enum LossModel {
+    Bernoulli { p: f64 },
+    /// Two-state Markov chain, `p_gb`/`p_bg` are the per-packet transition probabilities.
+    GilbertElliott {
+        p_gb: f64,
+        p_bg: f64,
+        #[serde(default)] loss_good: f64,
+        #[serde(default = "_default_loss_bad")] loss_bad: f64,
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReorderParams {
+    pub probability: f64,
+    pub extra_delay: f64, // held back this long so later packets overtake it (seconds)
+}
+
+/// Impairments applied by an `EmulatedTransport`, given per link in the manifest.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EmulationParams {
+    #[serde(default)] pub delay: Option<DelayDistribution>,
+    #[serde(default)] pub loss: Option<LossModel>,
+    #[serde(default)] pub reorder: Option<ReorderParams>,
+    #[serde(default)] pub rate_mbps: f64,      //default: 0.0, unlimited
+    #[serde(default)] pub trace: Option<String>, //default: none, Mahimahi delivery trace replacing `rate_mbps`
+    #[serde(default = "_default_queue_packets")]
+    pub queue_packets: usize,                   //default: 64, bottleneck queue size
+    #[serde(default)] pub seed: Option<u64>,   //default: from entropy
+    #[serde(skip)] pub loaded_trace: Option<DeliveryTrace>, // `trace` once read, as by the manifest validation
+}
+
+/// Mahimahi-style delivery trace: each line is a millisecond timestamp at which one
+/// `TRACE_SLOT_BYTES` delivery opportunity occurs. The trace repeats with the last timestamp as its period.
+#[derive(Debug, Clone)]
+pub struct DeliveryTrace {
+    slots: Vec<u64>,
+    period_ms: u64,
+}
+
+impl DeliveryTrace {
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let slots = content.lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|line| line.parse::<u64>().map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e)))
+            .collect::<Result<Vec<_>, _>>()?;
+        match slots.last() {
+            Some(&period_ms) if period_ms > 0 => Ok(DeliveryTrace { slots, period_ms }),
+            _ => Err(std::io::Error::new(ErrorKind::InvalidData, "delivery trace must end with a positive timestamp")),
+        }
+    }
+
+    /// Offset of the `index`-th opportunity from the trace start, counting across repetitions (seconds).
+    fn slot_time(&self, index: usize) -> f64 {
+        let (loops, slot) = (index / self.slots.len(), index % self.slots.len());
+        (loops as u64 * self.period_ms + self.slots[slot]) as f64 / 1e3
+    }
+}
+
+// Position in a delivery trace, anchored at the first packet sent on the link
+struct TraceCursor {
+    trace: DeliveryTrace,
+    start: Option<f64>,
+    index: usize,
+    bytes_left: usize, // unused bytes of the current opportunity
+}
+
+impl TraceCursor {
+    /// Serve `size` bytes arriving at `now` behind the queued ones, returning when the last byte leaves.
+    fn serve(&mut self, now: f64, size: usize) -> f64 {
+        let start = *self.start.get_or_insert(now);
+        // Opportunities passing while the queue is empty are wasted
+        if start + self.trace.slot_time(self.index) < now {
+            while start + self.trace.slot_time(self.index) < now {
+                self.index += 1;
+            }
+            self.bytes_left = TRACE_SLOT_BYTES;
+        }
+        let mut remains = size;
+        loop {
+            let served = remains.min(self.bytes_left);
+            (remains, self.bytes_left) = (remains - served, self.bytes_left - served);
+            if remains == 0 {
+                return start + self.trace.slot_time(self.index);
+            }
+            self.index += 1;
+            self.bytes_left = TRACE_SLOT_BYTES;
+        }
+    }
+}
+
+struct Scheduled {
+    deliver_at: f64,
+    order: u64,
+    buf: Vec<u8>,
+    frame_end: Option<u32>,
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+impl Eq for Scheduled {}
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+impl Ord for Scheduled {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.deliver_at.total_cmp(&other.deliver_at).then(self.order.cmp(&other.order))
+    }
+}
+
+/// Wraps a link with a bottleneck queue, loss, delay and reordering.
+///
+/// Packets are timed on `send` and handed to the inner transport by a delivery thread once due.
+/// With `rate_mbps` or a `trace` set, `send` returns `WouldBlock` while `queue_packets` are waiting on the bottleneck.
+pub struct EmulatedTransport {
+    params: EmulationParams,
+    rng: StdRng,
+    trace: Option<TraceCursor>,
+    tx: Option<flume::Sender<Scheduled>>,
+    handle: Option<JoinHandle<()>>,
+    departures: VecDeque<f64>, // bottleneck departure times of the queued packets
+    last_departure: f64,
+    last_delivery: f64,
+    bad_state: bool,
+    order: u64,
+}
+
+impl EmulatedTransport {
+    /// Wrap `inner`, reading the delivery trace unless `loaded_trace` already holds it.
+    pub fn new(params: EmulationParams, inner: Box<dyn Transport>) -> std::io::Result<Self> {
+        let rng = match params.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let trace = match (&params.loaded_trace, &params.trace) {
+            (Some(trace), _) => Some(trace.clone()),
+            (None, Some(path)) => Some(DeliveryTrace::load(path)?),
+            (None, None) => None,
+        };
+        let trace = trace.map(|trace| TraceCursor { trace, start: None, index: 0, bytes_left: TRACE_SLOT_BYTES });
+        let (tx, rx) = flume::unbounded();
+        let handle = thread::spawn(move || delivery_thread(inner, rx));
+        Ok(EmulatedTransport {
+            params, rng, trace, tx: Some(tx), handle: Some(handle),
+            departures: VecDeque::new(), last_departure: 0.0, last_delivery: 0.0, bad_state: false, order: 0,
+        })
+    }
+
+    fn is_lost(&mut self) -> bool {
+        match self.params.loss {
+            None => false,
+            Some(LossModel::Bernoulli { p }) => self.rng.gen_bool(p.clamp(0.0, 1.0)),
+            Some(LossModel::GilbertElliott { p_gb, p_bg, loss_good, loss_bad }) => {
+                let flip = if self.bad_state { p_bg } else { p_gb };
+                if self.rng.gen_bool(flip.clamp(0.0, 1.0)) {
+                    self.bad_state = !self.bad_state;
+                }
+                let p = if self.bad_state { loss_bad } else { loss_good };
+                self.rng.gen_bool(p.clamp(0.0, 1.0))
+            }
+        }
+    }
+}
+
+impl Transport for EmulatedTransport {
+    fn send(&mut self, buf: &[u8], frame_end: Option<u32>) -> std::io::Result<()> {
+        let now = now_secs_f64();
+        while self.departures.front().is_some_and(|&t| t <= now) {
+            self.departures.pop_front();
+        }
+
+        // Bottleneck: serialize behind the packets already queued
+        let departure = if self.trace.is_some() || self.params.rate_mbps > 0.0 {
+            if self.departures.len() >= self.params.queue_packets {
+                return Err(ErrorKind::WouldBlock.into());
+            }
+            let departure = match self.trace {
+                Some(ref mut cursor) => cursor.serve(now, buf.len()),
+                None => self.last_departure.max(now) + (buf.len() * 8) as f64 / (self.params.rate_mbps * 1e6),
+            };
+            self.departures.push_back(departure);
+            self.last_departure = departure;
+            departure
+        } else {
+            now
+        };
+
+        // A lost packet still took its slot on the bottleneck
+        if self.is_lost() {
+            trace!("Emulated loss of {} bytes", buf.len());
+            return Ok(());
+        }
+
+        let delay = match self.params.delay {
+            Some(ref delay) => delay.sample(&mut self.rng),
+            None => 0.0,
+        };
+        let reorder = self.params.reorder.as_ref()
+            .filter(|r| self.rng.gen_bool(r.probability.clamp(0.0, 1.0)))
+            .map(|r| r.extra_delay);
+        let deliver_at = match reorder {
+            Some(extra_delay) => departure + delay + extra_delay,
+            None => {
+                // Jitter alone keeps packets in order
+                self.last_delivery = (departure + delay).max(self.last_delivery);
+                self.last_delivery
+            }
+        };
+
+        self.order += 1;
+        let scheduled = Scheduled { deliver_at, order: self.order, buf: buf.to_vec(), frame_end };
+        match self.tx {
+            Some(ref tx) => tx.send(scheduled).map_err(|_| ErrorKind::BrokenPipe.into()),
+            None => Err(ErrorKind::BrokenPipe.into()),
+        }
+    }
+}
+
+impl Drop for EmulatedTransport {
+    fn drop(&mut self) {
+        // Let the delivery thread drain what is still in flight
+        self.tx.take();
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+}
+
+fn delivery_thread(mut inner: Box<dyn Transport>, rx: flume::Receiver<Scheduled>) {
+    let mut pending: BinaryHeap<Reverse<Scheduled>> = BinaryHeap::new();
+    let mut closed = false;
+    loop {
+        let now = now_secs_f64();
+        let mut blocked = false;
+        while pending.peek().is_some_and(|Reverse(s)| s.deliver_at <= now) {
+            let Reverse(scheduled) = pending.pop().unwrap();
+            match inner.send(&scheduled.buf, scheduled.frame_end) {
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    pending.push(Reverse(scheduled));
+                    blocked = true;
+                    break;
+                }
+                Err(e) => trace!("Emulated link dropped a packet: {}", e),
+                Ok(_) => {}
+            }
+        }
+        let _ = inner.flush();
+
+        let wait = match pending.peek() {
+            _ if blocked => BLOCKED_POLL,
+            Some(Reverse(s)) => Duration::from_secs_f64((s.deliver_at - now).max(0.0)),
+            None if closed => break,
+            None => IDLE_POLL,
+        };
+        if closed {
+            thread::sleep(wait);
+            continue;
+        }
+        match rx.recv_timeout(wait) {
+            Ok(scheduled) => pending.push(Reverse(scheduled)),
+            Err(flume::RecvTimeoutError::Timeout) => {}
+            Err(flume::RecvTimeoutError::Disconnected) => closed = true,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::SocketAddr;
+    use crate::transport::{loopback, LoopbackRecvTransport, RecvTransport};
+    use super::*;
+
+    fn params() -> EmulationParams {
+        let mut params: EmulationParams = serde_json::from_str("{}").unwrap();
+        params.seed = Some(7);
+        params
+    }
+
+    fn link(params: EmulationParams) -> (EmulatedTransport, LoopbackRecvTransport) {
+        let src_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+        let (tx, rx) = loopback(10_000, src_addr);
+        (EmulatedTransport::new(params, Box::new(tx)).unwrap(), rx)
+    }
+
+    // Send packets numbered 0..count, then everything the link delivers, in order of arrival
+    fn run(params: EmulationParams, count: u32) -> Vec<u32> {
+        let (mut emulated, mut rx) = link(params);
+        for i in 0..count {
+            emulated.send(&i.to_le_bytes(), None).unwrap();
+        }
+        // Dropping waits for the packets still in flight
+        drop(emulated);
+        let mut buf = [0u8; 4];
+        let mut received = Vec::new();
+        while rx.recv(&mut buf).is_ok() {
+            received.push(u32::from_le_bytes(buf));
+        }
+        received
+    }
+
+    #[test]
+    fn unimpaired_link_delivers_everything_in_order() {
+        assert_eq!(run(params(), 100), (0..100).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bernoulli_loss_drops_its_share() {
+        let mut lossy = params();
+        lossy.loss = Some(LossModel::Bernoulli { p: 0.3 });
+        let received = run(lossy, 2000);
+        assert!((1300..1500).contains(&received.len()), "{} received", received.len());
+        assert!(received.windows(2).all(|pair| pair[0] < pair[1]));
+
+        lossy = params();
+        lossy.loss = Some(LossModel::Bernoulli { p: 1.0 });
+        assert!(run(lossy, 100).is_empty());
+    }
+
+    #[test]
+    fn gilbert_elliott_loses_bursts_in_the_bad_state() {
+        let mut bursty = params();
+        bursty.loss = Some(LossModel::GilbertElliott { p_gb: 0.05, p_bg: 0.2, loss_good: 0.0, loss_bad: 1.0 });
+        let received = run(bursty, 2000);
+        // Bad a fifth of the time on average, and losses come in runs
+        assert!((1400..1800).contains(&received.len()), "{} received", received.len());
+        let gaps = received.windows(2).filter(|pair| pair[1] - pair[0] > 2).count();
+        assert!(gaps > 0);
+    }
+
+    #[test]
+    fn jitter_alone_keeps_order_and_reordering_breaks_it() {
+        let mut jittery = params();
+        jittery.delay = Some(DelayDistribution::Uniform { min: 0.0, max: 0.005 });
+        let received = run(jittery.clone(), 200);
+        assert_eq!(received, (0..200).collect::<Vec<_>>());
+
+        jittery.reorder = Some(ReorderParams { probability: 0.2, extra_delay: 0.01 });
+        let mut received = run(jittery, 200);
+        assert_ne!(received, (0..200).collect::<Vec<_>>());
+        received.sort();
+        assert_eq!(received, (0..200).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn full_bottleneck_would_block() {
+        let mut narrow = params();
+        (narrow.rate_mbps, narrow.queue_packets) = (1.0, 4);
+        let (mut emulated, _rx) = link(narrow);
+        for _ in 0..4 {
+            emulated.send(&[0u8; 1000], None).unwrap();
+        }
+        let blocked = emulated.send(&[0u8; 1000], None).unwrap_err();
+        assert_eq!(blocked.kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn trace_cursor_serves_opportunities_in_turn() {
+        // Opportunities at 1, 2 and 4 ms, repeating every 4 ms
+        let trace = DeliveryTrace { slots: vec![1, 2, 4], period_ms: 4 };
+        let mut cursor = TraceCursor { trace, start: None, index: 0, bytes_left: TRACE_SLOT_BYTES };
+        let close = |a: f64, b: f64| (a - b).abs() < 1e-9;
+        assert!(close(cursor.serve(0.0, 1000), 0.001));
+        // The rest of the first opportunity, then the next one
+        assert!(close(cursor.serve(0.0, 1000), 0.002));
+        assert!(close(cursor.serve(0.0, 3000), 0.005));
+        // Opportunities passing while idle are lost
+        assert!(close(cursor.serve(0.1, 100), 0.1));
+        assert!(close(cursor.serve(0.1, 1500), 0.101));
+    }
+
+    #[test]
+    fn delivery_trace_load_checks_its_content() {
+        let path = std::env::temp_dir().join(format!("delivery-trace-{}.txt", std::process::id()));
+        for (content, valid) in [("1\n2\n\n4\n", true), ("", false), ("0\n", false), ("1\nx\n", false)] {
+            std::fs::write(&path, content).unwrap();
+            assert_eq!(DeliveryTrace::load(&path).is_ok(), valid, "{:?}", content);
+        }
+        let _ = std::fs::remove_file(&path);
+
+        // A trace not loaded beforehand is read by the link, and a missing one fails it
+        let (tx, _rx) = loopback(1, "127.0.0.1:0".parse().unwrap());
+        let missing = EmulationParams { trace: Some(path.display().to_string()), ..params() };
+        assert!(EmulatedTransport::new(missing, Box::new(tx)).is_err());
+    }
+}
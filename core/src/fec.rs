@@ -0,0 +1,327 @@
+use serde::{Deserialize, Serialize};
+use crate::packet::MAX_PAYLOAD_LEN;
+
+// Systematic Reed-Solomon over GF(256) is limited to this many source and parity fragments
+pub const MAX_RS_FRAGMENTS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
+pub enum FecScheme {
+    XOR,
+    ReedSolomon,
+}
+
+impl FecScheme {
+    pub fn to_byte(scheme: Option<FecScheme>) -> u8 {
+        match scheme {
+            None => 0,
+            Some(FecScheme::XOR) => 1,
+            Some(FecScheme::ReedSolomon) => 2,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Option<FecScheme> {
+        match byte {
+            1 => Some(FecScheme::XOR),
+            2 => Some(FecScheme::ReedSolomon),
+            _ => None,
+        }
+    }
+}
+
+/// FEC layout of a frame, carried by every fragment of it (header V3 onwards).
+///
+/// Source fragments take offsets `0..source_num`, parity fragments the `parity_num` offsets after them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct FecHeader {
+    pub scheme: Option<FecScheme>,
+    pub source_num: u16,
+    pub parity_num: u8,
+    pub last_len: u16, // payload length of the last source fragment
+}
+
+impl FecHeader {
+    pub fn fragment_num(&self) -> usize {
+        self.source_num as usize + self.parity_num as usize
+    }
+
+    /// Whether the layout is one the tx sends, with fragment `offset` in it, so that recovery
+    /// stays within bounds.
+    pub fn fits(&self, offset: u16, is_last: bool) -> bool {
+        let (offset, num) = (offset as usize, self.fragment_num());
+        let bounded = self.source_num > 0 && self.parity_num > 0 && self.last_len as usize <= MAX_PAYLOAD_LEN
+            && offset < num && (!is_last || offset + 1 == num);
+        match self.scheme {
+            None => true,
+            Some(FecScheme::XOR) => bounded && self.parity_num as u16 <= self.source_num,
+            Some(FecScheme::ReedSolomon) => bounded && num <= MAX_RS_FRAGMENTS,
+        }
+    }
+
+    /// Whether the source fragments can be rebuilt from the fragments `present`, indexed by offset.
+    pub fn recoverable(&self, present: &[bool]) -> bool {
+        let (k, m) = (self.source_num as usize, self.parity_num as usize);
+        let has = |i: usize| present.get(i).copied().unwrap_or(false);
+        match self.scheme {
+            None => (0..k).all(has),
+            // Every group misses at most one source, and then has its parity
+            Some(FecScheme::XOR) => (0..m.max(1)).all(|group| {
+                let missing = (group..k).step_by(m.max(1)).filter(|&i| !has(i)).count();
+                missing == 0 || (missing == 1 && has(k + group))
+            }),
+            Some(FecScheme::ReedSolomon) => (0..k + m).filter(|&i| has(i)).count() >= k,
+        }
+    }
+
+    /// Parity fragments of `sources`, each as long as the longest source (shorter ones are zero padded).
+    pub fn encode(&self, sources: &[&[u8]]) -> Vec<Vec<u8>> {
+        let (k, m) = (sources.len(), self.parity_num as usize);
+        let shard_len = sources.iter().map(|s| s.len()).max().unwrap_or(0);
+        let mut parity = vec![vec![0u8; shard_len]; m];
+        for (i, shard) in parity.iter_mut().enumerate() {
+            for (j, source) in sources.iter().enumerate() {
+                match self.scheme {
+                    Some(FecScheme::XOR) if j % m == i => xor_into(shard, source),
+                    Some(FecScheme::ReedSolomon) => mul_add_into(shard, source, cauchy(k, i, j)),
+                    _ => {}
+                }
+            }
+        }
+        parity
+    }
+
+    /// Rebuild the missing source fragments in `shards` (sources then parity, `None` when lost);
+    /// returns false, leaving `shards` as they are, when too many are missing.
+    pub fn recover(&self, shards: &mut [Option<Vec<u8>>]) -> bool {
+        let (k, m) = (self.source_num as usize, self.parity_num as usize);
+        let present: Vec<bool> = shards.iter().map(|s| s.is_some()).collect();
+        if shards.len() < k + m || !self.recoverable(&present) {
+            return false;
+        }
+        let missing: Vec<usize> = (0..k).filter(|&j| !present[j]).collect();
+        if missing.is_empty() {
+            return true;
+        }
+        let shard_len = shards.iter().flatten().map(|s| s.len()).max().unwrap_or(0);
+        let source = |shards: &[Option<Vec<u8>>], j: usize| {
+            let mut s = shards[j].clone().unwrap_or_default();
+            s.resize(shard_len, 0);
+            s
+        };
+
+        match self.scheme {
+            Some(FecScheme::XOR) => {
+                for &j in &missing {
+                    let group = j % m;
+                    let mut shard = source(shards, k + group);
+                    for i in (group..k).step_by(m).filter(|&i| i != j) {
+                        xor_into(&mut shard, &source(shards, i));
+                    }
+                    shards[j] = Some(shard);
+                }
+            }
+            Some(FecScheme::ReedSolomon) => {
+                // Solve the missing sources from as many parity rows, less the known sources
+                let rows: Vec<usize> = (0..m).filter(|&i| present[k + i]).take(missing.len()).collect();
+                let matrix: Vec<Vec<u8>> = rows.iter()
+                    .map(|&i| missing.iter().map(|&j| cauchy(k, i, j)).collect())
+                    .collect();
+                let Some(inverse) = invert(matrix) else { return false };
+                let rhs: Vec<Vec<u8>> = rows.iter().map(|&i| {
+                    let mut shard = source(shards, k + i);
+                    for j in (0..k).filter(|&j| present[j]) {
+                        mul_add_into(&mut shard, &source(shards, j), cauchy(k, i, j));
+                    }
+                    shard
+                }).collect();
+                for (r, &j) in missing.iter().enumerate() {
+                    let mut shard = vec![0u8; shard_len];
+                    for (c, row) in rhs.iter().enumerate() {
+                        mul_add_into(&mut shard, row, inverse[r][c]);
+                    }
+                    shards[j] = Some(shard);
+                }
+            }
+            None => return false,
+        }
+        true
+    }
+}
+
+// GF(256) with the 0x11D polynomial
+const GF_TABLES: ([u8; 512], [u8; 256]) = gf_tables();
+
+const fn gf_tables() -> ([u8; 512], [u8; 256]) {
+    let (mut exp, mut log) = ([0u8; 512], [0u8; 256]);
+    let (mut x, mut i) = (1u16, 0);
+    while i < 255 {
+        exp[i] = x as u8;
+        exp[i + 255] = x as u8;
+        log[x as usize] = i as u8;
+        x <<= 1;
+        if x & 0x100 != 0 {
+            x ^= 0x11D;
+        }
+        i += 1;
+    }
+    (exp, log)
+}
+
+fn gf_mul(a: u8, b: u8) -> u8 {
+    if a == 0 || b == 0 {
+        return 0;
+    }
+    let (exp, log) = &GF_TABLES;
+    exp[log[a as usize] as usize + log[b as usize] as usize]
+}
+
+fn gf_inv(a: u8) -> u8 {
+    let (exp, log) = &GF_TABLES;
+    exp[255 - log[a as usize] as usize]
+}
+
+// Cauchy coefficient of source `j` in parity `i`; any `k` rows of identity and Cauchy rows are independent
+fn cauchy(k: usize, i: usize, j: usize) -> u8 {
+    gf_inv(((k + i) as u8) ^ (j as u8))
+}
+
+fn xor_into(dst: &mut [u8], src: &[u8]) {
+    dst.iter_mut().zip(src).for_each(|(d, s)| *d ^= s);
+}
+
+fn mul_add_into(dst: &mut [u8], src: &[u8], coef: u8) {
+    dst.iter_mut().zip(src).for_each(|(d, &s)| *d ^= gf_mul(coef, s));
+}
+
+// Gauss-Jordan inversion over GF(256)
+fn invert(mut matrix: Vec<Vec<u8>>) -> Option<Vec<Vec<u8>>> {
+    let n = matrix.len();
+    let mut inverse: Vec<Vec<u8>> = (0..n).map(|r| (0..n).map(|c| (r == c) as u8).collect()).collect();
+    for col in 0..n {
+        let pivot = (col..n).find(|&r| matrix[r][col] != 0)?;
+        matrix.swap(col, pivot);
+        inverse.swap(col, pivot);
+        let scale = gf_inv(matrix[col][col]);
+        matrix[col].iter_mut().for_each(|v| *v = gf_mul(*v, scale));
+        inverse[col].iter_mut().for_each(|v| *v = gf_mul(*v, scale));
+        for r in (0..n).filter(|&r| r != col) {
+            let factor = matrix[r][col];
+            if factor != 0 {
+                let (pivot_row, pivot_inv) = (matrix[col].clone(), inverse[col].clone());
+                mul_add_into(&mut matrix[r], &pivot_row, factor);
+                mul_add_into(&mut inverse[r], &pivot_inv, factor);
+            }
+        }
+    }
+    Some(inverse)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Sources of varying length, the last one short as in a real frame
+    fn sources(k: usize) -> Vec<Vec<u8>> {
+        (0..k).map(|j| {
+            let len = if j == k - 1 { 37 } else { 100 };
+            (0..len).map(|b| (j * 31 + b * 7 + 1) as u8).collect()
+        }).collect()
+    }
+
+    fn header(scheme: FecScheme, k: usize, m: usize) -> FecHeader {
+        FecHeader { scheme: Some(scheme), source_num: k as u16, parity_num: m as u8, last_len: 37 }
+    }
+
+    // Encode, erase the shards in `erased`, and check recovery against `recoverable`
+    fn round_trip(fec: &FecHeader, erased: &[usize]) -> bool {
+        let sources = sources(fec.source_num as usize);
+        let refs: Vec<&[u8]> = sources.iter().map(|s| s.as_slice()).collect();
+        let mut shards: Vec<Option<Vec<u8>>> = sources.iter().cloned().chain(fec.encode(&refs)).map(Some).collect();
+        for &i in erased {
+            shards[i] = None;
+        }
+        let present: Vec<bool> = shards.iter().map(|s| s.is_some()).collect();
+        let before = shards.clone();
+
+        let recovered = fec.recover(&mut shards);
+        assert_eq!(recovered, fec.recoverable(&present));
+        if !recovered {
+            assert_eq!(shards, before);
+            return false;
+        }
+        for (source, shard) in sources.iter().zip(&shards) {
+            let shard = shard.as_ref().unwrap();
+            assert_eq!(&shard[..source.len()], source.as_slice());
+            assert!(shard[source.len()..].iter().all(|&b| b == 0));
+        }
+        true
+    }
+
+    // Every subset of `0..n` with at most `max` elements
+    fn subsets(n: usize, max: usize) -> Vec<Vec<usize>> {
+        (0u32..1 << n).filter(|set| set.count_ones() as usize <= max)
+            .map(|set| (0..n).filter(|&i| set & (1 << i) != 0).collect())
+            .collect()
+    }
+
+    #[test]
+    fn reed_solomon_recovers_any_parity_erasures() {
+        for (k, m) in [(1, 1), (4, 2), (6, 3), (10, 4)] {
+            let fec = header(FecScheme::ReedSolomon, k, m);
+            for erased in subsets(k + m, m) {
+                assert!(round_trip(&fec, &erased), "k {} m {} erased {:?}", k, m, erased);
+            }
+        }
+    }
+
+    #[test]
+    fn reed_solomon_fails_beyond_parity() {
+        let fec = header(FecScheme::ReedSolomon, 6, 3);
+        for erased in subsets(9, 4).into_iter().filter(|erased| erased.len() == 4) {
+            assert!(!round_trip(&fec, &erased), "erased {:?}", erased);
+        }
+    }
+
+    #[test]
+    fn xor_recovers_one_erasure_per_group() {
+        let (k, m) = (7, 3);
+        let fec = header(FecScheme::XOR, k, m);
+        for erased in subsets(k + m, m) {
+            // Each group of sources `group, group + m, ..` and its parity `k + group` survives one loss
+            let per_group = |group: usize| erased.iter().filter(|&&i| if i < k { i % m == group } else { i == k + group }).count();
+            let expected = (0..m).all(|group| per_group(group) <= 1);
+            assert_eq!(round_trip(&fec, &erased), expected, "erased {:?}", erased);
+        }
+        assert!(!round_trip(&fec, &[0, 3]));
+        assert!(!round_trip(&fec, &[1, k + 1]));
+    }
+
+    #[test]
+    fn cauchy_rows_invert() {
+        for (k, m) in [(4, 4), (200, 56)] {
+            let matrix: Vec<Vec<u8>> = (0..m).map(|i| (0..m).map(|j| cauchy(k, i, j)).collect()).collect();
+            let inverse = invert(matrix.clone()).expect("Cauchy matrix is invertible");
+            for (r, row) in matrix.iter().enumerate() {
+                for c in 0..m {
+                    let dot = row.iter().zip(&inverse).fold(0u8, |acc, (&a, inverse_row)| acc ^ gf_mul(a, inverse_row[c]));
+                    assert_eq!(dot, (r == c) as u8);
+                }
+            }
+        }
+        assert!(invert(vec![vec![1, 2], vec![1, 2]]).is_none());
+    }
+
+    #[test]
+    fn fits_rejects_bad_layouts() {
+        assert!(header(FecScheme::ReedSolomon, 10, 4).fits(13, true));
+        assert!(!header(FecScheme::ReedSolomon, 10, 4).fits(12, true));
+        assert!(!header(FecScheme::ReedSolomon, 10, 4).fits(14, false));
+        assert!(!header(FecScheme::ReedSolomon, 250, 10).fits(0, false));
+        assert!(!header(FecScheme::ReedSolomon, 0, 2).fits(0, false));
+        assert!(!header(FecScheme::XOR, 4, 0).fits(0, false));
+        assert!(!header(FecScheme::XOR, 2, 3).fits(0, false));
+        let long_last = FecHeader { last_len: MAX_PAYLOAD_LEN as u16 + 1, ..header(FecScheme::XOR, 4, 2) };
+        assert!(!long_last.fits(0, false));
+        assert!(FecHeader::default().fits(99, false));
+    }
+}
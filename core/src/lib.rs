@@ -1,3 +1,8 @@
+pub mod ack;
+pub mod clock;
+pub mod emulator;
+pub mod fec;
 pub mod logger;
 pub mod packet;
 pub mod socket;
+pub mod transport;
@@ -1,11 +1,24 @@
 #![allow(dead_code)]
 use std::ops::{Deref, DerefMut};
+use serde::{Deserialize, Serialize};
+use crate::fec::{FecHeader, FecScheme};
 
 const IP_HEADER_LENGTH:usize = 20;
 const UDP_HEADER_LENGTH:usize = 8;
-pub const APP_HEADER_LENGTH:usize = 9;
+pub const APP_HEADER_LENGTH:usize = HeaderVersion::LATEST.header_len(); // the largest header we may emit
 pub const UDP_MAX_LENGTH:usize = 1500 - IP_HEADER_LENGTH - UDP_HEADER_LENGTH;
 pub const MAX_PAYLOAD_LEN:usize = UDP_MAX_LENGTH - APP_HEADER_LENGTH;
+pub const MAX_LINKS:usize = (LINK_ID_MASK as usize) + 1;
+
+// First header byte: high nibble is the magic, low nibble the header version.
+const HEADER_MAGIC:u8 = 0xA0;
+const MAGIC_MASK:u8 = 0xF0;
+
+// Indicator byte: low 6 bits are the link id, bit 6 marks a retransmission and the top bit
+// the last packet of a frame.
+const LINK_ID_MASK:u8 = 0x3F;
+const RETRANSMIT_FLAG:u8 = 0x40;
+const LAST_PACKET_FLAG:u8 = 0x80;
 
 pub type PacketSender   = flume::Sender<PacketStruct>;
 pub type PacketReceiver = flume::Receiver<PacketStruct>;
@@ -13,37 +26,128 @@ pub type PacketReceiver = flume::Receiver<PacketStruct>;
 pub type BufferSender = flume::Sender<Vec<u8>>;
 pub type BufferReceiver = flume::Receiver<Vec<u8>>;
 
-unsafe fn any_as_u8_slice<T: Sized>(p: &T) -> &[u8] {
-    ::std::slice::from_raw_parts(
-        (p as *const T) as *const u8,
-        ::std::mem::size_of::<T>(),
-    )
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PacketError {
+    TooShort { expected: usize, actual: usize },
+    BadMagic(u8),
+    UnsupportedVersion(u8),
+    PayloadTooLong(usize),
+}
+
+impl std::fmt::Display for PacketError {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::TooShort { expected, actual } => write!(f, "buffer too short: expected {} bytes, got {}", expected, actual),
+            Self::BadMagic(b) => write!(f, "bad header magic: {:#04x}", b),
+            Self::UnsupportedVersion(v) => write!(f, "unsupported header version: {}", v),
+            Self::PayloadTooLong(len) => write!(f, "payload length {} exceeds {}", len, MAX_PAYLOAD_LEN),
+        }
+    }
+}
+
+impl std::error::Error for PacketError {}
+
+/// Application header layout. The tx uses the manifest's `header_version`; the rx decodes
+/// whichever version arrives and echoes it back in its pongs.
+///
+/// V1 (10 bytes, little-endian):
+/// `| magic|ver (1) | seq (4) | offset (2) | length (2) | indicators (1) |`
+///
+/// V2 (26 bytes) appends the sender clock, in seconds since the unix epoch:
+/// `| V1 header (10) | arrival_time (8, f64) | send_time (8, f64) |`
+///
+/// V3 (32 bytes) appends the FEC layout of the frame:
+/// `| V2 header (26) | fec scheme (1) | source_num (2) | parity_num (1) | last_len (2) |`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum HeaderVersion {
+    V1,
+    #[default]
+    V2,
+    V3,
+}
+
+impl HeaderVersion {
+    pub const LATEST: HeaderVersion = HeaderVersion::V3;
+
+    pub const fn header_len(&self) -> usize {
+        match self {
+            HeaderVersion::V1 => 10,
+            HeaderVersion::V2 => 26,
+            HeaderVersion::V3 => 32,
+        }
+    }
+
+    pub fn has_timestamps(&self) -> bool {
+        !matches!(self, HeaderVersion::V1)
+    }
+
+    pub fn has_fec(&self) -> bool {
+        matches!(self, HeaderVersion::V3)
+    }
+
+    pub fn to_byte(self) -> u8 {
+        match self {
+            HeaderVersion::V1 => HEADER_MAGIC | 1,
+            HeaderVersion::V2 => HEADER_MAGIC | 2,
+            HeaderVersion::V3 => HEADER_MAGIC | 3,
+        }
+    }
+
+    pub fn from_byte(byte: u8) -> Result<Self, PacketError> {
+        if byte & MAGIC_MASK != HEADER_MAGIC {
+            return Err(PacketError::BadMagic(byte));
+        }
+        match byte & !MAGIC_MASK {
+            1 => Ok(HeaderVersion::V1),
+            2 => Ok(HeaderVersion::V2),
+            3 => Ok(HeaderVersion::V3),
+            v => Err(PacketError::UnsupportedVersion(v)),
+        }
+    }
 }
 
-#[derive(Clone)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum PacketType {
-    FirstLink,
-    SecondLink,
-    LastPacketInFirstLink,
-    LastPacketInSecondLink,
+    Link(u8),
+    LastPacketInLink(u8),
+}
+
+impl PacketType {
+    pub fn new(link: usize, is_last: bool) -> Self {
+        assert!(link < MAX_LINKS, "link id {} exceeds {} links", link, MAX_LINKS);
+        if is_last { PacketType::LastPacketInLink(link as u8) }
+        else { PacketType::Link(link as u8) }
+    }
+
+    pub fn link(&self) -> usize {
+        match self {
+            PacketType::Link(link) | PacketType::LastPacketInLink(link) => *link as usize,
+        }
+    }
+
+    pub fn is_last(&self) -> bool {
+        matches!(self, PacketType::LastPacketInLink(_))
+    }
 }
 
-#[repr(C,packed)]
 #[derive(Copy, Clone, Debug)]
 pub struct PacketStruct {
+    pub version: HeaderVersion,
     pub seq: u32,       //4 Bytes
     pub offset: u16,    //2 Bytes, how much left to send
     pub length: u16,    //2 Bytes
-    pub indicators: u8, //1 Byte, 0 - 1 represents the interface id, 10~19 represents the last packet of interface id 
+    pub indicators: u8, //1 Byte, bits 0-5 represent the interface id, bit 6 marks a retransmission, bit 7 the last packet of the frame
+    pub arrival_time: f64, //8 Bytes (V2), when the frame was handed to the sender
+    pub send_time: f64,    //8 Bytes (V2), when this fragment left the sender
+    pub fec: FecHeader,    //6 Bytes (V3), FEC layout of the frame
     pub payload: [u8; MAX_PAYLOAD_LEN]
 }
 
 #[derive(Copy, Clone, Debug)]
 pub struct PacketWithMeta {
     pub packet: PacketStruct,
-    pub port: u16,      
+    pub port: u16,
     pub num: usize,       // number of packets in the original datagram
-    pub arrival_time: f64,
     pub channel: usize,
     pub last_one: bool,
 }
@@ -61,21 +165,18 @@ impl DerefMut for PacketWithMeta {
 }
 
 impl PacketWithMeta {
-    pub fn new( port: u16 ) -> Self {
-        PacketWithMeta { 
-            packet: PacketStruct::new(), 
+    pub fn new( port: u16, version: HeaderVersion ) -> Self {
+        let mut packet = PacketStruct::new();
+        packet.version = version;
+        PacketWithMeta {
+            packet,
             port,
-            arrival_time: 0.0,
             num: 0,
             channel: 0,
             last_one: false,
         }
     }
 
-    pub fn to_u8_slice(&self) -> &[u8] {
-        unsafe { any_as_u8_slice(&self.packet) }
-    }
-
     pub fn next_seq(&mut self, num: usize) {
         self.num = num;
         self.packet.next_seq(num);
@@ -90,6 +191,24 @@ impl PacketWithMeta {
         self.packet.set_indicator(packet_type);
         self.channel = channel_info(self.packet.indicators) as usize;
     }
+
+    /// Fragments carrying the frame's data, excluding FEC parity.
+    pub fn source_num(&self) -> usize {
+        match self.fec.scheme {
+            Some(_) => self.fec.source_num as usize,
+            None => self.num,
+        }
+    }
+
+    pub fn is_parity(&self) -> bool {
+        self.offset as usize >= self.source_num()
+    }
+}
+
+impl Default for PacketStruct {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl PacketStruct {
@@ -97,7 +216,8 @@ impl PacketStruct {
         // dummy payload content from 0..MAX_PAYLOAD_LEN
         let mut payload = [0u8; MAX_PAYLOAD_LEN];
         (0..MAX_PAYLOAD_LEN).for_each(|i| payload[i] = i as u8);
-        PacketStruct { seq: 0, offset: 0, length: 0, indicators:0 , payload }
+        PacketStruct { version: HeaderVersion::default(), seq: 0, offset: 0, length: 0, indicators:0,
+                       arrival_time: 0.0, send_time: 0.0, fec: FecHeader::default(), payload }
     }
     pub fn set_length(&mut self, length: u16) {
         self.length = length;
@@ -114,41 +234,131 @@ impl PacketStruct {
         self.indicators = to_indicator(packet_type);
     }
 
+    pub fn set_retransmission(&mut self) {
+        self.indicators |= RETRANSMIT_FLAG;
+    }
+
+    pub fn is_retransmission(&self) -> bool {
+        self.indicators & RETRANSMIT_FLAG != 0
+    }
+
     pub fn set_payload(&mut self, payload: &[u8]) {
         self.payload[..payload.len()].copy_from_slice(payload);
     }
-}
 
-pub fn channel_info(indicator: u8) -> u8{
-    indicator & 0b00000001 // Get the last bit of the indicator
-}
+    pub fn encoded_len(&self) -> usize {
+        self.version.header_len() + self.length as usize
+    }
 
-pub fn from_buffer(buffer: &[u8]) -> PacketStruct {
-    // usafe to cast buffer to PacketStruct
-    unsafe {
-        let mut packet: PacketStruct = std::mem::zeroed();
-        let packet_ptr = &mut packet as *mut PacketStruct as *mut u8;
-        std::ptr::copy_nonoverlapping(buffer.as_ptr(), packet_ptr, std::mem::size_of::<PacketStruct>());
-        packet
+    /// Serialize header and the first `length` payload bytes into `buf`, returning the bytes written.
+    pub fn encode(&self, buf: &mut [u8]) -> Result<usize, PacketError> {
+        if self.length as usize > MAX_PAYLOAD_LEN {
+            return Err(PacketError::PayloadTooLong(self.length as usize));
+        }
+        let total = self.encoded_len();
+        if buf.len() < total {
+            return Err(PacketError::TooShort { expected: total, actual: buf.len() });
+        }
+
+        buf[0] = self.version.to_byte();
+        buf[1..5].copy_from_slice(&self.seq.to_le_bytes());
+        buf[5..7].copy_from_slice(&self.offset.to_le_bytes());
+        buf[7..9].copy_from_slice(&self.length.to_le_bytes());
+        buf[9] = self.indicators;
+        if self.version.has_timestamps() {
+            buf[10..18].copy_from_slice(&self.arrival_time.to_le_bytes());
+            buf[18..26].copy_from_slice(&self.send_time.to_le_bytes());
+        }
+        if self.version.has_fec() {
+            buf[26] = FecScheme::to_byte(self.fec.scheme);
+            buf[27..29].copy_from_slice(&self.fec.source_num.to_le_bytes());
+            buf[29] = self.fec.parity_num;
+            buf[30..32].copy_from_slice(&self.fec.last_len.to_le_bytes());
+        }
+
+        let header_len = self.version.header_len();
+        buf[header_len..total].copy_from_slice(&self.payload[..self.length as usize]);
+        Ok(total)
+    }
+
+    /// Parse a received datagram; never reads past `buf`.
+    pub fn decode(buf: &[u8]) -> Result<Self, PacketError> {
+        let first = *buf.first().ok_or(PacketError::TooShort { expected: 1, actual: 0 })?;
+        let version = HeaderVersion::from_byte(first)?;
+        let header_len = version.header_len();
+        if buf.len() < header_len {
+            return Err(PacketError::TooShort { expected: header_len, actual: buf.len() });
+        }
+
+        let seq = u32::from_le_bytes(buf[1..5].try_into().unwrap());
+        let offset = u16::from_le_bytes(buf[5..7].try_into().unwrap());
+        let length = u16::from_le_bytes(buf[7..9].try_into().unwrap());
+        let indicators = buf[9];
+        let (arrival_time, send_time) = if version.has_timestamps() {
+            (f64::from_le_bytes(buf[10..18].try_into().unwrap()), f64::from_le_bytes(buf[18..26].try_into().unwrap()))
+        } else {
+            (0.0, 0.0)
+        };
+        let fec = if version.has_fec() {
+            FecHeader {
+                scheme: FecScheme::from_byte(buf[26]),
+                source_num: u16::from_le_bytes(buf[27..29].try_into().unwrap()),
+                parity_num: buf[29],
+                last_len: u16::from_le_bytes(buf[30..32].try_into().unwrap()),
+            }
+        } else {
+            FecHeader::default()
+        };
+
+        if length as usize > MAX_PAYLOAD_LEN {
+            return Err(PacketError::PayloadTooLong(length as usize));
+        }
+        let total = header_len + length as usize;
+        if buf.len() < total {
+            return Err(PacketError::TooShort { expected: total, actual: buf.len() });
+        }
+
+        let mut payload = [0u8; MAX_PAYLOAD_LEN];
+        payload[..length as usize].copy_from_slice(&buf[header_len..total]);
+        Ok(PacketStruct { version, seq, offset, length, indicators, arrival_time, send_time, fec, payload })
     }
+
+    /// Build the pong acknowledging this fragment's frame, echoing the header version.
+    /// The payload carries one f64 per extra link: its arrival time relative to link 0.
+    pub fn to_pong(&self, deltas: &[f64]) -> PacketStruct {
+        let mut pong = *self;
+        let deltas = &deltas[..deltas.len().min(MAX_PAYLOAD_LEN / 8)];
+        pong.set_length((deltas.len() * 8) as u16);
+        deltas.iter().enumerate().for_each(|(i, delta)| {
+            pong.payload[i * 8..(i + 1) * 8].copy_from_slice(&delta.to_le_bytes());
+        });
+        pong
+    }
+
+    pub fn pong_deltas(&self) -> Vec<f64> {
+        self.payload[..self.length as usize]
+            .chunks_exact(8)
+            .map(|chunk| f64::from_le_bytes(chunk.try_into().unwrap()))
+            .collect()
+    }
+}
+
+pub fn channel_info(indicator: u8) -> u8{
+    indicator & LINK_ID_MASK // Get the link id bits of the indicator
 }
 
 pub fn to_indicator(packet_type: PacketType) -> u8 {
     match packet_type {
-        PacketType::FirstLink      =>  0b00000000,
-        PacketType::SecondLink      =>  0b00000001,
-        PacketType::LastPacketInFirstLink  =>  0b00000010,
-        PacketType::LastPacketInSecondLink  =>  0b00000011,
+        PacketType::Link(link)              =>  link & LINK_ID_MASK,
+        PacketType::LastPacketInLink(link)  =>  (link & LINK_ID_MASK) | LAST_PACKET_FLAG,
     }
 }
 
 pub fn get_packet_type(indicators: u8) -> PacketType {
-    match indicators {
-        0b00000000 => PacketType::FirstLink,      // FL
-        0b00000001 => PacketType::SecondLink,      // SL
-        0b00000010 => PacketType::LastPacketInFirstLink,  // LPinFL
-        0b00000011 => PacketType::LastPacketInSecondLink,  // LPinSL
-        _ => panic!("Invalid packet type")
+    let link = channel_info(indicators);
+    match indicators & LAST_PACKET_FLAG {
+        0 => PacketType::Link(link),
+        _ => PacketType::LastPacketInLink(link),
     }
 }
 
@@ -163,3 +373,107 @@ pub fn tos2ac(tos: u8) -> usize {
         _ => { panic!("Impossible ToS value.") }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(version: HeaderVersion) -> PacketStruct {
+        let mut packet = PacketStruct::new();
+        packet.version = version;
+        packet.seq = 0x01020304;
+        packet.offset = 7;
+        packet.set_length(100);
+        packet.set_indicator(PacketType::new(5, true));
+        packet.set_retransmission();
+        packet.arrival_time = 1.5;
+        packet.send_time = 2.25;
+        packet.fec = FecHeader { scheme: Some(FecScheme::ReedSolomon), source_num: 10, parity_num: 3, last_len: 42 };
+        packet
+    }
+
+    #[test]
+    fn round_trip_every_version() {
+        for version in [HeaderVersion::V1, HeaderVersion::V2, HeaderVersion::V3] {
+            let packet = sample(version);
+            let mut buf = [0u8; UDP_MAX_LENGTH];
+            let length = packet.encode(&mut buf).unwrap();
+            assert_eq!(length, version.header_len() + 100);
+
+            let decoded = PacketStruct::decode(&buf[..length]).unwrap();
+            assert_eq!(decoded.version, version);
+            assert_eq!((decoded.seq, decoded.offset, decoded.length, decoded.indicators), (packet.seq, 7, 100, packet.indicators));
+            assert_eq!(decoded.payload[..100], packet.payload[..100]);
+            assert_eq!(get_packet_type(decoded.indicators), PacketType::LastPacketInLink(5));
+            assert!(decoded.is_retransmission());
+            match version.has_timestamps() {
+                true => assert_eq!((decoded.arrival_time, decoded.send_time), (1.5, 2.25)),
+                false => assert_eq!((decoded.arrival_time, decoded.send_time), (0.0, 0.0)),
+            }
+            match version.has_fec() {
+                true => assert_eq!(decoded.fec, packet.fec),
+                false => assert_eq!(decoded.fec, FecHeader::default()),
+            }
+        }
+    }
+
+    #[test]
+    fn layout_is_little_endian() {
+        let mut buf = [0u8; UDP_MAX_LENGTH];
+        sample(HeaderVersion::V1).encode(&mut buf).unwrap();
+        assert_eq!(buf[..10], [0xA1, 0x04, 0x03, 0x02, 0x01, 7, 0, 100, 0, 0xC5]);
+    }
+
+    #[test]
+    fn every_truncation_is_too_short() {
+        for version in [HeaderVersion::V1, HeaderVersion::V2, HeaderVersion::V3] {
+            let mut buf = [0u8; UDP_MAX_LENGTH];
+            let length = sample(version).encode(&mut buf).unwrap();
+            for cut in 0..length {
+                let expected = match cut {
+                    0 => 1,
+                    cut if cut < version.header_len() => version.header_len(),
+                    _ => length,
+                };
+                assert_eq!(PacketStruct::decode(&buf[..cut]).unwrap_err(), PacketError::TooShort { expected, actual: cut });
+            }
+        }
+    }
+
+    #[test]
+    fn encode_checks_lengths() {
+        let mut packet = sample(HeaderVersion::V2);
+        let mut buf = [0u8; 64];
+        assert_eq!(packet.encode(&mut buf).unwrap_err(), PacketError::TooShort { expected: 126, actual: 64 });
+        packet.set_length(MAX_PAYLOAD_LEN as u16 + 1);
+        assert_eq!(packet.encode(&mut buf).unwrap_err(), PacketError::PayloadTooLong(MAX_PAYLOAD_LEN + 1));
+    }
+
+    #[test]
+    fn decode_rejects_bad_headers() {
+        let mut buf = [0u8; UDP_MAX_LENGTH];
+        let length = sample(HeaderVersion::V2).encode(&mut buf).unwrap();
+
+        buf[0] = 0x52;
+        assert_eq!(PacketStruct::decode(&buf[..length]).unwrap_err(), PacketError::BadMagic(0x52));
+        buf[0] = HEADER_MAGIC | 9;
+        assert_eq!(PacketStruct::decode(&buf[..length]).unwrap_err(), PacketError::UnsupportedVersion(9));
+
+        buf[0] = HeaderVersion::V2.to_byte();
+        buf[7..9].copy_from_slice(&(MAX_PAYLOAD_LEN as u16 + 1).to_le_bytes());
+        assert_eq!(PacketStruct::decode(&buf).unwrap_err(), PacketError::PayloadTooLong(MAX_PAYLOAD_LEN + 1));
+    }
+
+    #[test]
+    fn any_indicator_decodes() {
+        let mut buf = [0u8; UDP_MAX_LENGTH];
+        let length = sample(HeaderVersion::V1).encode(&mut buf).unwrap();
+        for indicators in 0..=u8::MAX {
+            buf[9] = indicators;
+            let packet = PacketStruct::decode(&buf[..length]).unwrap();
+            let packet_type = get_packet_type(packet.indicators);
+            assert_eq!(packet_type.link(), (indicators & LINK_ID_MASK) as usize);
+            assert_eq!(packet_type.is_last(), indicators & LAST_PACKET_FLAG != 0);
+        }
+    }
+}
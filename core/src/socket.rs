@@ -1,11 +1,24 @@
-use std::net::UdpSocket;
+use std::io;
+use std::net::{TcpStream, UdpSocket};
+use serde::{Deserialize, Serialize};
+
+/// Length prefix in front of every packet on a TCP stream.
+pub const STREAM_PREFIX_LENGTH: usize = 4;
+
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct TcpInfoSample {
+    pub cwnd: u32,        // congestion window (segments)
+    pub srtt_us: u32,     // smoothed RTT
+    pub rttvar_us: u32,
+    pub retransmits: u32, // total retransmitted segments
+}
 
 #[cfg(unix)]
 pub fn create_udp_socket(tos: u8, tx_ipaddr: String) -> Option<UdpSocket> {
     use std::os::unix::io::AsRawFd;
 
     let sock = UdpSocket::bind(format!("{}:0", tx_ipaddr)).ok()?;
-    print!("tx_ipaddr: {}\n", tx_ipaddr);
+    println!("tx_ipaddr: {}", tx_ipaddr);
     let res = unsafe{
         let fd = sock.as_raw_fd();
         let value = &(tos as i32) as *const libc::c_int as *const libc::c_void;
@@ -16,6 +29,88 @@ pub fn create_udp_socket(tos: u8, tx_ipaddr: String) -> Option<UdpSocket> {
     if res == 0 { Some(sock) } else { None }
 }
 
+#[cfg(unix)]
+pub fn create_tcp_stream(tos: u8, tx_ipaddr: String, rx_addr: String) -> io::Result<TcpStream> {
+    use std::net::{SocketAddr, SocketAddrV4};
+    use std::os::unix::io::{AsRawFd, FromRawFd};
+
+    fn to_sockaddr_in(addr: &str) -> io::Result<libc::sockaddr_in> {
+        let addr: SocketAddrV4 = match addr.parse::<SocketAddr>() {
+            Ok(SocketAddr::V4(addr)) => addr,
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("not an IPv4 address: {}", addr))),
+        };
+        let mut sin: libc::sockaddr_in = unsafe { std::mem::zeroed() };
+        sin.sin_family = libc::AF_INET as libc::sa_family_t;
+        sin.sin_port = addr.port().to_be();
+        sin.sin_addr.s_addr = u32::from_ne_bytes(addr.ip().octets());
+        Ok(sin)
+    }
+
+    let local = to_sockaddr_in(&format!("{}:0", tx_ipaddr))?;
+    let remote = to_sockaddr_in(&rx_addr)?;
+    let addr_len = std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_STREAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The stream owns the fd from here on and closes it on any early return
+    let stream = unsafe { TcpStream::from_raw_fd(fd) };
+    let res = unsafe {
+        let value = &(tos as i32) as *const libc::c_int as *const libc::c_void;
+        let option_len = std::mem::size_of::<libc::c_int>() as u32;
+        if libc::setsockopt(stream.as_raw_fd(), libc::IPPROTO_IP, libc::IP_TOS, value, option_len) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        if libc::bind(stream.as_raw_fd(), &local as *const _ as *const libc::sockaddr, addr_len) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        libc::connect(stream.as_raw_fd(), &remote as *const _ as *const libc::sockaddr, addr_len)
+    };
+
+    if res == 0 {
+        log::info!("tx_ipaddr: {} -> {} (TCP)", tx_ipaddr, rx_addr);
+        Ok(stream)
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+/// Sample the kernel TCP state of a connected stream.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn tcp_info(stream: &TcpStream) -> Option<TcpInfoSample> {
+    use std::os::unix::io::AsRawFd;
+
+    // Leading fields of `struct tcp_info` from <linux/tcp.h>, stable since 2.6
+    #[repr(C)]
+    #[derive(Default)]
+    struct LinuxTcpInfo {
+        state: u8, ca_state: u8, retransmits: u8, probes: u8, backoff: u8, options: u8, wscale: u8, flags: u8,
+        rto: u32, ato: u32, snd_mss: u32, rcv_mss: u32,
+        unacked: u32, sacked: u32, lost: u32, retrans: u32, fackets: u32,
+        last_data_sent: u32, last_ack_sent: u32, last_data_recv: u32, last_ack_recv: u32,
+        pmtu: u32, rcv_ssthresh: u32, rtt: u32, rttvar: u32, snd_ssthresh: u32, snd_cwnd: u32, advmss: u32, reordering: u32,
+        rcv_rtt: u32, rcv_space: u32,
+        total_retrans: u32,
+    }
+
+    let mut info = LinuxTcpInfo::default();
+    let mut len = std::mem::size_of::<LinuxTcpInfo>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO,
+            &mut info as *mut _ as *mut libc::c_void, &mut len)
+    };
+    if res != 0 {
+        return None;
+    }
+    Some(TcpInfoSample { cwnd: info.snd_cwnd, srtt_us: info.rtt, rttvar_us: info.rttvar, retransmits: info.total_retrans })
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub fn tcp_info(_stream: &TcpStream) -> Option<TcpInfoSample> {
+    None
+}
+
 #[cfg(windows)]
 pub fn create_udp_socket(tos: u8) -> Option<UdpSocket> {
     use std::net::Ipv4Addr;
@@ -82,4 +177,10 @@ pub fn create_udp_socket(tos: u8) -> Option<UdpSocket> {
         let sock = UdpSocket::from_raw_socket( raw_sock.0 as u64 );
         Some(sock)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(windows)]
+pub fn create_tcp_stream(_tos: u8, _tx_ipaddr: String, rx_addr: String) -> io::Result<TcpStream> {
+    //FIXME: bind to the link address and apply the QoS flow as for UDP
+    TcpStream::connect(rx_addr)
+}
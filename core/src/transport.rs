@@ -0,0 +1,188 @@
+use std::io::{ErrorKind, Read, Write};
+use std::net::{SocketAddr, TcpStream, UdpSocket};
+use std::time::Duration;
+
+use crate::clock::now_secs_f64;
+use crate::socket::{tcp_info, TcpInfoSample, STREAM_PREFIX_LENGTH};
+
+const LOOPBACK_POLL: Duration = Duration::from_millis(1);
+
+/// Sending half of a link, carrying one encoded packet per call.
+///
+/// `send` never blocks: `Err(WouldBlock)` means the link cannot take the packet right now and the
+/// caller should keep it and mark the link blocked; any other error also marks the link blocked.
+pub trait Transport: Send {
+    /// Send one encoded packet; `frame_end` carries the seq when it is the last fragment of a frame.
+    fn send(&mut self, buf: &[u8], frame_end: Option<u32>) -> std::io::Result<()>;
+
+    /// Push out anything accepted by `send` but not yet on the wire.
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Drain the (seq, time) send-complete records of frames finished on this link.
+    fn take_completed(&mut self) -> Vec<(u32, f64)> {
+        Vec::new()
+    }
+
+    /// A handle for sampling link statistics from another thread, for transports that have one.
+    fn stats_handle(&self) -> Option<Box<dyn LinkStats>> {
+        None
+    }
+}
+
+/// Receiving half of a link, yielding one encoded packet per call.
+///
+/// `Err(WouldBlock)` means nothing is pending; other errors mean the link is closed.
+pub trait RecvTransport: Send {
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)>;
+}
+
+pub trait LinkStats: Send {
+    fn tcp_info(&self) -> Option<TcpInfoSample>;
+}
+
+impl LinkStats for TcpStream {
+    fn tcp_info(&self) -> Option<TcpInfoSample> {
+        tcp_info(self)
+    }
+}
+
+pub struct UdpTransport {
+    socket: UdpSocket,
+    rx_addr: String,
+}
+
+impl UdpTransport {
+    pub fn new(socket: UdpSocket, rx_addr: String) -> Self {
+        UdpTransport { socket, rx_addr }
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, buf: &[u8], _frame_end: Option<u32>) -> std::io::Result<()> {
+        self.socket.send_to(buf, &self.rx_addr).map(|_| ())
+    }
+}
+
+impl RecvTransport for UdpSocket {
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        self.recv_from(buf)
+    }
+}
+
+/// A connected TCP link carrying `u32` length-prefixed packets.
+pub struct TcpTransport {
+    stream: TcpStream,
+    pending: Vec<u8>,            // bytes accepted but not yet taken by the kernel
+    pending_seq: Option<u32>,    // frame whose last fragment is in `pending`
+    completed: Vec<(u32, f64)>,  // (seq, time the last byte of the frame left the application)
+}
+
+impl TcpTransport {
+    pub fn new(stream: TcpStream) -> Self {
+        TcpTransport { stream, pending: Vec::new(), pending_seq: None, completed: Vec::new() }
+    }
+}
+
+impl Transport for TcpTransport {
+    fn send(&mut self, buf: &[u8], frame_end: Option<u32>) -> std::io::Result<()> {
+        // Refuse new packets until the previous partial write drained
+        self.flush()?;
+        self.pending.extend_from_slice(&(buf.len() as u32).to_le_bytes());
+        self.pending.extend_from_slice(buf);
+        self.pending_seq = frame_end;
+        match self.flush() {
+            Err(e) if e.kind() != ErrorKind::WouldBlock => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        while !self.pending.is_empty() {
+            match self.stream.write(&self.pending) {
+                Ok(0) => return Err(ErrorKind::WriteZero.into()),
+                Ok(n) => { self.pending.drain(..n); }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if let Some(seq) = self.pending_seq.take() {
+            self.completed.push((seq, now_secs_f64()));
+        }
+        Ok(())
+    }
+
+    fn take_completed(&mut self) -> Vec<(u32, f64)> {
+        std::mem::take(&mut self.completed)
+    }
+
+    fn stats_handle(&self) -> Option<Box<dyn LinkStats>> {
+        let stream = self.stream.try_clone().ok()?;
+        Some(Box::new(stream))
+    }
+}
+
+/// Blocking reader for the rx end of a `TcpTransport`.
+pub struct TcpRecvTransport {
+    stream: TcpStream,
+    peer: SocketAddr,
+}
+
+impl TcpRecvTransport {
+    pub fn new(stream: TcpStream) -> std::io::Result<Self> {
+        let peer = stream.peer_addr()?;
+        Ok(TcpRecvTransport { stream, peer })
+    }
+}
+
+impl RecvTransport for TcpRecvTransport {
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        let mut prefix = [0u8; STREAM_PREFIX_LENGTH];
+        self.stream.read_exact(&mut prefix)?;
+        let length = u32::from_le_bytes(prefix) as usize;
+        if length > buf.len() {
+            return Err(std::io::Error::new(ErrorKind::InvalidData,
+                format!("packet length {} exceeds {}", length, buf.len())));
+        }
+        self.stream.read_exact(&mut buf[..length])?;
+        Ok((length, self.peer))
+    }
+}
+
+/// In-memory link holding at most `capacity` packets, `src_addr` is reported to the receiver.
+pub fn loopback(capacity: usize, src_addr: SocketAddr) -> (LoopbackTransport, LoopbackRecvTransport) {
+    let (tx, rx) = flume::bounded(capacity);
+    (LoopbackTransport { tx }, LoopbackRecvTransport { rx, src_addr })
+}
+
+pub struct LoopbackTransport {
+    tx: flume::Sender<Vec<u8>>,
+}
+
+pub struct LoopbackRecvTransport {
+    rx: flume::Receiver<Vec<u8>>,
+    src_addr: SocketAddr,
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, buf: &[u8], _frame_end: Option<u32>) -> std::io::Result<()> {
+        self.tx.try_send(buf.to_vec()).map_err(|e| match e {
+            flume::TrySendError::Full(_) => ErrorKind::WouldBlock.into(),
+            flume::TrySendError::Disconnected(_) => ErrorKind::BrokenPipe.into(),
+        })
+    }
+}
+
+impl RecvTransport for LoopbackRecvTransport {
+    fn recv(&mut self, buf: &mut [u8]) -> std::io::Result<(usize, SocketAddr)> {
+        // Wait briefly rather than spin, the rx loop retries on WouldBlock
+        let packet = self.rx.recv_timeout(LOOPBACK_POLL).map_err(|e| -> std::io::Error { match e {
+            flume::RecvTimeoutError::Timeout => ErrorKind::WouldBlock.into(),
+            flume::RecvTimeoutError::Disconnected => ErrorKind::BrokenPipe.into(),
+        }})?;
+        let length = packet.len().min(buf.len());
+        buf[..length].copy_from_slice(&packet[..length]);
+        Ok((length, self.src_addr))
+    }
+}
@@ -1,14 +1,22 @@
-use std::net::UdpSocket;
+use std::net::{SocketAddr, TcpListener, UdpSocket};
 use std::sync::{Arc, Mutex};
 use clap::Parser;
 use std::io::ErrorKind;
 
-use crate::record::{RecvData, RecvRecord};
+use log::{debug, warn};
+
+use crate::record::RecvData;
+use core::ack::FragmentAck;
+use core::clock::{now_secs_f64, ClockCorrection, SyncKind, TimeSync, TIME_SYNC_LENGTH};
+use core::packet::{channel_info, get_packet_type, PacketStruct, UDP_MAX_LENGTH};
 use core::socket::*;
+use core::transport::{RecvTransport, TcpRecvTransport};
 
 const PONG_PORT_INC: u16 = 1024;
+// Negative acks sent for one frame in ARQ mode
+const MAX_NACKS: u32 = 3;
 
-#[derive(Parser, Debug)]
+#[derive(Parser, Debug, Clone)]
 #[clap(author, version, about, long_about=None)]
 pub struct Args {
     pub port: u16,
@@ -16,6 +24,14 @@ pub struct Args {
     #[clap(long)]
     pub calc_rtt : bool,
     #[clap(long)]
+    pub calc_owd : bool,
+    /// Correct rx timestamps into the tx clock domain using the tx's clock sync estimate
+    #[clap(long)]
+    pub tx_clock : bool,
+    /// Also accept length-prefixed packets over TCP connections on the same port
+    #[clap(long)]
+    pub tcp: bool,
+    #[clap(long)]
     pub rx_mode: bool,
     #[clap(long, default_value_t = 1)]
     pub sample_rate: u32,
@@ -23,69 +39,198 @@ pub struct Args {
     pub src_ipaddrs: Vec<String>,
     #[clap(long, default_value_t = 5005)]
     pub forward_port: u16,
+    /// With --calc-rtt, acknowledge an incomplete frame after no fragment arrived for this long (unit: seconds)
+    #[clap(long, default_value_t = 0.05)]
+    pub ack_timeout: f64,
+    /// With --calc-rtt, NACK frames with missing fragments (up to 3 times) so the tx can retransmit them
+    #[clap(long)]
+    pub arq: bool,
+    /// The tx duplicates fragments over its links: log the link delivering each fragment first to logs/first-link-<port>.txt
+    #[clap(long)]
+    pub duplicate: bool,
 }
 
 pub fn recv_thread(args: Args, recv_params: Arc<Mutex<RecvData>>, lock: Arc<Mutex<bool>>){
     let addr = format!("0.0.0.0:{}", args.port);    
     let socket = UdpSocket::bind(&addr).unwrap();
     socket.set_nonblocking(true).unwrap();
-    let addr = format!("0.0.0.0");
+    let addr = String::from("0.0.0.0");
     // let pong_socket = UdpSocket::bind(&addr).unwrap();
     let pong_socket = create_udp_socket(192, addr.clone());
     if let Some(pong_socket) = pong_socket {
         pong_socket.set_nonblocking(true).unwrap();
+        if args.tcp {
+            let listener = TcpListener::bind(format!("0.0.0.0:{}", args.port)).unwrap();
+            let (args, recv_params, lock) = (args.clone(), Arc::clone(&recv_params), Arc::clone(&lock));
+            let pong_socket = pong_socket.try_clone().unwrap();
+            std::thread::spawn(move || {
+                tcp_accept_thread(listener, args, recv_params, lock, pong_socket);
+            });
+        }
         println!("Waiting ...");
-        let mut buffer = [0; 2048];
-        let mut started = false;
-        loop {
-            if let Ok((_len, src_addr)) = socket.recv_from(&mut buffer) {
-                let mut data = recv_params.lock().unwrap();
-                data.data_len += _len as u32;
-                if !started {
-                    *lock.lock().unwrap() = true;
-                    println!("Start");
-                    data.rx_start_time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
-                    started = true;
-                }
+        transport_recv_thread(args, socket, recv_params, lock, pong_socket);
+    }
+    else {
+        eprintln!("Error creating pong socket");
+    }
+}
 
-                if args.calc_rtt {
-                    handle_rtt(&args, &mut buffer, &mut data, &pong_socket, &src_addr);
+/// Receive packets from one link until it closes; pongs and clock sync replies go out on `pong_socket`.
+pub fn transport_recv_thread<T: RecvTransport>(
+    args: Args,
+    mut transport: T,
+    recv_params: Arc<Mutex<RecvData>>,
+    lock: Arc<Mutex<bool>>,
+    pong_socket: UdpSocket
+) {
+    let mut buffer = [0; 2048];
+    loop {
+        match transport.recv(&mut buffer) {
+            Ok((_len, src_addr)) => {
+                if TimeSync::is_time_sync(&buffer[.._len]) {
+                    handle_time_sync(&args, &buffer[.._len], &recv_params, &pong_socket, &src_addr);
+                    continue;
+                }
+                handle_packet(&args, &buffer[.._len], &recv_params, &lock, &pong_socket, &src_addr);
+            }
+            Err(ref e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted) => {
+                if !*lock.lock().unwrap() {
+                    std::thread::sleep(std::time::Duration::from_nanos(100_000));
                 }
+            }
+            Err(e) => {
+                debug!("Link closed: {}", e);
+                break;
+            }
+        }
+    }
+}
 
-            } else if !started {
-                std::thread::sleep(std::time::Duration::from_nanos(100_000));
+fn tcp_accept_thread(
+    listener: TcpListener,
+    args: Args,
+    recv_params: Arc<Mutex<RecvData>>,
+    lock: Arc<Mutex<bool>>,
+    pong_socket: UdpSocket
+) {
+    // One connection per tx link
+    for stream in listener.incoming() {
+        match stream.and_then(TcpRecvTransport::new) {
+            Ok(transport) => {
+                let (args, recv_params, lock) = (args.clone(), Arc::clone(&recv_params), Arc::clone(&lock));
+                let pong_socket = pong_socket.try_clone().unwrap();
+                std::thread::spawn(move || {
+                    transport_recv_thread(args, transport, recv_params, lock, pong_socket);
+                });
             }
+            Err(e) => eprintln!("Error accepting connection: {}", e),
         }
     }
-    else {
-        eprintln!("Error creating pong socket");
+}
+
+fn handle_packet(
+    args: &Args,
+    buffer: &[u8],
+    recv_params: &Arc<Mutex<RecvData>>,
+    lock: &Arc<Mutex<bool>>,
+    pong_socket: &UdpSocket,
+    src_addr: &SocketAddr
+) {
+    let packet = match PacketStruct::decode(buffer) {
+        Ok(packet) => packet,
+        Err(e) => {
+            warn!("Drop malformed packet from {}: {}", src_addr, e);
+            return;
+        }
+    };
+    let mut data = recv_params.lock().unwrap();
+    data.data_len += buffer.len() as u32;
+    let mut started = lock.lock().unwrap();
+    if !*started {
+        *started = true;
+        println!("Start");
+        data.rx_start_time = now_secs_f64();
+    }
+    drop(started);
+
+    if args.calc_rtt || args.calc_owd {
+        handle_frame(args, packet, &mut data, pong_socket, src_addr);
     }
 }
 
-fn handle_rtt(
+fn handle_frame(
     args: &Args, 
-    buffer: &mut [u8], 
+    packet: PacketStruct,
     data: &mut RecvData, 
     pong_socket: &UdpSocket, 
-    src_addr: &std::net::SocketAddr
+    src_addr: &SocketAddr
 ) -> Option<()> {
-    let seq = u32::from_le_bytes(buffer[0..4].try_into().unwrap());
+    let seq = packet.seq;
     data.last_seq = if seq > data.last_seq { seq } else { data.last_seq };
+    let rx_time = data.rx_time();
 
-    if seq % args.sample_rate != 0 {
+    if packet.version.has_timestamps() {
+        let owd = rx_time - packet.send_time;
+        debug!("seq {}, offset {}, link {}, owd {:.6}", seq, packet.offset, channel_info(packet.indicators), owd);
+        data.owd.update_fragment(owd);
+    }
+
+    if !seq.is_multiple_of(args.sample_rate) {
         return None
     } 
 
-    data.recv_records.entry(seq).or_insert_with(RecvRecord::new).record(buffer);
+    if packet.is_retransmission() {
+        data.retransmitted += 1;
+    }
+    let (offset, link) = (packet.offset, channel_info(packet.indicators) as usize);
+    data.mark_arrival(link);
+    if data.done_frames.contains(&seq) {
+        // Without FEC every fragment of a done frame already arrived
+        if packet.is_retransmission() {
+            data.duplicates += 1;
+        } else if packet.fec.scheme.is_none() {
+            data.mark_redundant(packet.encoded_len());
+        }
+        return None;
+    }
+
+    // Recovery trusts the FEC layout, so a malformed or conflicting one is dropped
+    let fec_agrees = data.recv_records.get(&seq).is_none_or(|record| record.fec_agrees(&packet.fec));
+    if !fec_agrees || !packet.fec.fits(offset, get_packet_type(packet.indicators).is_last()) {
+        warn!("Drop fragment {} of frame {} with a bad FEC layout {:?}", offset, seq, packet.fec);
+        return None;
+    }
+    match data.recv_records.entry(seq).or_default().record(packet, rx_time) {
+        true => data.mark_first_delivery(seq, offset, link),
+        false => data.mark_redundant(packet.encoded_len()),
+    }
+    data.forget_incomplete();
+    let ping_addr = format!("{}:{}", src_addr.ip(), args.port + PONG_PORT_INC);
+    if args.calc_rtt {
+        ack_stale_frames(args, data, pong_socket, &ping_addr, rx_time);
+    }
     let _record = data.recv_records.get_mut(&seq).unwrap();
 
-    if _record.is_complete {
-        data.stutter.update( std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64() );
+    // The last fragment reveals any gap before it
+    if args.calc_rtt && args.arq && !_record.is_complete && get_packet_type(packet.indicators).is_last() && _record.acks < MAX_NACKS {
+        _record.acks += 1;
+        _record.acked_at = rx_time;
+        let ack = FragmentAck { early: true, .._record.fragment_ack(seq, rx_time) };
+        send_fragment_ack(pong_socket, &ack, &ping_addr);
+    }
 
-        buffer[19..27].copy_from_slice(_record.delta().to_le_bytes().as_ref());
+    if _record.is_complete {
+        data.stutter.update( rx_time );
+        if let Some(delay) = _record.frame_delay(seq) {
+            data.owd.update_frame(delay);
+        }
 
-        let ping_addr = format!("{}:{}", src_addr.ip().to_string(), args.port + PONG_PORT_INC);
-        send_ack(pong_socket, buffer, &ping_addr);
+        if args.calc_rtt {
+            let mut buffer = [0u8; UDP_MAX_LENGTH];
+            let length = packet.to_pong(&_record.deltas()).encode(&mut buffer).ok()?;
+            send_ack(pong_socket, &buffer[..length], &ping_addr);
+            send_fragment_ack(pong_socket, &_record.fragment_ack(seq, rx_time), &ping_addr);
+        }
 
         if args.rx_mode && data.tx.is_some() {
             let res = _record.gather();
@@ -93,16 +238,77 @@ fn handle_rtt(
                 tx.send(res).unwrap();
             }
         }
+        if _record.retransmitted {
+            data.recovered += 1;
+        }
+        if _record.fec_recovered {
+            data.fec_recovered += 1;
+        }
         data.recv_records.remove(&seq);
+        data.mark_done(seq);
         data.recevied += 1;
     }
     
     None
 }
 
+fn handle_time_sync(
+    args: &Args,
+    buffer: &[u8],
+    recv_params: &Arc<Mutex<RecvData>>,
+    pong_socket: &UdpSocket,
+    src_addr: &SocketAddr
+) {
+    let t2 = now_secs_f64();
+    let request = match TimeSync::decode(buffer) {
+        Ok(sync) if sync.kind == SyncKind::Request => sync,
+        Ok(_) => return,
+        Err(e) => {
+            warn!("Drop malformed clock sync from {}: {}", src_addr, e);
+            return;
+        }
+    };
+
+    // The tx piggybacks its latest estimate; rx time `t1 + offset` is where it was taken
+    if let (true, Some((offset, drift))) = (args.tx_clock, request.estimate()) {
+        recv_params.lock().unwrap().clock = Some(ClockCorrection::new(offset, drift, request.t1 + offset));
+    }
+
+    let mut buf = [0u8; TIME_SYNC_LENGTH];
+    if let Ok(length) = request.response(t2, now_secs_f64()).encode(&mut buf) {
+        send_ack(pong_socket, &buf[..length], &src_addr.to_string());
+    }
+}
+
+// Acknowledge frames that stopped receiving fragments so the tx learns about losses: once each,
+// or every `ack_timeout` up to MAX_NACKS times in ARQ mode
+fn ack_stale_frames(args: &Args, data: &mut RecvData, pong_socket: &UdpSocket, ping_addr: &str, now: f64) {
+    if now - data.last_ack_scan < args.ack_timeout / 2.0 {
+        return;
+    }
+    data.last_ack_scan = now;
+    let max_acks = if args.arq { MAX_NACKS } else { 1 };
+    let stale = data.recv_records.iter_mut()
+        .filter(|(_, record)| !record.is_complete && record.acks < max_acks)
+        .filter(|(_, record)| now - record.updated_at.max(record.acked_at) > args.ack_timeout);
+    for (&seq, record) in stale {
+        record.acks += 1;
+        record.acked_at = now;
+        send_fragment_ack(pong_socket, &record.fragment_ack(seq, now), ping_addr);
+    }
+}
+
+fn send_fragment_ack(pong_socket: &UdpSocket, ack: &FragmentAck, ping_addr: &str) {
+    let mut buffer = [0u8; UDP_MAX_LENGTH];
+    match ack.encode(&mut buffer) {
+        Ok(length) => send_ack(pong_socket, &buffer[..length], ping_addr),
+        Err(e) => warn!("Fragment ack for frame {} not sent: {}", ack.seq, e),
+    }
+}
+
 fn send_ack(pong_socket: &UdpSocket, buffer: &[u8], ping_addr: &str) {
     loop {
-        match pong_socket.send_to(&buffer, ping_addr) {
+        match pong_socket.send_to(buffer, ping_addr) {
             Ok(_) => break,
             Err(ref e) if e.kind() == ErrorKind::WouldBlock => {
                 println!("Send operation would block, retrying later...");
@@ -3,7 +3,7 @@ mod record;
 mod statistic;
 mod forward;
 
-use std::{fs::File, io::Write, sync::{mpsc, Arc, Mutex}};
+use std::{fs::File, io::{BufWriter, Write}, sync::{mpsc, Arc, Mutex}};
 use clap::Parser;
 use record::RecvData;
 use crate::destination::*;
@@ -20,6 +20,13 @@ fn main() {
 
     // Extract duration from args
     let port = args.port;
+    // Per-frame one-way delay: seq, frame delay, sender queueing, network delay
+    if args.calc_owd {
+        recv_data.lock().unwrap().owd.frame_log = File::create(format!("logs/owd-{port}.txt")).ok().map(BufWriter::new);
+    }
+    if args.duplicate {
+        recv_data.lock().unwrap().first_link_log = File::create(format!("logs/first-link-{port}.txt")).ok().map(BufWriter::new);
+    }
     let duration = args.duration;
     
     let lock = Arc::new(Mutex::new(false));
@@ -47,15 +54,38 @@ fn main() {
 
     println!("Received Bytes: {:.3} MB", data_len as f64/ 1024.0 / 1024.0);
     println!("Average Throughput: {:.3} Mbps", data_len as f64 / rx_duration / 1e6 * 8.0);
-    let recv_data = recv_data_final.lock().unwrap();
+    let mut recv_data = recv_data_final.lock().unwrap();
     let non_received = recv_data.last_seq - recv_data.recevied;
     println!("Packet loss rate: {:.5}", non_received as f64 / recv_data.last_seq as f64);
     println!("Stuttering rate: {:.5}", recv_data.stutter.get_stuttering());
+    if recv_data.fec_recovered > 0 {
+        println!("FEC recovered frames: {}", recv_data.fec_recovered);
+    }
+    if recv_data.redundant > 0 {
+        println!("Duplicate fragments: {} ({:.2}% of received bytes)", recv_data.redundant, 100.0 * recv_data.redundant_bytes as f64 / data_len as f64);
+        println!("First delivery per link: {:?} (arrivals {:?})", recv_data.first_delivery, recv_data.arrivals);
+    }
+    if recv_data.retransmitted > 0 {
+        println!("Retransmitted fragments: {} (recovered frames {}, duplicates {})", recv_data.retransmitted, recv_data.recovered, recv_data.duplicates);
+    }
+    if let Some((avg, max)) = recv_data.owd.get_fragment_delay() {
+        println!("Fragment one-way delay: avg {:.3} ms, max {:.3} ms", avg * 1e3, max * 1e3);
+    }
+    if let Some((frame, queueing, network)) = recv_data.owd.get_frame_delay() {
+        println!("Frame one-way delay: {:.3} ms (sender queueing {:.3} ms, network {:.3} ms)", frame * 1e3, queueing * 1e3, network * 1e3);
+    }
 
     // Write the data to stuttering file
     let mut logger = File::create( format!("logs/stuttering-{port}.txt", ) ).unwrap();
     for val in &recv_data.stutter.ack_times {
         logger.write_all(format!("{:?}\n", val).as_bytes()).unwrap();
     }
+
+    if let Some(mut logger) = recv_data.first_link_log.take() {
+        let _ = logger.flush();
+    }
+    if let Some(mut logger) = recv_data.owd.frame_log.take() {
+        let _ = logger.flush();
+    }
 }
 
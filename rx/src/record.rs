@@ -1,16 +1,38 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
 
 use std::sync::mpsc::Sender;
-use core::packet::{self, PacketStruct, PacketType};
+use core::ack::{FragmentAck, LinkArrival};
+use core::clock::ClockCorrection;
+use core::fec::FecHeader;
+use core::packet::{self, PacketStruct};
 
+use crate::statistic::owd::{FrameDelay, OneWayDelay};
 use crate::statistic::stuttering::Stutter;
+
+// Completed frames remembered to drop late retransmissions
+const DONE_HISTORY: u32 = 1024;
+
 #[derive(Default)]
 struct RecvOffsets {
-    first_link_rx_time: Option<f64>,
-    second_link_rx_time: Option<f64>,
+    link_rx_times: Vec<Option<f64>>, // latest arrival time per link id
+    link_send_times: Vec<f64>,       // send time of that latest fragment
+    link_counts: Vec<u16>,           // fragments received per link id
 }
 
-type IsACK = (bool, bool);
+impl RecvOffsets {
+    fn update(&mut self, link: usize, rx_time: f64, send_time: f64) {
+        if self.link_rx_times.len() <= link {
+            self.link_rx_times.resize(link + 1, None);
+            self.link_send_times.resize(link + 1, 0.0);
+            self.link_counts.resize(link + 1, 0);
+        }
+        self.link_rx_times[link] = Some(rx_time);
+        self.link_send_times[link] = send_time;
+        self.link_counts[link] = self.link_counts[link].saturating_add(1);
+    }
+}
 
 pub struct RecvData{
     pub recv_records: HashMap<u32, RecvRecord>,
@@ -19,9 +41,28 @@ pub struct RecvData{
     pub data_len: u32,
     pub rx_start_time: f64,
     pub stutter: Stutter,
+    pub owd: OneWayDelay,
+    pub clock: Option<ClockCorrection>,
+    pub last_ack_scan: f64,
+    pub done_frames: HashSet<u32>,
+    pub retransmitted: u32, // retransmitted fragments received
+    pub recovered: u32,     // frames completed by a retransmission
+    pub duplicates: u32,    // retransmissions arriving after their frame completed
+    pub fec_recovered: u32, // frames rebuilt from FEC parity
+    pub arrivals: Vec<u32>,             // fragments each link delivered, later copies included, by link id
+    pub first_delivery: Vec<u32>,       // fragments each link delivered first, by link id
+    pub first_link_log: Option<BufWriter<File>>, // `seq offset link` of every first delivery
+    pub redundant: u32,     // copies of fragments already received
+    pub redundant_bytes: u64,
     pub tx: Option<Sender<Vec<u8>>>
 }
 
+impl Default for RecvData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl RecvData{
     pub fn new() -> Self{
         Self{
@@ -31,56 +72,189 @@ impl RecvData{
             data_len: 0,
             rx_start_time: 0.0,
             stutter: Stutter::new(),
+            owd: OneWayDelay::new(),
+            clock: None,
+            last_ack_scan: 0.0,
+            done_frames: HashSet::new(),
+            retransmitted: 0,
+            recovered: 0,
+            duplicates: 0,
+            fec_recovered: 0,
+            arrivals: Vec::new(),
+            first_delivery: Vec::new(),
+            first_link_log: None,
+            redundant: 0,
+            redundant_bytes: 0,
             tx: None,
         }
     }
+
+    /// Current time, in the tx clock domain once a clock estimate is applied.
+    pub fn rx_time(&self) -> f64 {
+        let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
+        self.clock.map_or(now, |clock| clock.to_tx_clock(now))
+    }
+
+    pub fn mark_arrival(&mut self, link: usize) {
+        if self.arrivals.len() <= link {
+            self.arrivals.resize(link + 1, 0);
+        }
+        self.arrivals[link] += 1;
+    }
+
+    pub fn mark_first_delivery(&mut self, seq: u32, offset: u16, link: usize) {
+        if self.first_delivery.len() <= link {
+            self.first_delivery.resize(link + 1, 0);
+        }
+        self.first_delivery[link] += 1;
+        if let Some(ref mut logger) = self.first_link_log {
+            let _ = writeln!(logger, "{} {} {}", seq, offset, link);
+        }
+    }
+
+    pub fn mark_redundant(&mut self, bytes: usize) {
+        self.redundant += 1;
+        self.redundant_bytes += bytes as u64;
+    }
+
+    pub fn mark_done(&mut self, seq: u32) {
+        self.done_frames.insert(seq);
+        if self.done_frames.len() > 2 * DONE_HISTORY as usize {
+            let oldest = self.last_seq.saturating_sub(DONE_HISTORY);
+            self.done_frames.retain(|&seq| seq > oldest);
+        }
+    }
+
+    /// Forget incomplete frames more than `DONE_HISTORY` behind the latest one, long past their
+    /// acks; they stay counted as lost.
+    pub fn forget_incomplete(&mut self) {
+        if self.recv_records.len() > 2 * DONE_HISTORY as usize {
+            let oldest = self.last_seq.saturating_sub(DONE_HISTORY);
+            self.recv_records.retain(|&seq, _| seq > oldest);
+        }
+    }
 }
 
 
 pub struct RecvRecord {
     pub packets: HashMap<u16, PacketStruct>, // Use a HashMap to store packets by their offset
-    pub is_ack: IsACK,
     offsets: RecvOffsets,
     last_packet_id: Option<u16>,
     pub is_complete: bool,
+    // Sender timestamps (header V2 onwards)
+    has_timestamps: bool,
+    arrival_time: f64,
+    last_send_time: f64,
+    last_rx_time: f64,
+    max_network_delay: f64,
+    pub updated_at: f64, // rx time of the latest fragment
+    pub acks: u32,       // fragment acks sent before the frame completed
+    pub acked_at: f64,   // rx time of the latest of them
+    pub retransmitted: bool,
+    fec: Option<FecHeader>,
+    pub fec_recovered: bool, // completed by rebuilding lost source fragments
+}
+
+impl Default for RecvRecord {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl RecvRecord {
     pub fn new() -> Self{
         Self{
             packets: HashMap::<u16, PacketStruct>::new(),
-            is_ack : (false, false),
             offsets: RecvOffsets::default(),
             last_packet_id: None,
             is_complete: false,
+            has_timestamps: false,
+            arrival_time: 0.0,
+            last_send_time: 0.0,
+            last_rx_time: 0.0,
+            max_network_delay: f64::MIN,
+            updated_at: 0.0,
+            acks: 0,
+            acked_at: 0.0,
+            retransmitted: false,
+            fec: None,
+            fec_recovered: false,
         }
     }
-    pub fn record(&mut self, data: &[u8]) {
-        let packet = packet::from_buffer(data);
+    /// Record a fragment arriving at `rx_time`. Another copy of a fragment already received
+    /// only counts as an arrival on its link, and returns false.
+    pub fn record(&mut self, packet: PacketStruct, rx_time: f64) -> bool {
         let offset = Some(packet.offset);
-        let rx_time = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap().as_secs_f64();
-
-        match packet::get_packet_type(packet.indicators) {
-            PacketType::FirstLink => self.offsets.first_link_rx_time = Some(rx_time),
-            PacketType::SecondLink => self.offsets.second_link_rx_time = Some(rx_time),
-            PacketType::LastPacketInFirstLink => {
-                self.last_packet_id = offset;
-                self.offsets.first_link_rx_time = Some(rx_time);
-            },
-            PacketType::LastPacketInSecondLink => {
-                self.last_packet_id = offset;
-                self.offsets.second_link_rx_time = Some(rx_time);
-            }
+
+        let packet_type = packet::get_packet_type(packet.indicators);
+        self.offsets.update(packet_type.link(), rx_time, packet.send_time);
+        self.updated_at = rx_time;
+        if self.packets.contains_key(&packet.offset) {
+            return false;
         }
+        if packet_type.is_last() {
+            self.last_packet_id = offset;
+        }
+        self.retransmitted |= packet.is_retransmission();
+        if packet.fec.scheme.is_some() {
+            self.fec = Some(packet.fec);
+        }
+
+        if packet.version.has_timestamps() {
+            self.has_timestamps = true;
+            self.arrival_time = packet.arrival_time;
+            self.last_send_time = self.last_send_time.max(packet.send_time);
+            self.last_rx_time = self.last_rx_time.max(rx_time);
+            self.max_network_delay = self.max_network_delay.max(rx_time - packet.send_time);
+        }
+
+        self.packets.insert(packet.offset, packet);
+        self.is_complete = self.determine_complete() || self.recover();
+        true
+    }
+
+    /// Whether `fec`, from a new fragment, is the FEC layout of the frame so far.
+    pub fn fec_agrees(&self, fec: &FecHeader) -> bool {
+        fec.scheme.is_none() || self.fec.is_none_or(|known| known == *fec)
+    }
 
-        self.packets.insert(packet.offset as u16, packet);
-        self.is_complete = self.determine_complete();
+    /// Arrival time of each link `1..n` relative to link 0 (a missing link counts as time 0).
+    pub fn deltas(&self) -> Vec<f64> {
+        let times: Vec<f64> = self.offsets.link_rx_times.iter().map(|t| t.unwrap_or(0.0)).collect();
+        let first_link_time = times.first().copied().unwrap_or(0.0);
+        times.iter().skip(1).map(|t| t - first_link_time).collect()
     }
 
-    pub fn delta(&self) -> f64 {
-        let first_link_time = self.offsets.first_link_rx_time.unwrap_or(0.0);
-        let second_link_time = self.offsets.second_link_rx_time.unwrap_or(0.0);
-        second_link_time - first_link_time
+    /// Fragment-level acknowledgement of what has arrived so far, leaving rx at `now`.
+    pub fn fragment_ack(&self, seq: u32, now: f64) -> FragmentAck {
+        let count = match (self.fec, self.last_packet_id) {
+            (Some(fec), _) => fec.fragment_num(),
+            (None, Some(last_id)) => last_id as usize + 1,
+            (None, None) => self.packets.keys().max().map_or(0, |&max| max as usize + 1),
+        };
+        let received = (0..count).map(|i| self.packets.contains_key(&(i as u16))).collect();
+        let links = self.offsets.link_rx_times.iter().enumerate().map(|(link, rx_time)| match rx_time {
+            Some(rx_time) => LinkArrival {
+                received: self.offsets.link_counts[link],
+                send_time: self.offsets.link_send_times[link],
+                hold: now - rx_time,
+            },
+            None => LinkArrival::default(),
+        }).collect();
+        FragmentAck { seq, complete: self.is_complete, early: false, received, links }
+    }
+
+    /// One-way delay breakdown of the frame, once complete and stamped by the sender.
+    pub fn frame_delay(&self, seq: u32) -> Option<FrameDelay> {
+        if !self.is_complete || !self.has_timestamps {
+            return None;
+        }
+        Some(FrameDelay {
+            seq,
+            frame_delay: self.last_rx_time - self.arrival_time,
+            sender_queueing: self.last_send_time - self.arrival_time,
+            network_delay: self.max_network_delay,
+        })
     }
 
     fn determine_complete(&self) -> bool {
@@ -88,21 +262,112 @@ impl RecvRecord {
             range.all(|i| packets.contains_key(&i))
         }
     
-        if let Some(last_id) = self.last_packet_id {
+        if let Some(fec) = self.fec {
+            fec.source_num > 0 && is_range_complete(&self.packets, 0..=fec.source_num - 1)
+        }
+        else if let Some(last_id) = self.last_packet_id {
             is_range_complete(&self.packets, 0..=last_id)
         }
         else {
             false
         }
     }
+    // Rebuild lost source fragments from the parity once enough fragments arrived
+    fn recover(&mut self) -> bool {
+        let Some(fec) = self.fec else { return false };
+        let present: Vec<bool> = (0..fec.fragment_num()).map(|i| self.packets.contains_key(&(i as u16))).collect();
+        if !fec.recoverable(&present) {
+            return false;
+        }
+        let mut shards: Vec<Option<Vec<u8>>> = (0..fec.fragment_num())
+            .map(|i| self.packets.get(&(i as u16)).map(|p| p.payload[..p.length as usize].to_vec()))
+            .collect();
+        if !fec.recover(&mut shards) {
+            return false;
+        }
+        let Some(&template) = self.packets.values().next() else { return false };
+        // The last source fragment cannot be longer than the shard it is rebuilt from
+        let last = fec.source_num - 1;
+        if !self.packets.contains_key(&last) && shards[last as usize].as_ref().is_none_or(|shard| shard.len() < fec.last_len as usize) {
+            return false;
+        }
+        for (offset, shard) in shards.into_iter().enumerate().take(fec.source_num as usize) {
+            let offset = offset as u16;
+            if self.packets.contains_key(&offset) {
+                continue;
+            }
+            let Some(shard) = shard else { return false };
+            let mut packet = template;
+            packet.offset = offset;
+            packet.set_length(if offset == fec.source_num - 1 { fec.last_len } else { shard.len() as u16 });
+            packet.set_payload(&shard[..packet.length as usize]);
+            self.packets.insert(offset, packet);
+        }
+        self.fec_recovered = true;
+        true
+    }
+
     #[allow(dead_code)]
     pub fn gather(&self) -> Vec<u8>{
         let mut data = Vec::new();
-        let num_packets = self.packets.len();
+        let num_packets = self.fec.map_or(self.packets.len(), |fec| fec.source_num as usize);
         for i in 0..num_packets{
             let packet = self.packets.get(&(i as u16)).unwrap();
             data.extend_from_slice(&packet.payload[ ..packet.length as usize]);
         }
-        return data;
+        data
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::fec::FecScheme;
+    use core::packet::PacketType;
+
+    fn fragment(offset: u16, fec: FecHeader) -> PacketStruct {
+        let mut packet = PacketStruct::new();
+        packet.version = packet::HeaderVersion::V3;
+        packet.offset = offset;
+        packet.set_length(10);
+        packet.set_indicator(PacketType::new(0, offset as usize + 1 == fec.fragment_num()));
+        packet.fec = fec;
+        packet
+    }
+
+    #[test]
+    fn recover_rejects_last_len_beyond_shard() {
+        let fec = FecHeader { scheme: Some(FecScheme::XOR), source_num: 2, parity_num: 1, last_len: 500 };
+        let mut record = RecvRecord::default();
+        assert!(record.record(fragment(0, fec), 0.0));
+        assert!(record.record(fragment(2, fec), 0.0));
+        assert!(!record.is_complete);
+    }
+
+    #[test]
+    fn incomplete_frames_are_forgotten_past_the_history() {
+        let fec = FecHeader::default();
+        let mut data = RecvData::new();
+        for seq in 0..3 * DONE_HISTORY {
+            data.last_seq = seq;
+            // The first of two fragments, the second being lost
+            let mut packet = fragment(0, fec);
+            packet.seq = seq;
+            packet.set_indicator(PacketType::new(0, false));
+            data.recv_records.entry(seq).or_default().record(packet, 0.0);
+            data.forget_incomplete();
+            assert!(data.recv_records.len() <= 2 * DONE_HISTORY as usize + 1);
+        }
+        assert!(data.recv_records.contains_key(&(3 * DONE_HISTORY - 1)));
+        assert!(!data.recv_records.contains_key(&0));
+    }
+
+    #[test]
+    fn recover_rebuilds_last_fragment() {
+        let fec = FecHeader { scheme: Some(FecScheme::XOR), source_num: 2, parity_num: 1, last_len: 4 };
+        let mut record = RecvRecord::default();
+        record.record(fragment(0, fec), 0.0);
+        record.record(fragment(2, fec), 0.0);
+        assert!(record.is_complete && record.fec_recovered);
+        assert_eq!(record.gather().len(), 14);
+    }
+}
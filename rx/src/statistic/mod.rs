@@ -1 +1,2 @@
-pub mod stuttering;
\ No newline at end of file
+pub mod stuttering;
+pub mod owd;
\ No newline at end of file
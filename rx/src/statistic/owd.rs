@@ -0,0 +1,67 @@
+use std::fs::File;
+use std::io::{BufWriter, Write};
+
+/// One-way delay of a complete frame, all in seconds on the sender clock.
+#[derive(Debug, Clone, Copy)]
+pub struct FrameDelay {
+    pub seq: u32,
+    pub frame_delay: f64,     // frame arrival at the sender -> last fragment received
+    pub sender_queueing: f64, // frame arrival at the sender -> last fragment sent
+    pub network_delay: f64,   // largest send -> receive time among the fragments
+}
+
+pub struct OneWayDelay {
+    pub frame_log: Option<BufWriter<File>>, // `seq frame_delay sender_queueing network_delay` of every frame
+    frame_count: usize,
+    frame_sums: (f64, f64, f64),
+    fragment_count: usize,
+    fragment_sum: f64,
+    fragment_max: f64,
+}
+
+impl OneWayDelay {
+    pub fn new() -> Self {
+        OneWayDelay {
+            frame_log: None,
+            frame_count: 0,
+            frame_sums: (0.0, 0.0, 0.0),
+            fragment_count: 0,
+            fragment_sum: 0.0,
+            fragment_max: f64::MIN,
+        }
+    }
+
+    pub fn update_fragment(&mut self, owd: f64) {
+        self.fragment_count += 1;
+        self.fragment_sum += owd;
+        self.fragment_max = self.fragment_max.max(owd);
+    }
+
+    pub fn update_frame(&mut self, delay: FrameDelay) {
+        self.frame_count += 1;
+        self.frame_sums.0 += delay.frame_delay;
+        self.frame_sums.1 += delay.sender_queueing;
+        self.frame_sums.2 += delay.network_delay;
+        if let Some(ref mut logger) = self.frame_log {
+            let _ = writeln!(logger, "{} {:.6} {:.6} {:.6}", delay.seq, delay.frame_delay, delay.sender_queueing, delay.network_delay);
+        }
+    }
+
+    /// Average and maximum fragment one-way delay.
+    pub fn get_fragment_delay(&self) -> Option<(f64, f64)> {
+        if self.fragment_count == 0 {
+            return None;
+        }
+        Some((self.fragment_sum / self.fragment_count as f64, self.fragment_max))
+    }
+
+    /// Average frame delay, sender queueing and network delay.
+    pub fn get_frame_delay(&self) -> Option<(f64, f64, f64)> {
+        if self.frame_count == 0 {
+            return None;
+        }
+        let count = self.frame_count as f64;
+        let (frame, queueing, network) = self.frame_sums;
+        Some((frame / count, queueing / count, network / count))
+    }
+}
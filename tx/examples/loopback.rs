@@ -0,0 +1,82 @@
+//! Replay the first stream of a manifest over in-memory links straight into the receiver,
+//! exercising the whole tx -> rx pipeline inside one process. Link emulation parameters apply as usual.
+//!
+//! Usage: cargo run -p stream-replay-tx --example loopback -- <manifest_file> <duration>
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use clap::Parser;
+use core::socket::create_udp_socket;
+use core::emulator::EmulatedTransport;
+use core::transport::{loopback, Transport};
+use stream_replay_rx::destination::{transport_recv_thread, Args};
+use stream_replay_rx::record::RecvData;
+use stream_replay_tx::conf::{Manifest, StreamParam};
+use stream_replay_tx::source::SourceManager;
+use stream_replay_tx::LatestBus;
+
+// Packets each in-memory link holds before reporting WouldBlock
+const LINK_CAPACITY: usize = 64;
+
+fn main() {
+    let argv: Vec<String> = std::env::args().collect();
+    let (manifest_file, duration) = match argv.as_slice() {
+        [_, manifest_file, duration] => (manifest_file.clone(), duration.parse::<f64>().expect("invalid duration")),
+        _ => {
+            eprintln!("Usage: {} <manifest_file> <duration>", argv[0]);
+            std::process::exit(1);
+        }
+    };
+    std::fs::create_dir_all("logs").unwrap();
+
+    let file = std::fs::File::open(&manifest_file).unwrap();
+    let manifest: Manifest = serde_json::from_reader(std::io::BufReader::new(file)).unwrap();
+    let root = Path::new(&manifest_file).parent();
+    let stream = manifest.streams.into_iter()
+        .find_map(|x| x.validate(root, duration))
+        .expect("no valid stream in manifest");
+    let (StreamParam::UDP(ref params) | StreamParam::TCP(ref params)) = stream;
+    let (port, links) = (params.port, params.links.clone());
+
+    // rx side: one receive thread per link sharing the same records
+    let args = Args::parse_from(["stream-replay-rx".to_string(), port.to_string(), (duration as u32).to_string(),
+                                 "--calc-owd".to_string(), "--calc-rtt".to_string()]);
+    let recv_data = Arc::new(Mutex::new(RecvData::new()));
+    let lock = Arc::new(Mutex::new(false));
+    let pong_socket = create_udp_socket(192, String::from("0.0.0.0")).expect("Error creating pong socket");
+    pong_socket.set_nonblocking(true).unwrap();
+
+    let src_addr: SocketAddr = "127.0.0.1:0".parse().unwrap();
+    let mut socket_infos: HashMap<usize, Box<dyn Transport>> = HashMap::new();
+    for (link_id, link) in links.iter().enumerate() {
+        let (tx, rx) = loopback(LINK_CAPACITY, src_addr);
+        let tx: Box<dyn Transport> = match link.emulation {
+            Some(ref emulation) => Box::new(EmulatedTransport::new(emulation.clone(), Box::new(tx)).expect("Failed to emulate link")),
+            None => Box::new(tx),
+        };
+        socket_infos.insert(link_id, tx);
+        let (args, recv_data, lock) = (args.clone(), Arc::clone(&recv_data), Arc::clone(&lock));
+        let pong_socket = pong_socket.try_clone().unwrap();
+        std::thread::spawn(move || transport_recv_thread(args, rx, recv_data, lock, pong_socket));
+    }
+
+    // tx side
+    let mut source = SourceManager::with_transports(stream, manifest.window_size, LatestBus::new(false), socket_infos);
+    source.start(1, String::from("0.0.0.0"));
+    std::thread::sleep(Duration::from_secs_f64(duration));
+
+    let recv_data = recv_data.lock().unwrap();
+    let non_received = recv_data.last_seq - recv_data.recevied;
+    println!("Received Bytes: {:.3} MB", recv_data.data_len as f64 / 1024.0 / 1024.0);
+    println!("Packet loss rate: {:.5}", non_received as f64 / recv_data.last_seq.max(1) as f64);
+    if let Some((frame, queueing, network)) = recv_data.owd.get_frame_delay() {
+        println!("Frame one-way delay: {:.3} ms (sender queueing {:.3} ms, network {:.3} ms)", frame * 1e3, queueing * 1e3, network * 1e3);
+    }
+    if let Some(stat) = source.statistics() {
+        println!("Tx RTT: {:.3} ms, throughput {:.3} Mbps", stat.rtt * 1e3, stat.throughput);
+    }
+    std::process::exit(0);
+}
@@ -0,0 +1,171 @@
+use serde::{Deserialize, Serialize};
+
+const fn _default_codel_target() -> f64 { 0.005 }
+const fn _default_codel_interval() -> f64 { 0.1 }
+const fn _default_pie_target() -> f64 { 0.015 }
+const fn _default_pie_tupdate() -> f64 { 0.015 }
+const fn _default_pie_alpha() -> f64 { 0.125 }
+const fn _default_pie_beta() -> f64 { 1.25 }
+
+/// Queue discipline of the throttler buffer. Whatever the discipline, a full buffer drops
+/// the incoming fragments, except with `DropHead` and `WholeFrame`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(tag = "type")]
+pub enum AqmParams {
+    /// Drop incoming fragments once the buffer is full.
+    #[default]
+    DropTail,
+    /// Drop the oldest fragments to make room.
+    DropHead,
+    /// Drop an incoming frame as a whole when it does not fit.
+    WholeFrame,
+    /// Drop the head frame once fragments have queued longer than `target` for `interval` (RFC 8289).
+    CoDel {
+        #[serde(default = "_default_codel_target")]   //default:
+        target: f64,                                 //         0.005 s
+        #[serde(default = "_default_codel_interval")] //default:
+        interval: f64,                               //         0.1 s
+    },
+    /// Drop incoming frames with a probability driven by the queueing delay (RFC 8033).
+    Pie {
+        #[serde(default = "_default_pie_target")]  //default:
+        target: f64,                              //         0.015 s
+        #[serde(default = "_default_pie_tupdate")] //default:
+        tupdate: f64,                             //         0.015 s between probability updates
+        #[serde(default = "_default_pie_alpha")]   //default:
+        alpha: f64,                               //         0.125
+        #[serde(default = "_default_pie_beta")]    //default:
+        beta: f64,                                //         1.25
+    },
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DropReason {
+    Tail,
+    Head,
+    Frame,
+    CoDel,
+    Pie,
+    Expired,
+}
+
+/// Fragments dropped from the throttler buffer, by reason.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct QueueDrops {
+    pub tail: usize,
+    pub head: usize,
+    pub frame: usize,
+    pub codel: usize,
+    pub pie: usize,
+    pub expired: usize,        // past the frame deadline
+    pub expired_frames: usize, // frames any fragment of which expired
+    pub expired_bytes: usize,
+    pub bytes: usize,          // payload of all of them
+}
+
+impl QueueDrops {
+    pub fn count(&mut self, reason: DropReason, bytes: usize) {
+        let counter = match reason {
+            DropReason::Tail => &mut self.tail,
+            DropReason::Head => &mut self.head,
+            DropReason::Frame => &mut self.frame,
+            DropReason::CoDel => &mut self.codel,
+            DropReason::Pie => &mut self.pie,
+            DropReason::Expired => {
+                self.expired_bytes += bytes;
+                &mut self.expired
+            }
+        };
+        *counter += 1;
+        self.bytes += bytes;
+    }
+}
+
+/// Sojourn-time state of `CoDel` and `Pie`; the overflow disciplines are stateless.
+#[derive(Debug)]
+pub struct Aqm {
+    pub params: AqmParams,
+    // CoDel
+    first_above: Option<f64>,
+    dropping: bool,
+    drop_next: f64,
+    count: u32,
+    // PIE
+    prob: f64,
+    qdelay_old: f64,
+    last_update: f64,
+}
+
+impl Aqm {
+    pub fn new(params: AqmParams) -> Self {
+        Aqm { params, first_above: None, dropping: false, drop_next: 0.0, count: 0, prob: 0.0, qdelay_old: 0.0, last_update: 0.0 }
+    }
+
+    pub fn reset(&mut self) {
+        *self = Aqm::new(self.params);
+    }
+
+    /// Whether the frame at the head, queued for `sojourn`, is to be dropped as it is dequeued at `now`.
+    pub fn drop_on_dequeue(&mut self, sojourn: f64, now: f64) -> bool {
+        let AqmParams::CoDel { target, interval } = self.params else { return false };
+        let control_law = |t: f64, count: u32| t + interval / (count as f64).sqrt();
+
+        let above = match self.first_above {
+            _ if sojourn < target => {
+                self.first_above = None;
+                false
+            }
+            None => {
+                self.first_above = Some(now + interval);
+                false
+            }
+            Some(first_above) => now >= first_above,
+        };
+        if self.dropping {
+            if !above {
+                self.dropping = false;
+            } else if now >= self.drop_next {
+                self.count += 1;
+                self.drop_next = control_law(self.drop_next, self.count);
+                return true;
+            }
+            false
+        } else if above {
+            // Resume near the previous drop rate if dropping stopped only recently
+            self.dropping = true;
+            self.count = if self.count > 2 && now - self.drop_next < 16.0 * interval { self.count - 2 } else { 1 };
+            self.drop_next = control_law(now, self.count);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Whether an incoming frame is to be dropped at `now`, with the head of the queue waiting for `qdelay`.
+    pub fn drop_on_enqueue(&mut self, qdelay: f64, now: f64) -> bool {
+        let AqmParams::Pie { target, tupdate, alpha, beta } = self.params else { return false };
+        if now - self.last_update >= tupdate {
+            // Smaller steps while the probability is low
+            let scale = match self.prob {
+                p if p < 0.000001 => 1.0 / 2048.0,
+                p if p < 0.00001 => 1.0 / 512.0,
+                p if p < 0.0001 => 1.0 / 128.0,
+                p if p < 0.001 => 1.0 / 32.0,
+                p if p < 0.01 => 1.0 / 8.0,
+                p if p < 0.1 => 1.0 / 2.0,
+                _ => 1.0,
+            };
+            let delta = scale * (alpha * (qdelay - target) + beta * (qdelay - self.qdelay_old));
+            self.prob = (self.prob + delta).clamp(0.0, 1.0);
+            // Decay while the queue stays empty
+            if qdelay == 0.0 && self.qdelay_old == 0.0 {
+                self.prob *= 0.98;
+            }
+            (self.qdelay_old, self.last_update) = (qdelay, now);
+        }
+        if self.qdelay_old < target / 2.0 && self.prob < 0.2 {
+            return false;
+        }
+        rand::random::<f64>() < self.prob
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use core::ack::FragmentAck;
+use core::packet::PacketWithMeta;
+use crate::policies::LinkState;
+
+pub type GuardedArqBuffer = Arc<Mutex<ArqBuffer>>;
+
+// Window of the reported retransmission rate (s)
+static RATE_WINDOW: f64 = 1.0;
+
+#[derive(Debug, Clone, Copy)]
+struct SentFragment {
+    packet: PacketWithMeta,
+    link: usize,
+    sent_at: f64,
+}
+
+#[derive(Debug)]
+struct SentFrame {
+    deadline: f64,
+    fragments: Vec<Option<SentFragment>>, // by offset, `None` once received
+}
+
+/// Sent fragments kept until their frame is acked complete or its `target_rtt` deadline passes,
+/// and the ones fragment acks report missing, due for retransmission.
+#[derive(Debug)]
+pub struct ArqBuffer {
+    target_rtt: f64,
+    frames: HashMap<u32, SentFrame>,
+    due: VecDeque<PacketWithMeta>,
+    recent: VecDeque<(f64, usize)>, // (time, bytes) of the retransmissions in RATE_WINDOW
+    pub retransmissions: usize,
+}
+
+impl ArqBuffer {
+    pub fn new(target_rtt: f64) -> Self {
+        ArqBuffer { target_rtt, frames: HashMap::new(), due: VecDeque::new(), recent: VecDeque::new(), retransmissions: 0 }
+    }
+
+    pub fn on_sent(&mut self, packet: &PacketWithMeta, now: f64) {
+        if packet.offset == 0 {
+            self.frames.retain(|_, frame| frame.deadline > now);
+        }
+        let deadline = packet.arrival_time + self.target_rtt;
+        let frame = self.frames.entry(packet.seq)
+            .or_insert_with(|| SentFrame { deadline, fragments: vec![None; packet.num] });
+        if let Some(entry) = frame.fragments.get_mut(packet.offset as usize) {
+            *entry = Some(SentFragment { packet: *packet, link: packet.channel, sent_at: now });
+        }
+    }
+
+    pub fn on_retransmitted(&mut self, packet: &PacketWithMeta, now: f64) {
+        self.retransmissions += 1;
+        self.recent.push_back((now, packet.length as usize));
+        let fragment = self.frames.get_mut(&packet.seq)
+            .and_then(|frame| frame.fragments.get_mut(packet.offset as usize))
+            .and_then(|entry| entry.as_mut());
+        if let Some(fragment) = fragment {
+            (fragment.link, fragment.sent_at) = (packet.channel, now);
+        }
+    }
+
+    /// Queue the fragments `ack` reports missing, unless they may still be in flight on their link.
+    pub fn on_ack(&mut self, ack: &FragmentAck, now: f64, links: &[LinkState]) {
+        let Some(frame) = self.frames.get_mut(&ack.seq) else { return };
+        if ack.complete || frame.deadline <= now {
+            self.frames.remove(&ack.seq);
+            return;
+        }
+        for (offset, entry) in frame.fragments.iter_mut().enumerate() {
+            if ack.received.get(offset).copied().unwrap_or(false) {
+                *entry = None;
+            }
+        }
+        let num = frame.fragments.len();
+        for offset in ack.missing(num) {
+            let Some(fragment) = frame.fragments[offset].as_mut() else { continue };
+            let in_flight = links.get(fragment.link)
+                .and_then(|state| Some(state.srtt? + state.rttvar.unwrap_or(0.0)))
+                .unwrap_or(0.0);
+            if now - fragment.sent_at > in_flight {
+                fragment.sent_at = now;
+                let mut packet = fragment.packet;
+                packet.set_retransmission();
+                self.due.push_back(packet);
+            }
+        }
+    }
+
+    /// Fragments due for retransmission whose frame can still make its deadline.
+    pub fn take_due(&mut self, now: f64) -> Vec<PacketWithMeta> {
+        let frames = &self.frames;
+        self.due.drain(..)
+            .filter(|packet| frames.get(&packet.seq).is_some_and(|frame| frame.deadline > now))
+            .collect()
+    }
+
+    pub fn rate_mbps(&mut self, now: f64) -> f64 {
+        while self.recent.front().is_some_and(|&(time, _)| now - time > RATE_WINDOW) {
+            self.recent.pop_front();
+        }
+        self.recent.iter().map(|&(_, bytes)| bytes).sum::<usize>() as f64 * 8.0 / 1e6 / RATE_WINDOW
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::packet::HeaderVersion;
+    use super::*;
+
+    // Fragment `offset` of a three-fragment frame `seq` arriving at the sender at `arrival_time`
+    fn fragment(seq: u32, offset: u16, arrival_time: f64) -> PacketWithMeta {
+        let mut packet = PacketWithMeta::new(0, HeaderVersion::default());
+        (packet.seq, packet.offset, packet.num, packet.length) = (seq, offset, 3, 1000);
+        packet.arrival_time = arrival_time;
+        packet
+    }
+
+    fn ack(seq: u32, received: &[bool]) -> FragmentAck {
+        FragmentAck { seq, complete: received.iter().all(|&r| r), early: false, received: received.to_vec(), links: Vec::new() }
+    }
+
+    // One link with a 50 ms RTT and 10 ms variation
+    fn links() -> Vec<LinkState> {
+        vec![LinkState { srtt: Some(0.05), rttvar: Some(0.01), ..Default::default() }]
+    }
+
+    fn sent_buffer() -> ArqBuffer {
+        let mut arq = ArqBuffer::new(0.2);
+        for offset in 0..3 {
+            arq.on_sent(&fragment(1, offset, 0.0), 0.01);
+        }
+        arq
+    }
+
+    #[test]
+    fn missing_fragments_still_in_flight_are_not_resent() {
+        let mut arq = sent_buffer();
+        arq.on_ack(&ack(1, &[true, false, true]), 0.03, &links());
+        assert!(arq.take_due(0.03).is_empty());
+
+        // Past srtt + rttvar the fragment is resent once
+        arq.on_ack(&ack(1, &[true, false, true]), 0.08, &links());
+        let due = arq.take_due(0.08);
+        assert_eq!(due.iter().map(|packet| packet.offset).collect::<Vec<_>>(), vec![1]);
+        assert!(due[0].is_retransmission());
+        arq.on_ack(&ack(1, &[true, false, true]), 0.09, &links());
+        assert!(arq.take_due(0.09).is_empty());
+    }
+
+    #[test]
+    fn without_an_rtt_missing_fragments_are_resent_at_once() {
+        let mut arq = sent_buffer();
+        arq.on_ack(&ack(1, &[false, true, false]), 0.02, &[]);
+        assert_eq!(arq.take_due(0.02).len(), 2);
+    }
+
+    #[test]
+    fn fragments_past_their_deadline_are_purged() {
+        let mut arq = sent_buffer();
+        arq.on_ack(&ack(1, &[true, false, true]), 0.1, &links());
+        // Due before the deadline, but not taken until after it
+        assert!(arq.take_due(0.21).is_empty());
+
+        // A late ack drops the frame, and so does the next frame without an ack
+        arq.on_ack(&ack(1, &[true, false, true]), 0.25, &links());
+        assert!(arq.frames.is_empty());
+        let mut arq = sent_buffer();
+        arq.on_sent(&fragment(2, 0, 0.25), 0.25);
+        assert_eq!(arq.frames.len(), 1);
+    }
+
+    #[test]
+    fn a_complete_frame_is_forgotten() {
+        let mut arq = sent_buffer();
+        arq.on_ack(&ack(1, &[true; 3]), 0.1, &links());
+        assert!(arq.frames.is_empty());
+    }
+}
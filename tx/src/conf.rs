@@ -3,10 +3,18 @@ use rand::prelude::*;
 use rand::distributions::Standard;
 use serde::{Serialize, Deserialize};
 
-use crate::{source::STREAM_PROTO, policies::{PolicyParameter,Policy}};
+use std::collections::HashMap;
+use core::emulator::{DeliveryTrace, EmulationParams};
+use core::fec::FecScheme;
+use core::packet::HeaderVersion;
+use crate::{aqm::AqmParams, limiter::LimiterParams, shaper::ShapingParams, source::STREAM_PROTO, policies::{create_policy, PolicyParameter, DEFAULT_POLICY}, utils::throttle_profile::ThrottleProfile, statistic::{link_health::LinkHealthParams, mac_backend::{LinkBackend, QueueBackend}, mac_emulator::MacEmulationParams}};
 
 const fn _default_duration() -> [f64; 2] { [0.0, f64::MAX] }
 const fn _default_loops() -> usize { usize::MAX }
+const fn _default_sync_interval() -> f64 { 1.0 }
+const fn _default_parity() -> u8 { 1 }
+const fn _default_share_weight() -> f64 { 1.0 }
+fn _default_policy() -> String { DEFAULT_POLICY.to_string() }
 fn _random_value<T>() -> T where Standard: Distribution<T> { rand::thread_rng().gen() }
 
 use serde::de::Deserializer;
@@ -14,6 +22,15 @@ use serde::de::Deserializer;
 pub struct Link {
     pub tx_ipaddr: String,
     pub rx_ipaddr: String,
+    pub emulation: Option<EmulationParams>,
+}
+
+// `[tx_ipaddr, rx_ipaddr]`, optionally followed by the link emulation parameters
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LinkEntry {
+    Plain(String, String),
+    Emulated(String, String, EmulationParams),
 }
 
 impl<'de> Deserialize<'de> for Link {
@@ -21,14 +38,62 @@ impl<'de> Deserialize<'de> for Link {
     where
         D: Deserializer<'de>,
     {
-        let arr: [String; 2] = Deserialize::deserialize(deserializer)?;
-        Ok(Link {
-            tx_ipaddr: arr[0].clone(),
-            rx_ipaddr: arr[1].clone(),
-        })
+        let link = match LinkEntry::deserialize(deserializer)? {
+            LinkEntry::Plain(tx_ipaddr, rx_ipaddr) => Link { tx_ipaddr, rx_ipaddr, emulation: None },
+            LinkEntry::Emulated(tx_ipaddr, rx_ipaddr, emulation) => Link { tx_ipaddr, rx_ipaddr, emulation: Some(emulation) },
+        };
+        Ok(link)
     }
 }
 
+/// A tx interface and the backends its MAC statistics are read from.
+#[derive(Serialize, Debug, Clone)]
+pub struct TxDevice {
+    pub ipaddr: String,
+    pub queue_backend: QueueBackend,
+    pub link_backend: LinkBackend,
+}
+
+// Either a bare address using the default backends, or an object naming them
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TxDeviceEntry {
+    Plain(String),
+    Configured {
+        ipaddr: String,
+        #[serde(default)] queue_backend: QueueBackend,
+        #[serde(default)] link_backend: LinkBackend,
+    },
+}
+
+impl<'de> Deserialize<'de> for TxDevice {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let device = match TxDeviceEntry::deserialize(deserializer)? {
+            TxDeviceEntry::Plain(ipaddr) => TxDevice { ipaddr, queue_backend: QueueBackend::default(), link_backend: LinkBackend::default() },
+            TxDeviceEntry::Configured { ipaddr, queue_backend, link_backend } => TxDevice { ipaddr, queue_backend, link_backend },
+        };
+        Ok(device)
+    }
+}
+
+/// Parity fragments appended to every frame.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct FecParams {
+    pub scheme: FecScheme,
+    #[serde(default = "_default_parity")] //default:
+    pub parity: u8,                        //         1 fragment per frame
+}
+
+/// Frames still queued `deadline` seconds after they arrived are dropped instead of sent.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct ExpiryParams {
+    #[serde(default)] pub deadline: f64,   //default: target_rtt
+    #[serde(default)] pub partial: bool,   //default: false, also drop the rest of a frame partly sent
+}
+
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct ConnParams {
     pub npy_file: String,
@@ -44,17 +109,32 @@ pub struct ConnParams {
     pub loops: usize,                       //         +inf
     #[serde(default)] pub tos: u8,          //default: 0
     #[serde(default)] pub throttle: f64,    //default: 0.0
+    #[serde(default)] pub throttle_profile: Option<ThrottleProfile>, //default: none
+    #[serde(default)] pub limiter: LimiterParams, //default: Windowed
+    #[serde(default)] pub aqm: AqmParams,   //default: DropTail
+    #[serde(default)] pub expiry: Option<ExpiryParams>, //default: none, stale frames are sent anyway
+    #[serde(default = "_default_share_weight")] //default:
+    pub share_weight: f64,                  //         1.0, share of the manifest `shaping` caps
     #[serde(default)] pub priority: String, //default: ""
     #[serde(default)] pub calc_rtt: bool,   //default: false
+    #[serde(default)] pub arq: bool,        //default: false, retransmit lost fragments before target_rtt
+    #[serde(default)] pub fec: Option<FecParams>, //default: none
+    #[serde(default)] pub duplicate: bool,  //default: false, send every fragment on all links
+    #[serde(default)] pub link_health: LinkHealthParams, //default: see LinkHealthParams
     #[serde(default)] pub no_logging: bool, //default: false
     #[serde(default)] pub links: Vec<Link>, //default: [[]]
-    #[serde(default)] pub policy: Policy,   //default: ""
+    #[serde(default = "_default_policy")]   //default:
+    pub policy: String,                     //         HardThreshold
     #[serde(default)] pub policy_parameters: PolicyParameter,   //default: []
+    #[serde(default)] pub header_version: HeaderVersion,        //default: V2
+    #[serde(default = "_default_sync_interval")] //default:
+    pub sync_interval: f64,                 //         1.0 s, 0 disables clock sync
 }
 
 
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(tag = "type")]
+#[allow(clippy::upper_case_acronyms)]
 pub enum StreamParam {
     TCP(ConnParams),
     UDP(ConnParams)
@@ -70,28 +150,93 @@ impl std::fmt::Display for StreamParam {
         let _file:String = _param.npy_file.clone();
 
         write!(f,
-            "{type} {{ port: {port}, tos: {tos}, throttle: {throttle} Mbps, file: \"{file}\", loops: {loops}, with Policy {policy:?} }}",
+            "{type} {{ port: {port}, tos: {tos}, throttle: {throttle} Mbps, file: \"{file}\", loops: {loops}, with Policy {policy} }}",
             type=_type, port=_param.port, tos=_param.tos, throttle=_param.throttle, loops=_param.loops as isize, file=_file, policy=_param.policy
         )
     }
 }
 
+// `path` as found from the working directory, or else from the manifest's directory
+fn resolve_path(root: Option<&Path>, path: &str) -> Option<String> {
+    let cwd = std::env::current_dir().unwrap();
+    let path_trail1 = cwd.join( path );
+    let path_trail2 = root.unwrap_or( cwd.as_path() ).join( path );
+    if path_trail1.exists() {
+        Some( String::from( path_trail1.to_str().unwrap() ) )
+    }
+    else if path_trail2.exists() {
+        Some( String::from( path_trail2.to_str().unwrap() ) )
+    }
+    else {
+        eprintln!("File not found: {}", path);
+        None
+    }
+}
+
 impl StreamParam {
     pub fn validate(mut self, root:Option<&Path>, duration:f64) -> Option<Self> {
         let ( Self::TCP(ref mut param) | Self::UDP(ref mut param) ) = self;
 
         // validate npy file existence
         if !param.npy_file.starts_with(STREAM_PROTO) {
-            let cwd = std::env::current_dir().unwrap();
-            let path_trail1 = cwd.join( &param.npy_file );
-            let path_trail2 = root.unwrap_or( cwd.as_path() ).join( &param.npy_file );
-            if path_trail1.exists() {
-                param.npy_file = String::from( path_trail1.to_str().unwrap() );
+            param.npy_file = resolve_path(root, &param.npy_file)?;
+        }
+
+        // validate delivery traces of emulated links
+        for emulation in param.links.iter_mut().filter_map(|link| link.emulation.as_mut()) {
+            if let Some(ref mut trace) = emulation.trace {
+                *trace = resolve_path(root, trace)?;
+                match DeliveryTrace::load(&trace) {
+                    Ok(loaded) => emulation.loaded_trace = Some(loaded),
+                    Err(e) => {
+                        eprintln!("Invalid delivery trace {}: {}", trace, e);
+                        return None;
+                    }
+                }
             }
-            else if path_trail2.exists() {
-                param.npy_file = String::from( path_trail2.to_str().unwrap() );
+        }
+
+        // validate throttle profile, read from its file if given
+        if let Some(ref mut profile) = param.throttle_profile {
+            if let Some(ref mut file) = profile.file {
+                *file = resolve_path(root, file)?;
+            }
+            if let Err(e) = profile.load() {
+                eprintln!("Invalid throttle profile: {}", e);
+                return None;
             }
-            else {
+        }
+
+        if param.links.is_empty() {
+            eprintln!("Stream {} has no links", param.port);
+            return None;
+        }
+
+        // ARQ learns about lost fragments from the acks on the pong channel
+        if param.arq {
+            param.calc_rtt = true;
+        }
+
+        if let Some(ref mut expiry) = param.expiry {
+            if expiry.deadline <= 0.0 {
+                expiry.deadline = param.target_rtt;
+            }
+        }
+
+        // The FEC layout travels in the V3 header
+        if param.fec.is_some_and(|fec| fec.parity == 0) {
+            param.fec = None;
+        }
+        if param.fec.is_some() {
+            param.header_version = HeaderVersion::V3;
+        }
+
+        // validate the policy and its parameters, as the stream would create it
+        match create_policy(&param.policy, &param.policy_parameters, param.links.len()) {
+            // Policies learning from the frame pongs need them
+            Ok(policy) => param.calc_rtt |= policy.needs_feedback(),
+            Err(e) => {
+                eprintln!("Invalid scheduling policy: {}", e);
                 return None;
             }
         }
@@ -123,6 +268,10 @@ pub struct Manifest {
     pub orchestrator: Option<String>,
     pub window_size: usize,
     pub streams: Vec<StreamParam>,
-    pub tx_ipaddrs: Vec<String>,
+    pub tx_ipaddrs: Vec<TxDevice>,
     pub ipc_port: Option<u16>,
+    #[serde(default)]
+    pub emulated_mac: HashMap<String, MacEmulationParams>, // keyed by tx_ipaddr
+    #[serde(default)]
+    pub shaping: ShapingParams,
 }
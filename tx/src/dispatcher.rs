@@ -1,25 +1,53 @@
 use std::collections::HashMap;
+use std::io;
 use core::socket::{*};
+use core::emulator::EmulatedTransport;
+use core::transport::{TcpTransport, Transport, UdpTransport};
 
 use crate::conf::Link;
 use crate::source::SocketInfo;
+use crate::statistic::mac_emulator::MacEmulator;
 
-pub fn dispatch(links: Vec<Link>, tos:u8) -> SocketInfo {
+/// Open one transport per link; UDP sends datagrams to `<rx_ipaddr>:<port>`, TCP connects there.
+/// Links with emulation parameters are wrapped in an `EmulatedTransport`, and links leaving an
+/// emulated device queue in its MAC first. Every link is tried, and the stream fails with the
+/// first error if any of them could not be opened.
+pub fn dispatch(links: Vec<Link>, tos:u8, port: u16, tcp: bool, emulated_mac: &MacEmulator) -> io::Result<SocketInfo> {
     // create Hashmap for each tx_ipaddr and set each non blocking
     let mut socket_infos = HashMap::new();
+    let mut failure = None;
 
     for (link_id, link) in links.iter().enumerate() {
         let tx_ipaddr = link.tx_ipaddr.clone();
-        let rx_addr =  format!("{}",link.rx_ipaddr.clone());
-        let socket = create_udp_socket(tos, tx_ipaddr);
-        if let Some(socket) = socket {
-            socket.set_nonblocking(true).unwrap();
-            socket_infos.insert(link_id,  (socket, rx_addr));
-        }
-        else{
-            eprintln!("Socket creation failure: ip_addr {:?} tos {}.", link, tos);
-            break;
+        let rx_addr = format!("{}:{}", link.rx_ipaddr, port);
+        let transport: io::Result<Box<dyn Transport>> = match tcp {
+            false => create_udp_socket(tos, tx_ipaddr).map(|socket| {
+                socket.set_nonblocking(true).unwrap();
+                Box::new(UdpTransport::new(socket, rx_addr.clone())) as _
+            }).ok_or_else(|| io::Error::other(format!("socket creation failure on {} with tos {}", link.tx_ipaddr, tos))),
+            true => create_tcp_stream(tos, tx_ipaddr, rx_addr.clone()).map(|stream| {
+                stream.set_nodelay(true).unwrap();
+                stream.set_nonblocking(true).unwrap();
+                Box::new(TcpTransport::new(stream)) as _
+            }),
+        };
+        let transport = transport.and_then(|transport| match link.emulation {
+            Some(ref emulation) => Ok(Box::new(EmulatedTransport::new(emulation.clone(), transport)?) as _),
+            None => Ok(transport),
+        });
+        match transport {
+            Ok(transport) => {
+                let transport = emulated_mac.attach(&link.tx_ipaddr, tos, transport);
+                socket_infos.insert(link_id, transport);
+            }
+            Err(e) => {
+                eprintln!("Link {} from {} to {} failed: {}", link_id, link.tx_ipaddr, rx_addr, e);
+                failure.get_or_insert(e);
+            }
         }
     }
-    socket_infos
-}
\ No newline at end of file
+    match failure {
+        Some(e) => Err(e),
+        None => Ok(socket_infos),
+    }
+}
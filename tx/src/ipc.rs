@@ -1,22 +1,36 @@
-use std::{net::UdpSocket, collections::HashMap, time::{Duration, SystemTime}};
+use std::{net::UdpSocket, collections::{BTreeMap, HashMap}, time::{Duration, SystemTime}};
 use serde::{Serialize, Deserialize};
-use crate::{policies::PolicyParameter, source::SourceManager, statistic::mac_queue::MACQueuesSnapshot};
+use core::clock::now_secs_f64;
+use core::socket::TcpInfoSample;
+use crate::{aqm::QueueDrops, policies::{LinkState, PolicyParameter}, shaper::{AggregateStatistics, GuardedShaper}, source::SourceManager, statistic::{link_health::{LinkEvent, LinkHealth}, mac_queue::MACQueuesSnapshot}};
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct FlowStatistics {
     pub rtt: f64,
     pub outage_rate: f64,
     pub throughput: f64,
+    pub instant_throughput: f64,      // Mbps over the latest few packets
     pub throttle: f64,
     pub bitrate: u64,
     pub app_buff: usize,
     pub frame_count: usize,
+    pub drops: QueueDrops,            // fragments dropped from the application buffer, by reason
+    pub clock_offset: f64, // rx clock - tx clock (s)
+    pub clock_drift: f64,  // offset change per second
+    pub tcp_info: BTreeMap<usize, TcpInfoSample>, // by TCP link id, empty for UDP
+    pub links: Vec<LinkState>,        // per link RTT and loss, empty without calc_rtt
+    pub retransmissions: usize,       // fragments retransmitted by ARQ
+    pub retransmit_throughput: f64,   // Mbps of them over the last second, included in `throughput`
+    pub link_health: Vec<LinkHealth>, // per link
+    pub link_events: Vec<LinkEvent>,  // latest health transitions, oldest first
 }
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
 pub struct Statistics {
     pub flow_stat: HashMap<String, FlowStatistics>,
     pub device_stat: MACQueuesSnapshot,
+    pub link_stat: HashMap<String, AggregateStatistics>, // all streams together, keyed by tx_ipaddr
+    pub total_stat: AggregateStatistics,
 }
 
 #[derive(Serialize, Deserialize, Debug,Clone)]
@@ -52,12 +66,18 @@ struct Response {
 pub struct IPCDaemon {
     ipc_port: u16,
     tx_ipaddr: String,
-    sources: HashMap<String, SourceManager>
+    sources: HashMap<String, SourceManager>,
+    shaper: Option<GuardedShaper>,
 }
 
 impl IPCDaemon {
     pub fn new(sources: HashMap<String, SourceManager>, ipc_port: u16, tx_ipaddr:String) -> Self {
-        Self{ sources, ipc_port, tx_ipaddr }
+        Self{ sources, ipc_port, tx_ipaddr, shaper: None }
+    }
+
+    /// Report the aggregate rates of `shaper` along with the statistics.
+    pub fn set_shaper(&mut self, shaper: GuardedShaper) {
+        self.shaper = Some(shaper);
     }
 
     fn handle_request(&self, req:Request) -> Option<Response> {
@@ -66,46 +86,47 @@ impl IPCDaemon {
                 let _:Vec<_> = data.iter().map(|(name, value)| {
                     self.sources[name].throttle(*value);
                 }).collect();
-                return None;
+                None
             },
 
             RequestValue::PolicyParameters(data) => {
                 let _:Vec<_> = data.iter().map(|(name, value)| {
-                    self.sources[name].set_policy_parameters(*value);
+                    self.sources[name].set_policy_parameters(value.clone());
                 }).collect();
-                return None;
+                None
             },
 
             RequestValue::Version(data) => {
                 let _:Vec<_>  = data.iter().map(|(name, value)| {
                     self.sources[name].set_version(*value);
                 }).collect();
-                return None;
+                None
             },
 
             RequestValue::Control(data) => {
                 let _:Vec<_> = data.iter().map(|(name, value)| {
                     self.sources[name].set_version(value.version);
-                    self.sources[name].set_policy_parameters(value.policy_parameters);
+                    self.sources[name].set_policy_parameters(value.policy_parameters.clone());
                 }).collect();
-                return None;
+                None
             },
 
             RequestValue::Statistics(_)  => {
-                let flow_stat = Some( self.sources.iter().filter_map(|(name,src)| {
-                    match src.statistics() {
-                        Some(stat) => Some(( name.clone(), stat )),
-                        None => None
-                    }
-                }).collect() ).unwrap();
+                let flow_stat = self.sources.iter().filter_map(|(name,src)| {
+                    src.statistics().map(|stat| ( name.clone(), stat ))
+                }).collect();
 
                 //get device statistics from only one source
                 let first_source = self.sources.values().next().unwrap();
                 let device_stat = first_source.device_statistics();
 
-                return Some(Response{ cmd: ResponseValue::Statistics(
-                    Statistics{ flow_stat, device_stat }
-                ) });
+                let (link_stat, total_stat) = self.shaper.as_ref()
+                    .map(|shaper| shaper.lock().unwrap().statistics(now_secs_f64()))
+                    .unwrap_or_default();
+
+                Some(Response{ cmd: ResponseValue::Statistics(
+                    Statistics{ flow_stat, device_stat, link_stat, total_stat }
+                ) })
             }
         }
     }
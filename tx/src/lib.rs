@@ -1,12 +1,20 @@
 mod throttle;
+mod limiter;
+mod aqm;
+mod shaper;
 mod dispatcher;
 mod rtt;
+mod arq;
 mod tx_part_ctl;
 mod statistic;
 mod utils;
-mod policies;
 mod version_manager;
 
 pub mod conf;
 pub mod source;
 pub mod ipc;
+pub mod policies;
+
+pub use statistic::mac_queue::LatestBus;
+pub use statistic::mac_emulator::{mon_emulated_mac_thread, MacEmulator};
+pub use statistic::mac_replay::{mon_replay_thread, MacReplay};
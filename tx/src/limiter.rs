@@ -0,0 +1,301 @@
+use std::collections::VecDeque;
+use serde::{Deserialize, Serialize};
+use core::packet::UDP_MAX_LENGTH;
+
+// Latest sends the instantaneous rate is measured over
+static INSTANT_PACKETS: usize = 8;
+// Window of the averaged rate of the token bucket and pacing (s)
+static AVERAGE_WINDOW: f64 = 1.0;
+
+const fn _default_burst_bytes() -> usize { 10 * UDP_MAX_LENGTH }
+
+/// Algorithm holding a stream to its `throttle` rate.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+#[serde(tag = "type")]
+pub enum LimiterParams {
+    /// Average over the latest `window_size` packets stays below the throttle.
+    #[default]
+    Windowed,
+    /// Tokens refill at the throttle rate up to `burst_bytes`; a packet spends its size.
+    TokenBucket {
+        #[serde(default = "_default_burst_bytes")] //default:
+        burst_bytes: usize,                        //         10 full packets
+    },
+    /// Packets are spaced by their transmission time at the throttle rate.
+    Pacing,
+}
+
+/// Decides when the next packet may go out at `rate_mbps`; a rate of zero means unlimited.
+pub trait RateLimiter: Send + std::fmt::Debug {
+    /// Seconds to wait before `size_bytes` may be sent at `now`, zero if it may go now.
+    fn wait(&mut self, size_bytes: usize, rate_mbps: f64, now: f64) -> f64;
+
+    fn on_sent(&mut self, size_bytes: usize, rate_mbps: f64, now: f64);
+
+    /// (instantaneous, averaged) sending rate in Mbps.
+    fn rates_mbps(&self, now: f64) -> (f64, f64);
+
+    fn reset(&mut self);
+}
+
+pub fn create_limiter(params: LimiterParams, window_size: usize) -> Box<dyn RateLimiter> {
+    match params {
+        LimiterParams::Windowed => Box::new(Windowed::new(window_size)),
+        LimiterParams::TokenBucket { burst_bytes } => Box::new(TokenBucket::new(burst_bytes)),
+        LimiterParams::Pacing => Box::new(Pacing::default()),
+    }
+}
+
+fn bytes_per_sec(rate_mbps: f64) -> f64 {
+    rate_mbps * 1e6 / 8.0
+}
+
+/// Sends over the latest `AVERAGE_WINDOW`, and at least the latest `INSTANT_PACKETS`.
+#[derive(Debug, Default)]
+struct RateMeter {
+    sent: VecDeque<(f64, usize)>,
+}
+
+impl RateMeter {
+    fn on_sent(&mut self, size_bytes: usize, now: f64) {
+        self.sent.push_back((now, size_bytes));
+        while self.sent.len() > INSTANT_PACKETS && self.sent.front().is_some_and(|&(time, _)| now - time > AVERAGE_WINDOW) {
+            self.sent.pop_front();
+        }
+    }
+
+    fn instant_mbps(&self, now: f64) -> f64 {
+        let recent = self.sent.iter().skip(self.sent.len().saturating_sub(INSTANT_PACKETS));
+        let (first, bytes) = recent.fold((now, 0), |(first, bytes), &(time, size)| (first.min(time), bytes + size));
+        match now - first {
+            elapsed if elapsed > 0.0 => bytes as f64 * 8.0 / 1e6 / elapsed,
+            _ => 0.0,
+        }
+    }
+
+    fn average_mbps(&self, now: f64) -> f64 {
+        let bytes: usize = self.sent.iter().filter(|&&(time, _)| now - time <= AVERAGE_WINDOW).map(|&(_, size)| size).sum();
+        bytes as f64 * 8.0 / 1e6 / AVERAGE_WINDOW
+    }
+}
+
+#[derive(Debug)]
+struct Windowed {
+    window: VecDeque<(f64, usize)>,
+    window_size: usize,
+    sum_bytes: usize,
+    meter: RateMeter,
+}
+
+impl Windowed {
+    fn new(window_size: usize) -> Self {
+        Windowed { window: VecDeque::with_capacity(window_size), window_size, sum_bytes: 0, meter: RateMeter::default() }
+    }
+}
+
+impl RateLimiter for Windowed {
+    fn wait(&mut self, size_bytes: usize, rate_mbps: f64, now: f64) -> f64 {
+        let Some(&(first, _)) = self.window.front() else { return 0.0 };
+        if rate_mbps <= 0.0 {
+            return 0.0;
+        }
+        // Until the window average with this packet drops below the rate
+        let needed = (self.sum_bytes + size_bytes) as f64 / bytes_per_sec(rate_mbps);
+        (needed - (now - first)).max(0.0)
+    }
+
+    fn on_sent(&mut self, size_bytes: usize, _rate_mbps: f64, now: f64) {
+        self.window.push_back((now, size_bytes));
+        self.sum_bytes += size_bytes;
+        if self.window.len() > self.window_size {
+            if let Some((_, size)) = self.window.pop_front() {
+                self.sum_bytes -= size;
+            }
+        }
+        self.meter.on_sent(size_bytes, now);
+    }
+
+    fn rates_mbps(&self, now: f64) -> (f64, f64) {
+        let average = match self.window.front() {
+            Some(&(first, _)) if now > first => self.sum_bytes as f64 * 8.0 / 1e6 / (now - first),
+            _ => 0.0,
+        };
+        (self.meter.instant_mbps(now), average)
+    }
+
+    fn reset(&mut self) {
+        *self = Windowed::new(self.window_size);
+    }
+}
+
+#[derive(Debug)]
+struct TokenBucket {
+    burst_bytes: f64,
+    tokens: f64, // bytes, negative after a packet larger than what was left
+    updated: f64,
+    meter: RateMeter,
+}
+
+impl TokenBucket {
+    fn new(burst_bytes: usize) -> Self {
+        let burst_bytes = burst_bytes.max(1) as f64;
+        TokenBucket { burst_bytes, tokens: burst_bytes, updated: 0.0, meter: RateMeter::default() }
+    }
+}
+
+impl RateLimiter for TokenBucket {
+    fn wait(&mut self, size_bytes: usize, rate_mbps: f64, now: f64) -> f64 {
+        if rate_mbps <= 0.0 {
+            (self.tokens, self.updated) = (self.burst_bytes, now);
+            return 0.0;
+        }
+        let refill = (now - self.updated).max(0.0) * bytes_per_sec(rate_mbps);
+        (self.tokens, self.updated) = ((self.tokens + refill).min(self.burst_bytes), now);
+        // A packet above the burst goes out on a full bucket and leaves it in debt
+        let needed = (size_bytes as f64).min(self.burst_bytes);
+        ((needed - self.tokens) / bytes_per_sec(rate_mbps)).max(0.0)
+    }
+
+    fn on_sent(&mut self, size_bytes: usize, _rate_mbps: f64, now: f64) {
+        self.tokens -= size_bytes as f64;
+        self.meter.on_sent(size_bytes, now);
+    }
+
+    fn rates_mbps(&self, now: f64) -> (f64, f64) {
+        (self.meter.instant_mbps(now), self.meter.average_mbps(now))
+    }
+
+    fn reset(&mut self) {
+        *self = TokenBucket::new(self.burst_bytes as usize);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Pacing {
+    next: f64, // earliest time of the next send
+    meter: RateMeter,
+}
+
+impl RateLimiter for Pacing {
+    fn wait(&mut self, _size_bytes: usize, rate_mbps: f64, now: f64) -> f64 {
+        match rate_mbps > 0.0 {
+            true => (self.next - now).max(0.0),
+            false => 0.0,
+        }
+    }
+
+    fn on_sent(&mut self, size_bytes: usize, rate_mbps: f64, now: f64) {
+        // A late send may be caught up on by one interval, time idle beyond that is not made up for with a burst
+        if rate_mbps > 0.0 {
+            let interval = size_bytes as f64 / bytes_per_sec(rate_mbps);
+            self.next = self.next.max(now - interval) + interval;
+        }
+        self.meter.on_sent(size_bytes, now);
+    }
+
+    fn rates_mbps(&self, now: f64) -> (f64, f64) {
+        (self.meter.instant_mbps(now), self.meter.average_mbps(now))
+    }
+
+    fn reset(&mut self) {
+        *self = Pacing::default();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 8 Mbps is 1e6 bytes per second, so 1000 bytes take 1 ms
+    const RATE: f64 = 8.0;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 1e-9, "{} != {}", actual, expected);
+    }
+
+    #[test]
+    fn token_bucket_allows_its_burst() {
+        let mut limiter = TokenBucket::new(3000);
+        for _ in 0..3 {
+            assert_close(limiter.wait(1000, RATE, 0.0), 0.0);
+            limiter.on_sent(1000, RATE, 0.0);
+        }
+        assert_close(limiter.wait(1000, RATE, 0.0), 0.001);
+        assert_close(limiter.wait(1000, RATE, 0.0005), 0.0005);
+
+        // Idle time refills no more than the burst
+        for _ in 0..3 {
+            assert_close(limiter.wait(1000, RATE, 10.0), 0.0);
+            limiter.on_sent(1000, RATE, 10.0);
+        }
+        assert_close(limiter.wait(1000, RATE, 10.0), 0.001);
+    }
+
+    #[test]
+    fn token_bucket_sends_oversized_packets_on_a_full_bucket() {
+        let mut limiter = TokenBucket::new(3000);
+        assert_close(limiter.wait(5000, RATE, 0.0), 0.0);
+        limiter.on_sent(5000, RATE, 0.0);
+        // 2000 bytes of debt, then 1000 for the next packet
+        assert_close(limiter.wait(1000, RATE, 0.0), 0.003);
+    }
+
+    #[test]
+    fn pacing_spaces_packets_by_their_transmission_time() {
+        let mut limiter = Pacing::default();
+        assert_close(limiter.wait(1000, RATE, 0.0), 0.0);
+        limiter.on_sent(1000, RATE, 0.0);
+        assert_close(limiter.wait(1000, RATE, 0.0), 0.001);
+        assert_close(limiter.wait(1000, RATE, 0.0004), 0.0006);
+        limiter.on_sent(1000, RATE, 0.001);
+        assert_close(limiter.wait(500, RATE, 0.001), 0.001);
+
+        // After idling, one interval is caught up on and no more
+        limiter.on_sent(1000, RATE, 1.0);
+        assert_close(limiter.wait(1000, RATE, 1.0), 0.0);
+        limiter.on_sent(1000, RATE, 1.0);
+        assert_close(limiter.wait(1000, RATE, 1.0), 0.001);
+    }
+
+    #[test]
+    fn windowed_keeps_the_window_average_below_the_rate() {
+        let mut limiter = Windowed::new(4);
+        assert_close(limiter.wait(1000, RATE, 0.0), 0.0);
+        limiter.on_sent(1000, RATE, 0.0);
+        assert_close(limiter.wait(1000, RATE, 0.0), 0.002);
+        assert_close(limiter.wait(1000, RATE, 0.0015), 0.0005);
+    }
+
+    #[test]
+    fn zero_throttle_is_unlimited() {
+        for params in [LimiterParams::Windowed, LimiterParams::TokenBucket { burst_bytes: 3000 }, LimiterParams::Pacing] {
+            let mut limiter = create_limiter(params, 4);
+            for _ in 0..100 {
+                assert_close(limiter.wait(UDP_MAX_LENGTH, 0.0, 0.0), 0.0);
+                limiter.on_sent(UDP_MAX_LENGTH, 0.0, 0.0);
+            }
+            // The bucket and pacing carry no debt into a throttle set later
+            if !matches!(params, LimiterParams::Windowed) {
+                assert_close(limiter.wait(1000, RATE, 0.0), 0.0);
+            }
+        }
+    }
+
+    #[test]
+    fn rates_follow_the_sends() {
+        for params in [LimiterParams::Windowed, LimiterParams::TokenBucket { burst_bytes: 3000 }, LimiterParams::Pacing] {
+            let mut limiter = create_limiter(params, 4);
+            assert_eq!(limiter.rates_mbps(0.0), (0.0, 0.0));
+            // 1000 bytes every 100 ms is 0.08 Mbps
+            for i in 0..10 {
+                limiter.on_sent(1000, RATE, i as f64 * 0.1);
+            }
+            let (instant, average) = limiter.rates_mbps(1.0);
+            assert_close(instant, 0.08);
+            assert_close(average, 0.08);
+
+            limiter.reset();
+            assert_eq!(limiter.rates_mbps(1.0), (0.0, 0.0));
+        }
+    }
+}
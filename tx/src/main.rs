@@ -1,8 +1,12 @@
 mod conf;
 mod throttle;
+mod limiter;
+mod aqm;
+mod shaper;
 mod source;
 mod dispatcher;
 mod rtt;
+mod arq;
 mod ipc;
 mod tx_part_ctl;
 mod statistic;
@@ -12,6 +16,8 @@ mod version_manager;
 
 use std::collections::HashMap;
 use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use log::info;
 
 use core::logger::init_log;
@@ -19,12 +25,14 @@ use std::time::SystemTime;
 // use std::rc::Rc;
 
 use clap::Parser;
-use serde_json;
 
 use crate::conf::Manifest;
 use crate::ipc::IPCDaemon;
+use crate::shaper::Shaper;
 use crate::source::SourceManager;
-use crate::statistic::mac_queue::{mon_mac_thread, LatestBus, MACQueueMonitor};
+use crate::statistic::mac_emulator::{mon_emulated_mac_thread, MacEmulator};
+use crate::statistic::mac_queue::{mon_mac_thread, LatestBus, MACQueueMonitor, SnapshotPublisher};
+use crate::statistic::mac_replay::{mon_replay_thread, MacReplay};
 
 
 #[derive(Parser, Debug)]
@@ -42,6 +50,26 @@ struct ProgArgs {
     /// Start the MAC queue monitor or not
     #[clap(long, action)]
     mon_mac: bool,
+    /// MAC monitor sampling interval (unit: seconds)
+    #[clap(long, default_value_t = 0.01)]
+    mon_interval: f64,
+    /// Record every MAC snapshot to logs/mac-snapshots.jsonl
+    #[clap(long, action)]
+    mon_log: bool,
+    /// Drive the MAC statistics from a recorded snapshot log instead of the monitor
+    #[clap(long)]
+    mac_replay: Option<String>,
+    /// Playback speed of `--mac-replay` relative to the recording
+    #[clap(long, default_value_t = 1.0, value_parser = parse_replay_speed)]
+    replay_speed: f64,
+}
+
+fn parse_replay_speed(arg: &str) -> Result<f64, String> {
+    match arg.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        Ok(_) => Err("must be a positive number".into()),
+        Err(e) => Err(e.to_string()),
+    }
 }
 
 fn main() {
@@ -59,25 +87,52 @@ fn main() {
     let ipc_port = manifest.ipc_port.unwrap_or(11112);
     println!("Sliding Window Size: {}.", window_size);
 
-    let mac_monitor = MACQueueMonitor::new(&manifest.tx_ipaddrs);
-    let mac_info_bus = LatestBus::new(args.mon_mac);
+    // A recording replaces both the driver statistics and the emulated devices
+    let mac_replay = args.mac_replay.as_ref().map(|path| MacReplay::load(path).expect("Failed to load MAC snapshot log"));
+    let emulated_mac = match mac_replay {
+        Some(_) => MacEmulator::default(),
+        None => MacEmulator::new(&manifest.emulated_mac),
+    };
+    let mac_info_bus = LatestBus::new(args.mon_mac || !emulated_mac.is_empty() || mac_replay.is_some());
 
     // spawn the source thread
-    let mut sources:HashMap<_,_> = streams.into_iter().map(|stream| {
-        let src = SourceManager::new(stream, window_size, mac_info_bus.clone());
+    let shaper = Arc::new(Mutex::new(Shaper::new(manifest.shaping)));
+    let mut sources:HashMap<_,_> = streams.into_iter().filter_map(|stream| {
+        let name = stream.name();
+        let src = match SourceManager::new(stream, window_size, mac_info_bus.clone(), &emulated_mac) {
+            Ok(src) => src,
+            Err(e) => {
+                eprintln!("Stream {} not started: {}", name, e);
+                return None;
+            }
+        };
+        src.set_shaper(&shaper);
         let name = src.name.clone();
-        (name, src)
+        Some((name, src))
     }).collect();
     let _handles:Vec<_> = sources.iter_mut().enumerate().map(|(i,(_name,src))| {
         src.start(i+1, String::from("0.0.0.0"))
     }).collect();
 
-    if args.mon_mac {
-        mon_mac_thread(mac_monitor, mac_info_bus);
+    // Emulated devices replace the driver statistics
+    if let Some(replay) = mac_replay {
+        println!("Replaying {:.3} s of MAC snapshots at {}x.", replay.duration(), args.replay_speed);
+        mon_replay_thread(replay, mac_info_bus, args.replay_speed);
+    }
+    else {
+        let publisher = SnapshotPublisher::new(mac_info_bus, Duration::from_secs_f64(args.mon_interval),
+            args.mon_log.then_some("logs/mac-snapshots.jsonl"));
+        if !emulated_mac.is_empty() {
+            mon_emulated_mac_thread(emulated_mac, publisher);
+        }
+        else if args.mon_mac {
+            mon_mac_thread(MACQueueMonitor::new(&manifest.tx_ipaddrs), publisher);
+        }
     }
 
     // start global IPC
-    let ipc = IPCDaemon::new( sources, ipc_port, String::from("0.0.0.0"));
+    let mut ipc = IPCDaemon::new( sources, ipc_port, String::from("0.0.0.0"));
+    ipc.set_shaper(shaper);
     ipc.start_loop( args.duration);
 
     std::process::exit(0); //force exit
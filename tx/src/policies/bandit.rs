@@ -0,0 +1,255 @@
+use std::collections::HashMap;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde::Deserialize;
+use crate::policies::{PolicyContext, PolicyParameter, SchedulingMessage, SchedulingPolicy};
+use core::packet::PacketType;
+
+const fn _default_exploration() -> f64 { 2.0 }
+const fn _default_discount() -> f64 { 1.0 }
+// Shares of the first link tried when no arms are given
+const DEFAULT_FIRST_SHARES: [f32; 5] = [1.0, 0.75, 0.5, 0.25, 0.0];
+// Frames awaiting feedback beyond this many are forgotten
+const MAX_PENDING: usize = 1024;
+
+#[derive(Debug, Clone, Copy, Default, Deserialize)]
+#[allow(clippy::upper_case_acronyms)]
+enum BanditAlgorithm {
+    #[default]
+    UCB,
+    Thompson,
+}
+
+#[derive(Debug, Deserialize)]
+struct BanditParams {
+    #[serde(default)] arms: Vec<Vec<f32>>,         //default: first link carrying 100%, 75%, .. 0%, the rest split evenly
+    #[serde(default)] algorithm: BanditAlgorithm,  //default: UCB
+    #[serde(default = "_default_exploration")]
+    exploration: f64,                              //default: 2.0, UCB confidence scale
+    #[serde(default = "_default_discount")]
+    discount: f64,                                 //default: 1.0, < 1 forgets old frames to track changing links
+    #[serde(default)] seed: Option<u64>,           //default: from entropy
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+struct ArmStats {
+    hits: f64,
+    trials: f64,
+}
+
+/// Learns the split of each frame over the links from whether frames meet `target_rtt`.
+/// Every arm is a split ratio; one arm is drawn per frame with UCB1 or Thompson sampling.
+#[derive(Debug)]
+pub struct Bandit {
+    params: BanditParams,
+    arms: Vec<Vec<f32>>,   // normalised per-link shares
+    stats: Vec<ArmStats>,
+    pending: HashMap<usize, usize>, // seq -> arm
+    frame: (usize, usize), // (seq, arm) being scheduled
+    rng: StdRng,
+}
+
+impl Default for Bandit {
+    fn default() -> Self {
+        let params = BanditParams {
+            arms: Vec::new(), algorithm: BanditAlgorithm::default(),
+            exploration: _default_exploration(), discount: _default_discount(), seed: None,
+        };
+        Bandit { params, arms: Vec::new(), stats: Vec::new(), pending: HashMap::new(), frame: (usize::MAX, 0), rng: StdRng::from_entropy() }
+    }
+}
+
+impl Bandit {
+    fn select(&mut self) -> usize {
+        let total: f64 = self.stats.iter().map(|s| s.trials).sum();
+        let scores: Vec<f64> = self.stats.iter().map(|s| match self.params.algorithm {
+            BanditAlgorithm::UCB if s.trials <= 0.0 => f64::INFINITY,
+            BanditAlgorithm::UCB => s.hits / s.trials + (self.params.exploration * total.max(1.0).ln() / s.trials).sqrt(),
+            BanditAlgorithm::Thompson => sample_beta(&mut self.rng, 1.0 + s.hits, 1.0 + s.trials - s.hits),
+        }).collect();
+        scores.iter().enumerate()
+            .max_by(|a, b| a.1.total_cmp(b.1))
+            .map_or(0, |(arm, _)| arm)
+    }
+}
+
+impl SchedulingPolicy for Bandit {
+    fn configure(&mut self, params: &PolicyParameter) -> Result<(), String> {
+        let params: BanditParams = params.parse()?;
+        if params.arms.iter().any(|arm| arm.iter().any(|&share| share < 0.0) || arm.iter().sum::<f32>() <= 0.0) {
+            return Err("every arm needs non-negative shares with a positive sum".into());
+        }
+        if !(params.discount > 0.0 && params.discount <= 1.0) {
+            return Err("discount must be in (0, 1]".into());
+        }
+        let reseed = params.seed != self.params.seed;
+        let rearm = params.arms != self.params.arms;
+        self.params = params;
+        if reseed {
+            self.rng = self.params.seed.map_or_else(StdRng::from_entropy, StdRng::seed_from_u64);
+        }
+        // New arms need fresh statistics
+        if rearm && !self.arms.is_empty() {
+            self.init(self.arms[0].len());
+        }
+        Ok(())
+    }
+
+    fn init(&mut self, link_num: usize) {
+        let arms = if self.params.arms.is_empty() {
+            DEFAULT_FIRST_SHARES.iter().map(|&first| {
+                match link_num {
+                    0 => Vec::new(),
+                    1 => vec![1.0],
+                    _ => std::iter::once(first).chain(std::iter::repeat_n((1.0 - first) / (link_num - 1) as f32, link_num - 1)).collect(),
+                }
+            }).collect()
+        } else {
+            self.params.arms.clone()
+        };
+        self.arms = arms.into_iter().map(|mut arm| {
+            arm.resize(link_num, 0.0);
+            let sum: f32 = arm.iter().sum();
+            arm.iter().map(|share| share / sum).collect()
+        }).collect();
+        self.reset();
+    }
+
+    fn reset(&mut self) {
+        self.stats = vec![ArmStats::default(); self.arms.len()];
+        self.pending.clear();
+        self.frame = (usize::MAX, 0);
+    }
+
+    fn needs_feedback(&self) -> bool { true }
+
+    fn on_feedback(&mut self, seq: usize, hit: bool) {
+        let Some(arm) = self.pending.remove(&seq) else { return };
+        for stats in self.stats.iter_mut() {
+            stats.hits *= self.params.discount;
+            stats.trials *= self.params.discount;
+        }
+        self.stats[arm].trials += 1.0;
+        if hit {
+            self.stats[arm].hits += 1.0;
+        }
+    }
+
+    fn get_packet_state(&mut self, msg: &SchedulingMessage, _ctx: &PolicyContext) -> PacketType {
+        if self.arms.first().is_none_or(|arm| arm.len() != msg.link_num()) {
+            self.init(msg.link_num());
+        }
+        if self.frame.0 != msg.seq {
+            let arm = self.select();
+            self.frame = (msg.seq, arm);
+            if self.pending.len() >= MAX_PENDING {
+                self.pending.clear();
+            }
+            self.pending.insert(msg.seq, arm);
+        }
+
+        // Consecutive fragments fill the links in order, each up to its share of the frame
+        let position = (msg.offset as f32 + 0.5) / msg.num as f32;
+        let shares = &self.arms[self.frame.1];
+        let mut cumulative = 0.0;
+        let link = shares.iter()
+            .position(|share| { cumulative += share; position < cumulative })
+            .unwrap_or_else(|| shares.iter().rposition(|&share| share > 0.0).unwrap_or(0));
+        PacketType::new(link, msg.is_last())
+    }
+}
+
+// Marsaglia-Tsang gamma sampler, shape >= 1
+fn sample_gamma(rng: &mut StdRng, shape: f64) -> f64 {
+    let d = shape - 1.0 / 3.0;
+    let c = 1.0 / (9.0 * d).sqrt();
+    loop {
+        // Box-Muller transform
+        let (u1, u2): (f64, f64) = (1.0 - rng.gen::<f64>(), rng.gen());
+        let x = (-2.0 * u1.ln()).sqrt() * (2.0 * std::f64::consts::PI * u2).cos();
+        let v = (1.0 + c * x).powi(3);
+        if v <= 0.0 {
+            continue;
+        }
+        let u: f64 = 1.0 - rng.gen::<f64>();
+        if u.ln() < 0.5 * x * x + d - d * v + d * v.ln() {
+            return d * v;
+        }
+    }
+}
+
+fn sample_beta(rng: &mut StdRng, alpha: f64, beta: f64) -> f64 {
+    let x = sample_gamma(rng, alpha);
+    let y = sample_gamma(rng, beta);
+    x / (x + y)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+    use super::*;
+
+    fn message(seq: usize, offset: usize, num: usize) -> SchedulingMessage {
+        SchedulingMessage {
+            seq, offset, num, arrival_time: 0.0, current_time: SystemTime::UNIX_EPOCH,
+            blocked_signals: vec![false; 2], ac1_info: vec![0; 2], mcs_values: None,
+        }
+    }
+
+    fn policy(params: serde_json::Value) -> Bandit {
+        let params: PolicyParameter = serde_json::from_value(params).unwrap();
+        let mut policy = Bandit::default();
+        policy.configure(&params).unwrap();
+        policy.init(2);
+        policy
+    }
+
+    // Send single-fragment frames `seqs`, each a hit only on `good_link`; the frames per link
+    fn run(policy: &mut Bandit, seqs: std::ops::Range<usize>, good_link: usize) -> [usize; 2] {
+        let ctx = PolicyContext { now: 0.0, frame_rtt: None, links: Vec::new(), mac_age: None };
+        let mut counts = [0; 2];
+        for seq in seqs {
+            let link = policy.get_packet_state(&message(seq, 0, 1), &ctx).link();
+            counts[link] += 1;
+            policy.on_feedback(seq, link == good_link);
+        }
+        counts
+    }
+
+    #[test]
+    fn arms_split_the_frame_in_order() {
+        let mut policy = policy(serde_json::json!({"arms": [[0.5, 0.5]]}));
+        let ctx = PolicyContext { now: 0.0, frame_rtt: None, links: Vec::new(), mac_age: None };
+        let links: Vec<usize> = (0..4).map(|offset| policy.get_packet_state(&message(0, offset, 4), &ctx).link()).collect();
+        assert_eq!(links, vec![0, 0, 1, 1]);
+    }
+
+    #[test]
+    fn ucb_tries_every_arm_then_settles_on_the_best() {
+        let mut policy = policy(serde_json::json!({"arms": [[1, 0], [0, 1]]}));
+        assert_eq!(run(&mut policy, 0..2, 1), [1, 1]);
+        let counts = run(&mut policy, 2..502, 1);
+        assert!(counts[1] > 450, "{:?}", counts);
+    }
+
+    #[test]
+    fn thompson_settles_on_the_best_arm() {
+        let mut policy = policy(serde_json::json!({"arms": [[1, 0], [0, 1]], "algorithm": "Thompson", "seed": 7}));
+        run(&mut policy, 0..100, 0);
+        let counts = run(&mut policy, 100..500, 0);
+        assert!(counts[0] > 380, "{:?}", counts);
+    }
+
+    #[test]
+    fn a_discount_follows_a_change_of_the_best_arm() {
+        let mut discounted = policy(serde_json::json!({"arms": [[1, 0], [0, 1]], "algorithm": "Thompson", "seed": 7, "discount": 0.9}));
+        let mut undiscounted = policy(serde_json::json!({"arms": [[1, 0], [0, 1]], "algorithm": "Thompson", "seed": 7}));
+        run(&mut discounted, 0..500, 0);
+        run(&mut undiscounted, 0..500, 0);
+        // The second link becomes the better one
+        let counts = run(&mut discounted, 500..600, 1);
+        let undiscounted_counts = run(&mut undiscounted, 500..600, 1);
+        assert!(counts[1] > 80, "{:?}", counts);
+        assert!(undiscounted_counts[1] < 30, "{:?}", undiscounted_counts);
+    }
+}
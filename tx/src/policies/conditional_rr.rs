@@ -1,13 +1,20 @@
-use crate::policies::{SchedulingMessage, PolicyParameter};
+use crate::policies::{PolicyContext, PolicyParameter, SchedulingMessage, SchedulingPolicy};
 use core::packet::PacketType;
 
 
-pub fn get_packet_state(params: SchedulingMessage, _policy_parameters: &PolicyParameter) -> PacketType {
-    let is_last = params.offset == params.num - 1;
-    if params.blocked_signals[0] && !params.blocked_signals[1] {
-        if is_last { return PacketType::LastPacketInSecondLink }
-        else { return PacketType::SecondLink }
+#[derive(Debug, Default)]
+pub struct ConditionalRR;
+
+impl SchedulingPolicy for ConditionalRR {
+    fn configure(&mut self, _params: &PolicyParameter) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn get_packet_state(&mut self, msg: &SchedulingMessage, _ctx: &PolicyContext) -> PacketType {
+        // Stay on the first link unless it is blocked; fall over to the next unblocked one
+        let link = msg.blocked_signals.iter()
+            .position(|blocked| !blocked)
+            .unwrap_or(0);
+        PacketType::new(link, msg.is_last())
     }
-    if is_last { return PacketType::LastPacketInFirstLink }
-    else { return PacketType::FirstLink }
-}
\ No newline at end of file
+}
@@ -0,0 +1,179 @@
+use serde::Deserialize;
+use crate::policies::{PolicyContext, PolicyParameter, SchedulingMessage, SchedulingPolicy};
+use core::packet::{PacketType, MAX_PAYLOAD_LEN};
+
+const fn _default_rate_mbps() -> f64 { 10.0 }
+const fn _default_max_mac_age() -> f64 { 0.1 }
+
+#[derive(Debug, Deserialize)]
+struct EarliestDeliveryParams {
+    #[serde(default)] rates_mbps: Vec<f64>,    //default: MAC tx bitrate, else `default_rate_mbps`
+    #[serde(default = "_default_rate_mbps")]
+    default_rate_mbps: f64,                    //default: 10.0
+    #[serde(default)] owd: Vec<f64>,           //default: 0.0 s, used until an RTT is measured
+    #[serde(default = "_default_max_mac_age")]
+    max_mac_age: f64,                          //default: 0.1 s, older MAC backlog is ignored
+}
+
+/// Sends each fragment on the link predicted to deliver it first, from the estimated
+/// per-link rate, backlog and one-way delay.
+#[derive(Debug)]
+pub struct EarliestDelivery {
+    params: EarliestDeliveryParams,
+    free_at: Vec<f64>,     // when each link has sent what was scheduled on it
+    frame: (usize, f64),   // (seq, predicted arrival of its latest fragment so far)
+    pending: Option<(usize, usize, usize, f64, f64)>, // (seq, offset, link, sent, arrival) until the fragment is sent
+}
+
+impl Default for EarliestDelivery {
+    fn default() -> Self {
+        let params = EarliestDeliveryParams {
+            rates_mbps: Vec::new(), default_rate_mbps: _default_rate_mbps(), owd: Vec::new(), max_mac_age: _default_max_mac_age(),
+        };
+        EarliestDelivery { params, free_at: Vec::new(), frame: (usize::MAX, 0.0), pending: None }
+    }
+}
+
+impl EarliestDelivery {
+    fn rate_mbps(&self, msg: &SchedulingMessage, link: usize) -> f64 {
+        let rate = self.params.rates_mbps.get(link).copied()
+            .or_else(|| msg.mcs_values.as_ref().and_then(|mcs| mcs.get(link)).map(|&mcs| mcs as f64))
+            .unwrap_or(self.params.default_rate_mbps);
+        if rate > 0.0 { rate } else { self.params.default_rate_mbps }
+    }
+
+    fn owd(&self, ctx: &PolicyContext, link: usize) -> f64 {
+        ctx.links.get(link).and_then(|state| state.srtt)
+            .or(ctx.frame_rtt)
+            .map(|rtt| rtt / 2.0)
+            .or_else(|| self.params.owd.get(link).copied())
+            .unwrap_or(0.0)
+    }
+}
+
+impl SchedulingPolicy for EarliestDelivery {
+    fn configure(&mut self, params: &PolicyParameter) -> Result<(), String> {
+        let params: EarliestDeliveryParams = params.parse()?;
+        if params.default_rate_mbps <= 0.0 {
+            return Err("default_rate_mbps must be positive".into());
+        }
+        self.params = params;
+        Ok(())
+    }
+
+    fn init(&mut self, link_num: usize) {
+        self.free_at = vec![0.0; link_num];
+        self.frame = (usize::MAX, 0.0);
+        self.pending = None;
+    }
+
+    fn reset(&mut self) {
+        self.init(self.free_at.len());
+    }
+
+    fn get_packet_state(&mut self, msg: &SchedulingMessage, ctx: &PolicyContext) -> PacketType {
+        let link_num = msg.link_num();
+        if self.free_at.len() != link_num {
+            self.init(link_num);
+        }
+        if self.frame.0 != msg.seq {
+            self.frame = (msg.seq, 0.0);
+        }
+        let fresh_mac = ctx.mac_age.is_some_and(|age| age <= self.params.max_mac_age);
+        let all_blocked = msg.blocked_signals.iter().all(|&blocked| blocked);
+
+        // (link, send start, arrival) of the fragment on every usable link
+        let candidates: Vec<_> = (0..link_num)
+            .filter(|&link| all_blocked || !msg.blocked_signals[link])
+            .map(|link| {
+                let tx_time = (MAX_PAYLOAD_LEN * 8) as f64 / (self.rate_mbps(msg, link) * 1e6);
+                let backlog = if fresh_mac { msg.ac1_info.get(link).copied().unwrap_or(0) } else { 0 };
+                let start = self.free_at[link].max(ctx.now + backlog as f64 * tx_time);
+                (link, start + tx_time, start + tx_time + self.owd(ctx, link))
+            })
+            .collect();
+
+        // The last fragment completes the frame: minimise the frame finish, and among links
+        // finishing it equally early prefer the one it reaches last
+        let frame_done = self.frame.1;
+        let (link, sent, arrival) = if msg.is_last() {
+            candidates.into_iter().min_by(|a, b| {
+                a.2.max(frame_done).total_cmp(&b.2.max(frame_done)).then(b.2.total_cmp(&a.2))
+            })
+        } else {
+            candidates.into_iter().min_by(|a, b| a.2.total_cmp(&b.2))
+        }.unwrap_or((0, ctx.now, ctx.now));
+
+        // Held back or failed fragments are scheduled again, so the prediction waits for the send
+        self.pending = Some((msg.seq, msg.offset, link, sent, arrival));
+        PacketType::new(link, msg.is_last())
+    }
+
+    fn on_sent(&mut self, seq: usize, offset: usize, link: usize) {
+        let Some((pending_seq, pending_offset, pending_link, sent, arrival)) = self.pending.take() else { return };
+        if (pending_seq, pending_offset, pending_link) == (seq, offset, link) {
+            self.free_at[link] = sent;
+            if self.frame.0 == seq {
+                self.frame.1 = self.frame.1.max(arrival);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::SystemTime;
+    use super::*;
+
+    fn message(seq: usize, offset: usize, num: usize, blocked_signals: Vec<bool>) -> SchedulingMessage {
+        let link_num = blocked_signals.len();
+        SchedulingMessage {
+            seq, offset, num, arrival_time: 0.0, current_time: SystemTime::UNIX_EPOCH,
+            blocked_signals, ac1_info: vec![0; link_num], mcs_values: None,
+        }
+    }
+
+    fn context() -> PolicyContext {
+        PolicyContext { now: 0.0, frame_rtt: None, links: Vec::new(), mac_age: None }
+    }
+
+    fn policy(owd: &[f64]) -> EarliestDelivery {
+        let params = PolicyParameter { named: HashMap::from([("owd".to_string(), serde_json::json!(owd))]), ..Default::default() };
+        let mut policy = EarliestDelivery::default();
+        policy.configure(&params).unwrap();
+        policy.init(owd.len());
+        policy
+    }
+
+    #[test]
+    fn fragments_go_to_the_earliest_arrival() {
+        let mut policy = policy(&[0.02, 0.01]);
+        let msg = message(0, 0, 3, vec![false; 2]);
+        assert_eq!(policy.get_packet_state(&msg, &context()).link(), 1);
+    }
+
+    #[test]
+    fn the_last_fragment_ties_on_the_frame_finish_towards_the_later_arrival() {
+        let mut policy = policy(&[0.05, 0.01, 0.02]);
+        // The first fragment only has the slow link, which then finishes the frame
+        let first = policy.get_packet_state(&message(0, 0, 2, vec![false, true, true]), &context());
+        assert_eq!(first.link(), 0);
+        policy.on_sent(0, 0, 0);
+
+        // Links 1 and 2 both arrive before it: a middle fragment takes the earliest one...
+        assert_eq!(policy.get_packet_state(&message(0, 1, 3, vec![false; 3]), &context()).link(), 1);
+        // ...while the last one leaves it free, as the frame finishes no sooner on it
+        assert_eq!(policy.get_packet_state(&message(0, 1, 2, vec![false; 3]), &context()).link(), 2);
+    }
+
+    #[test]
+    fn the_last_fragment_minimises_the_frame_finish() {
+        let mut policy = policy(&[0.0, 0.01]);
+        policy.get_packet_state(&message(0, 0, 2, vec![false, true]), &context());
+        policy.on_sent(0, 0, 0);
+        // Link 0 is still busy with the first fragment, but arrives before link 1 all the same
+        let last = policy.get_packet_state(&message(0, 1, 2, vec![false; 2]), &context());
+        assert_eq!(last.link(), 0);
+    }
+}
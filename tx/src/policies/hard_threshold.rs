@@ -1,13 +1,30 @@
-use crate::policies::{SchedulingMessage, PolicyParameter};
+use serde::Deserialize;
+use crate::policies::{PolicyContext, PolicyParameter, SchedulingMessage, SchedulingPolicy};
 use core::packet::PacketType;
 
-pub fn get_packet_state(params: SchedulingMessage, policy_parameters: &PolicyParameter) -> PacketType {
-    let is_last = params.offset == params.num - 1;
-    if params.offset as f32 >= policy_parameters.theta_1 * params.num as f32 {
-        if is_last { PacketType::LastPacketInSecondLink }
-        else { PacketType::SecondLink }
-    } else {
-        if is_last { PacketType::LastPacketInFirstLink }
-        else { PacketType::FirstLink }
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct HardThreshold {
+    theta_1: f32, // share of each frame kept on the first link
+}
+
+impl SchedulingPolicy for HardThreshold {
+    fn configure(&mut self, params: &PolicyParameter) -> Result<(), String> {
+        *self = params.parse()?;
+        Ok(())
+    }
+
+    fn get_packet_state(&mut self, msg: &SchedulingMessage, _ctx: &PolicyContext) -> PacketType {
+        let link_num = msg.link_num();
+        let threshold = self.theta_1 * msg.num as f32;
+        // The first `theta_1` share goes to the first link, the rest is split evenly over the others
+        let link = if msg.offset as f32 >= threshold && link_num > 1 {
+            let rest = (msg.num as f32 - threshold).max(1.0);
+            let share = (msg.offset as f32 - threshold) / rest;
+            1 + ((share * (link_num - 1) as f32) as usize).min(link_num - 2)
+        } else {
+            0
+        };
+        PacketType::new(link, msg.is_last())
     }
 }
@@ -1,4 +1,7 @@
+use std::collections::HashMap;
+use std::sync::{LazyLock, RwLock};
 use std::{fmt::Debug, time::SystemTime};
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use core::packet::PacketType;
 
@@ -6,39 +9,109 @@ mod queue_aware;
 mod conditional_rr;
 mod hard_threshold;
 mod simple_queue_aware;
+mod weighted_rr;
+mod earliest_delivery;
+mod bandit;
 
-// Define the `Policy` enum
-#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
-pub enum Policy {
-    QueueAware,
-    SimpleQueueAware,
-    ConditionalRR,
-    #[default]
-    HardThreshold,
+pub const DEFAULT_POLICY: &str = "HardThreshold";
+
+/// A multipath scheduler deciding the link of every fragment.
+pub trait SchedulingPolicy: Send + Debug {
+    /// Apply parameters from the manifest or an IPC update.
+    fn configure(&mut self, params: &PolicyParameter) -> Result<(), String>;
+    /// Called once the number of links is known, before the first fragment.
+    fn init(&mut self, _link_num: usize) {}
+    /// Drop any learned state, e.g. when the stream (re)starts.
+    fn reset(&mut self) {}
+    /// Whether frame `seq` met `target_rtt`, reported once per frame on streams with `calc_rtt`.
+    fn on_feedback(&mut self, _seq: usize, _hit: bool) {}
+    /// Whether the policy learns from `on_feedback`, so that its streams need `calc_rtt`.
+    fn needs_feedback(&self) -> bool { false }
+    /// Fragment `(seq, offset)` from the latest `get_packet_state` has left on `link`.
+    fn on_sent(&mut self, _seq: usize, _offset: usize, _link: usize) {}
+    fn get_packet_state(&mut self, msg: &SchedulingMessage, ctx: &PolicyContext) -> PacketType;
 }
 
-impl Policy {
-    // This function matches the policy and calls the corresponding function for each policy
-    pub fn get_packet_state(
-        &self,
-        params: SchedulingMessage,
-        policy_parameters: &PolicyParameter,
-    ) -> PacketType {
-        match self {
-            Policy::QueueAware => queue_aware::get_packet_state(params, policy_parameters),
-            Policy::ConditionalRR => conditional_rr::get_packet_state(params, policy_parameters),
-            Policy::HardThreshold => hard_threshold::get_packet_state(params, policy_parameters),
-            Policy::SimpleQueueAware => simple_queue_aware::get_packet_state(params, policy_parameters),
-        }
+pub type PolicyFactory = fn() -> Box<dyn SchedulingPolicy>;
+
+// Schedulers selectable by the manifest `policy` string
+static REGISTRY: LazyLock<RwLock<HashMap<String, PolicyFactory>>> = LazyLock::new(|| {
+    let builtin: [(&str, PolicyFactory); 7] = [
+        ("QueueAware", || Box::<queue_aware::QueueAware>::default()),
+        ("SimpleQueueAware", || Box::<simple_queue_aware::SimpleQueueAware>::default()),
+        ("ConditionalRR", || Box::<conditional_rr::ConditionalRR>::default()),
+        ("HardThreshold", || Box::<hard_threshold::HardThreshold>::default()),
+        ("WeightedRR", || Box::<weighted_rr::WeightedRR>::default()),
+        ("EarliestDelivery", || Box::<earliest_delivery::EarliestDelivery>::default()),
+        ("Bandit", || Box::<bandit::Bandit>::default()),
+    ];
+    RwLock::new(builtin.into_iter().map(|(name, factory)| (name.to_string(), factory)).collect())
+});
+
+/// Make a scheduler available under `name`, replacing any previous one.
+#[allow(dead_code)] // for schedulers living outside this crate
+pub fn register_policy(name: &str, factory: PolicyFactory) {
+    REGISTRY.write().unwrap().insert(name.to_string(), factory);
+}
+
+pub fn create_policy(name: &str, params: &PolicyParameter, link_num: usize) -> Result<Box<dyn SchedulingPolicy>, String> {
+    let factory = REGISTRY.read().unwrap().get(name).copied().ok_or_else(|| {
+        let mut known: Vec<_> = REGISTRY.read().unwrap().keys().cloned().collect();
+        known.sort();
+        format!("unknown policy \"{}\", expected one of {:?}", name, known)
+    })?;
+    let mut policy = factory();
+    policy.configure(params).map_err(|e| format!("policy \"{}\": {}", name, e))?;
+    policy.init(link_num);
+    Ok(policy)
+}
+
+/// `theta_*` knobs shared by the built-in policies, plus any named parameters of the selected one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PolicyParameter {
+    #[serde(default)] pub theta_1: f32,
+    #[serde(default)] pub theta_2: f32,
+    #[serde(default)] pub theta_3: f32,
+    #[serde(default)] pub theta_4: f32,
+    #[serde(flatten)] pub named: HashMap<String, serde_json::Value>,
+}
+
+impl PolicyParameter {
+    // Queue-aware weight of a link: `theta_3` for the first link, `theta_4` for the others
+    pub fn link_weight(&self, link: usize) -> f32 {
+        if link == 0 { self.theta_3 } else { self.theta_4 }
+    }
+
+    /// Read the parameters into a policy's own typed struct.
+    pub fn parse<T: DeserializeOwned>(&self) -> Result<T, String> {
+        serde_json::to_value(self)
+            .and_then(serde_json::from_value)
+            .map_err(|e| e.to_string())
     }
 }
 
+/// Per-link estimates from the fragment acks, `None` until measured.
 #[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
-pub struct PolicyParameter {
-    pub theta_1: f32,
-    pub theta_2: f32,
-    pub theta_3: f32,
-    pub theta_4: f32,
+pub struct LinkState {
+    pub srtt: Option<f64>,   // smoothed round trip (s)
+    pub rttvar: Option<f64>, // round trip variation (s)
+    pub loss: Option<f64>,   // smoothed share of fragments lost
+}
+
+/// What a policy may look at beyond the fragment itself.
+#[derive(Debug, Clone, Default)]
+pub struct PolicyContext {
+    pub now: f64,
+    pub frame_rtt: Option<f64>,  // smoothed frame round trip from the pongs
+    pub links: Vec<LinkState>,   // indexed by link id
+    pub mac_age: Option<f64>,    // seconds since the MAC snapshot was taken, `None` without a monitor
+}
+
+// Index of the smallest value; ties go to the later link
+fn argmin(values: impl Iterator<Item = f32>) -> usize {
+    values.enumerate()
+        .fold((0, f32::INFINITY), |(best, min), (i, v)| if v <= min { (i, v) } else { (best, min) })
+        .0
 }
 
 #[derive(Debug, Clone)]
@@ -48,9 +121,9 @@ pub struct SchedulingMessage {
     pub num: usize,
     pub arrival_time: f64,
     pub current_time: SystemTime,
-    pub blocked_signals: Vec<bool>,   // Now included in SchedulingMessage
-    pub ac1_info: Vec<usize>,         // Store ac1_info directly in SchedulingMessage
-    pub mcs_values: Option<Vec<f32>>, // MCS values for different access categories
+    pub blocked_signals: Vec<bool>,   // One entry per link
+    pub ac1_info: Vec<usize>,         // AC1 backlog per link, indexed by link id
+    pub mcs_values: Option<Vec<f32>>, // Tx bitrate per link, indexed by link id
 }
 
 impl SchedulingMessage {
@@ -58,9 +131,9 @@ impl SchedulingMessage {
         SchedulingMessage {
             seq: packet.seq as usize,
             arrival_time: packet.arrival_time,
-            current_time: current_time,
+            current_time,
             offset: packet.offset as usize,
-            num: packet.num as usize,
+            num: packet.source_num(),
             blocked_signals,
             ac1_info,
             mcs_values,
@@ -71,17 +144,21 @@ impl SchedulingMessage {
         self.seq = packet.seq as usize;
         self.arrival_time = packet.arrival_time;
         self.offset = packet.offset as usize;
-        self.num = packet.num as usize;
+        self.num = packet.source_num();
         self.blocked_signals = blocked_signals;
     }
 
+    pub fn link_num(&self) -> usize {
+        self.blocked_signals.len()
+    }
+
+    pub fn is_last(&self) -> bool {
+        self.offset == self.num - 1
+    }
+
     pub fn update_sended_counter(&mut self, packet_type: &PacketType){
-        match packet_type {
-            PacketType::FirstLink      =>  self.ac1_info[0] += 1,
-            PacketType::SecondLink      =>  self.ac1_info[1] += 1,
-            PacketType::LastPacketInFirstLink  => self.ac1_info[0] += 1,
-            PacketType::LastPacketInSecondLink  =>  self.ac1_info[1] += 1,
-        };
+        if let Some(counter) = self.ac1_info.get_mut(packet_type.link()) {
+            *counter += 1;
+        }
     }
 }
-
@@ -1,23 +1,27 @@
-use crate::policies::{SchedulingMessage, PolicyParameter};
+use crate::policies::{PolicyContext, PolicyParameter, SchedulingMessage, SchedulingPolicy};
 use core::packet::PacketType;
 
 
-pub fn get_packet_state(params: SchedulingMessage, policy_parameters: &PolicyParameter) -> PacketType {
-    let ac1_info = params.ac1_info;
-    let mcs_values= params.mcs_values.unwrap();
-    let is_last = params.offset == params.num - 1;
+#[derive(Debug, Default)]
+pub struct SimpleQueueAware {
+    params: PolicyParameter,
+}
+
+impl SchedulingPolicy for SimpleQueueAware {
+    fn configure(&mut self, params: &PolicyParameter) -> Result<(), String> {
+        self.params = params.clone();
+        Ok(())
+    }
 
-    if parameterized_function(params.num - params.offset, ac1_info, policy_parameters, mcs_values) {
-        if is_last { return PacketType::LastPacketInFirstLink }
-        else { return PacketType::FirstLink }
-    } else {
-        if is_last { return PacketType::LastPacketInSecondLink }
-        else { return PacketType::SecondLink }
+    fn get_packet_state(&mut self, msg: &SchedulingMessage, _ctx: &PolicyContext) -> PacketType {
+        // Without a PHY rate for every link, compare the queues alone
+        let mcs_values = msg.mcs_values.clone().unwrap_or_else(|| vec![1.0; msg.link_num()]);
+        PacketType::new(parameterized_function(msg.num - msg.offset, &msg.ac1_info, &self.params, mcs_values), msg.is_last())
     }
 }
 
-fn parameterized_function(left_pkts: usize, ac1_info:Vec<usize>, policy_parameters: &PolicyParameter, mcs_values: Vec<f32>) -> bool {
-    let val1 = ((left_pkts + ac1_info[0]) as f32) / (policy_parameters.theta_3 * mcs_values[0]);
-    let val2 = ((left_pkts + ac1_info[1]) as f32) / (policy_parameters.theta_4 * mcs_values[1]);
-    return val1 < val2;
+fn parameterized_function(left_pkts: usize, ac1_info: &[usize], policy_parameters: &PolicyParameter, mcs_values: Vec<f32>) -> usize {
+    super::argmin(ac1_info.iter().zip(mcs_values).enumerate().map(|(link, (queued, mcs))| {
+        ((left_pkts + queued) as f32) / (policy_parameters.link_weight(link) * mcs)
+    }))
 }
@@ -0,0 +1,134 @@
+use serde::Deserialize;
+use crate::policies::{PolicyContext, PolicyParameter, SchedulingMessage, SchedulingPolicy};
+use core::packet::PacketType;
+
+const fn _default_quantum() -> f32 { 1.0 }
+
+#[derive(Debug, Deserialize)]
+struct WeightedRRParams {
+    #[serde(default)] weights: Vec<f32>,    //default: 1.0 for every link
+    #[serde(default = "_default_quantum")]
+    quantum: f32,                           //default: 1.0 fragment per round for the heaviest link
+}
+
+/// Deficit weighted round robin over the links, one fragment costing one unit of credit.
+#[derive(Debug)]
+pub struct WeightedRR {
+    params: WeightedRRParams,
+    deficits: Vec<f32>,
+    current: usize,
+}
+
+impl Default for WeightedRR {
+    fn default() -> Self {
+        WeightedRR { params: WeightedRRParams { weights: Vec::new(), quantum: _default_quantum() }, deficits: Vec::new(), current: 0 }
+    }
+}
+
+impl WeightedRR {
+    fn weight(&self, link: usize) -> f32 {
+        self.params.weights.get(link).copied().unwrap_or(1.0).max(0.0)
+    }
+}
+
+impl SchedulingPolicy for WeightedRR {
+    fn configure(&mut self, params: &PolicyParameter) -> Result<(), String> {
+        let params: WeightedRRParams = params.parse()?;
+        if params.quantum < 1.0 {
+            return Err("quantum must be at least one fragment".into());
+        }
+        self.params = params;
+        Ok(())
+    }
+
+    fn init(&mut self, link_num: usize) {
+        self.deficits = vec![0.0; link_num];
+        self.current = 0;
+    }
+
+    fn reset(&mut self) {
+        self.init(self.deficits.len());
+    }
+
+    fn get_packet_state(&mut self, msg: &SchedulingMessage, _ctx: &PolicyContext) -> PacketType {
+        let link_num = msg.link_num();
+        if self.deficits.len() != link_num {
+            self.init(link_num);
+        }
+        // Blocked or zero-weight links are passed over, unless nothing else is left
+        let eligible: Vec<bool> = (0..link_num)
+            .map(|link| !msg.blocked_signals[link] && self.weight(link) > 0.0)
+            .collect();
+        if !eligible.contains(&true) {
+            return PacketType::new(self.current, msg.is_last());
+        }
+        // Credit per round relative to the heaviest link, so every round serves at least one fragment
+        let max_weight = (0..link_num).filter(|&link| eligible[link]).map(|link| self.weight(link)).fold(0.0, f32::max);
+
+        while !(eligible[self.current] && self.deficits[self.current] >= 1.0) {
+            if !eligible[self.current] {
+                self.deficits[self.current] = 0.0;
+            }
+            self.current = (self.current + 1) % link_num;
+            if eligible[self.current] {
+                self.deficits[self.current] += self.params.quantum * self.weight(self.current) / max_weight;
+            }
+        }
+        self.deficits[self.current] -= 1.0;
+        PacketType::new(self.current, msg.is_last())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::SystemTime;
+    use super::*;
+
+    fn message(blocked_signals: Vec<bool>) -> SchedulingMessage {
+        let link_num = blocked_signals.len();
+        SchedulingMessage {
+            seq: 0, offset: 0, num: 1, arrival_time: 0.0, current_time: SystemTime::UNIX_EPOCH,
+            blocked_signals, ac1_info: vec![0; link_num], mcs_values: None,
+        }
+    }
+
+    fn policy(weights: &[f32]) -> WeightedRR {
+        let params = PolicyParameter { named: HashMap::from([("weights".to_string(), serde_json::json!(weights))]), ..Default::default() };
+        let mut policy = WeightedRR::default();
+        policy.configure(&params).unwrap();
+        policy.init(weights.len());
+        policy
+    }
+
+    fn picks(policy: &mut WeightedRR, msg: &SchedulingMessage, count: usize) -> Vec<usize> {
+        let ctx = PolicyContext { now: 0.0, frame_rtt: None, links: Vec::new(), mac_age: None };
+        (0..count).map(|_| policy.get_packet_state(msg, &ctx).link()).collect()
+    }
+
+    #[test]
+    fn a_light_link_carries_its_deficit_over_rounds() {
+        let mut policy = policy(&[1.0, 2.0]);
+        let msg = message(vec![false; 2]);
+        // Half a fragment of credit per round, so the first link sends every second round
+        assert_eq!(picks(&mut policy, &msg, 6), vec![1, 1, 0, 1, 1, 0]);
+    }
+
+    #[test]
+    fn fractional_weights_keep_their_share() {
+        let mut policy = policy(&[1.0, 2.5]);
+        let msg = message(vec![false; 2]);
+        let links = picks(&mut policy, &msg, 70);
+        assert_eq!(links.iter().filter(|&&link| link == 0).count(), 20);
+    }
+
+    #[test]
+    fn a_blocked_link_loses_its_carried_credit() {
+        let mut policy = policy(&[1.0, 2.0]);
+        picks(&mut policy, &message(vec![false; 2]), 2);
+        // Link 0 holds half a fragment of credit; while blocked it is dropped, not saved up
+        assert_eq!(picks(&mut policy, &message(vec![true, false]), 4), vec![1; 4]);
+        // With the half kept it would send right away, without it after a round of link 1
+        assert_eq!(picks(&mut policy, &message(vec![false; 2]), 3), vec![1, 0, 1]);
+    }
+}
@@ -3,58 +3,127 @@ use std::io::{prelude::*, BufWriter};
 use std::net::UdpSocket;
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
-use std::time::{Duration, SystemTime};
+use std::time::Duration;
 
+pub use core::clock::now_secs_f64;
+use core::ack::FragmentAck;
+use core::clock::{SyncKind, TimeSync, TIME_SYNC_LENGTH};
+use core::packet::PacketStruct;
+use log::warn;
+
+use crate::arq::GuardedArqBuffer;
+use crate::statistic::clock_sync::ClockSync;
+use crate::statistic::link_records::LinkRecords;
 use crate::statistic::rtt_records::RttRecords;
 
 pub type GuardedRttRecords = Arc<Mutex<RttRecords>>;
+pub type GuardedLinkRecords = Arc<Mutex<LinkRecords>>;
+pub type GuardedClockSync = Arc<Mutex<ClockSync>>;
 static PONG_PORT_INC: u16 = 1024;
-
-pub fn now_secs_f64() -> f64 {
-    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64()
-}
+static CLOCK_SYNC_SAMPLES: usize = 64;
 
 pub struct RttRecorder {
     recv_handle: Option<JoinHandle<()>>,
+    sync_handle: Option<JoinHandle<()>>,
     name: String,
     port: u16,
+    rx_addr: Option<String>,
+    sync_interval: f64,
     pub rtt_records: GuardedRttRecords,
+    pub link_records: GuardedLinkRecords,
+    pub clock_sync: GuardedClockSync,
+    pub arq: Option<GuardedArqBuffer>,
 }
 
 impl RttRecorder {
-    pub fn new(name: &String, port: u16, _mul_link_num: usize, target_rtt: f64) -> Self {
-        let name = name.clone();
+    pub fn new(name: &str, port: u16, link_num: usize, target_rtt: f64, rx_ipaddr: Option<&str>, sync_interval: f64) -> Self {
+        let name = name.to_string();
+        let rx_addr = rx_ipaddr.map(|ip| format!("{}:{}", ip, port)); // time sync goes to the data port
         let port = port + PONG_PORT_INC; // pong recv port
         let rtt_records = Arc::new(Mutex::new(RttRecords::new(200, target_rtt)));
+        let link_records = Arc::new(Mutex::new(LinkRecords::new(200, link_num)));
+        let clock_sync = Arc::new(Mutex::new(ClockSync::new(CLOCK_SYNC_SAMPLES)));
 
         RttRecorder {
             name,
             port,
+            rx_addr,
+            sync_interval,
             recv_handle: None,
+            sync_handle: None,
             rtt_records,
+            link_records,
+            clock_sync,
+            arq: None,
         }
     }
 
-    /// Start only the RX (pong) thread.
+    /// Start the RX (pong) thread, and the clock sync requests sharing its socket.
     pub fn start(&mut self, tx_ipaddr: String) {
         let name = self.name.clone();
-        let port = self.port;
         let rtt_for_rx = Arc::clone(&self.rtt_records);
+        let links_for_rx = Arc::clone(&self.link_records);
+        let clock_for_rx = Arc::clone(&self.clock_sync);
+        let arq_for_rx = self.arq.clone();
+        let sock = UdpSocket::bind(format!("{}:{}", tx_ipaddr, self.port)).unwrap();
+
+        if let (Some(rx_addr), true) = (self.rx_addr.clone(), self.sync_interval > 0.0) {
+            let sock = sock.try_clone().unwrap();
+            let clock_sync = Arc::clone(&self.clock_sync);
+            let interval = Duration::from_secs_f64(self.sync_interval);
+            self.sync_handle = Some(thread::spawn(move || {
+                sync_send_thread(sock, rx_addr, interval, clock_sync);
+            }));
+        }
 
         self.recv_handle = Some(thread::spawn(move || {
-            pong_recv_thread(name, port, rtt_for_rx, tx_ipaddr);
+            pong_recv_thread(name, sock, rtt_for_rx, links_for_rx, clock_for_rx, arq_for_rx);
         }));
     }
 }
 
+fn sync_send_thread(sock: UdpSocket, rx_addr: String, interval: Duration, clock_sync: GuardedClockSync) {
+    let mut buf = [0u8; TIME_SYNC_LENGTH];
+    let mut seq = 0u32;
+    loop {
+        let t1 = now_secs_f64();
+        let estimate = {
+            let clock = clock_sync.lock().unwrap();
+            clock.offset_at(t1).map(|offset| (offset, clock.drift))
+        };
+        let length = TimeSync::request(seq, t1, estimate).encode(&mut buf).unwrap();
+        if let Err(e) = sock.send_to(&buf[..length], &rx_addr) {
+            warn!("Clock sync request to {} failed: {}", rx_addr, e);
+        }
+        seq = seq.wrapping_add(1);
+        thread::sleep(interval);
+    }
+}
+
+fn handle_sync_response(buf: &[u8], clock_sync: &GuardedClockSync, logger: &mut Option<BufWriter<File>>) {
+    let t4 = now_secs_f64();
+    match TimeSync::decode(buf) {
+        Ok(sync) if sync.kind == SyncKind::Response => {
+            let mut clock = clock_sync.lock().unwrap();
+            clock.update(sync.t1, sync.t2, sync.t3, t4);
+            if let Some(ref mut w) = logger {
+                let _ = writeln!(w, "{:.6} {:.9} {:.9} {:.9}", t4, clock.offset_at(t4).unwrap_or(0.0), clock.drift, clock.delay);
+            }
+        }
+        Ok(_) => {}
+        Err(e) => warn!("Malformed clock sync response: {}", e),
+    }
+}
+
 fn pong_recv_thread(
     name: String,
-    port: u16,
+    sock: UdpSocket,
     rtt_records: GuardedRttRecords,
-    tx_ipaddr: String,
+    link_records: GuardedLinkRecords,
+    clock_sync: GuardedClockSync,
+    arq: Option<GuardedArqBuffer>,
 ) {
     let mut buf = [0u8; 2048];
-    let sock = UdpSocket::bind(format!("{}:{}", tx_ipaddr, port)).unwrap();
     // Avoid tiny packets coalescing delays on some stacks
     let _ = sock.set_read_timeout(Some(Duration::from_millis(200)));
 
@@ -62,24 +131,52 @@ fn pong_recv_thread(
         let f = File::create(format!("logs/rtt-{}.txt", name)).ok();
         f.map(BufWriter::new)
     };
+    let mut clock_logger = File::create(format!("logs/clock-{}.txt", name)).ok().map(BufWriter::new);
 
     loop {
         match sock.recv_from(&mut buf) {
-            Ok((_n, _addr)) => {
-                // seq: [0..4], delta: [19..27]
-                let seq = u32::from_le_bytes(buf[..4].try_into().unwrap());
-                let delta = f64::from_le_bytes(buf[19..27].try_into().unwrap());
+            Ok((n, _)) if TimeSync::is_time_sync(&buf[..n]) => {
+                handle_sync_response(&buf[..n], &clock_sync, &mut clock_logger);
+            }
+            Ok((n, addr)) if FragmentAck::is_fragment_ack(&buf[..n]) => {
+                let ack = match FragmentAck::decode(&buf[..n]) {
+                    Ok(ack) => ack,
+                    Err(e) => {
+                        warn!("Malformed fragment ack from {}: {}", addr, e);
+                        continue;
+                    }
+                };
+                let now = now_secs_f64();
+                let links = {
+                    let mut rec = link_records.lock().unwrap();
+                    rec.on_ack(&ack, now);
+                    rec.estimates()
+                };
+                if let Some(ref arq) = arq {
+                    arq.lock().unwrap().on_ack(&ack, now, &links);
+                }
+            }
+            Ok((n, addr)) => {
+                let (seq, deltas) = match PacketStruct::decode(&buf[..n]) {
+                    Ok(pong) => (pong.seq, pong.pong_deltas()),
+                    Err(e) => {
+                        warn!("Malformed pong from {}: {}", addr, e);
+                        continue;
+                    }
+                };
                 let pong_time = now_secs_f64();
 
                 // Update records (short lock)
                 let rtt = {
                     let mut rec = rtt_records.lock().unwrap();
-                    rec.update(seq as usize, pong_time, delta)
+                    rec.update(seq as usize, pong_time, deltas.clone())
                 };
+                let Some(rtt) = rtt else { continue };
 
                 if let Some(ref mut w) = logger {
                     // keep allocations out of the lock
-                    let _ = writeln!(w, "{} {:.6} {:.6}", seq, rtt, delta);
+                    let deltas: Vec<_> = deltas.iter().map(|d| format!("{:.6}", d)).collect();
+                    let _ = writeln!(w, "{} {:.6} {}", seq, rtt, deltas.join(" "));
                 }
             }
             Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
@@ -0,0 +1,253 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+use serde::{Deserialize, Serialize};
+use core::packet::UDP_MAX_LENGTH;
+
+pub type GuardedShaper = Arc<Mutex<Shaper>>;
+
+// Streams that asked to send within this long share a cap (s)
+static ACTIVE_WINDOW: f64 = 0.1;
+// Window of the reported aggregate rates (s)
+static RATE_WINDOW: f64 = 1.0;
+
+const fn _default_burst_bytes() -> usize { 10 * UDP_MAX_LENGTH }
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Sharing {
+    /// Every active stream is assured an equal share of a cap.
+    #[default]
+    Fair,
+    /// Shares follow the streams' `share_weight`.
+    Weighted,
+}
+
+/// Aggregate caps all streams of the process draw from, in Mbps; `0` leaves a level uncapped.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ShapingParams {
+    #[serde(default)] pub total_mbps: f64,                  //default: 0.0
+    #[serde(default)] pub links_mbps: HashMap<String, f64>, //default: {}, keyed by tx_ipaddr
+    #[serde(default)] pub sharing: Sharing,                 //default: Fair
+    #[serde(default = "_default_burst_bytes")]              //default:
+    pub burst_bytes: usize,                                 //         10 full packets
+}
+
+impl Default for ShapingParams {
+    fn default() -> Self {
+        ShapingParams { total_mbps: 0.0, links_mbps: HashMap::new(), sharing: Sharing::Fair, burst_bytes: _default_burst_bytes() }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct AggregateStatistics {
+    pub rate_mbps: f64, // all streams over the last second
+    pub cap_mbps: f64,  // 0 when uncapped
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Bucket {
+    tokens: f64, // bytes
+    updated: f64,
+}
+
+impl Bucket {
+    fn new(burst: f64) -> Self {
+        Bucket { tokens: burst, updated: 0.0 }
+    }
+
+    fn refill(&mut self, bytes_per_sec: f64, burst: f64, now: f64) {
+        let refill = (now - self.updated).max(0.0) * bytes_per_sec;
+        (self.tokens, self.updated) = ((self.tokens + refill).min(burst), now);
+    }
+}
+
+/// One cap: a pool refilled at the cap, and per stream an assured share of it. A stream
+/// sends on its share, or borrows from the pool while the pool has tokens to spare.
+#[derive(Debug)]
+struct Node {
+    cap_mbps: f64,
+    pool: Bucket,
+    assured: HashMap<usize, Bucket>,
+    asked_at: HashMap<usize, f64>,
+    sent: VecDeque<(f64, usize)>, // (time, bytes) over RATE_WINDOW
+}
+
+impl Node {
+    fn new(cap_mbps: f64, burst: f64) -> Self {
+        Node { cap_mbps, pool: Bucket::new(burst), assured: HashMap::new(), asked_at: HashMap::new(), sent: VecDeque::new() }
+    }
+
+    fn wait(&mut self, stream: usize, size_bytes: usize, now: f64, weights: &[f64], burst: f64) -> f64 {
+        self.asked_at.insert(stream, now);
+        if self.cap_mbps <= 0.0 {
+            return 0.0;
+        }
+        let weight = |id: usize| weights.get(id).copied().unwrap_or(1.0);
+        let active: f64 = self.asked_at.iter()
+            .filter(|&(_, &asked)| now - asked <= ACTIVE_WINDOW)
+            .map(|(&id, _)| weight(id))
+            .sum();
+        let cap = self.cap_mbps * 1e6 / 8.0;
+        let share = cap * weight(stream) / active.max(f64::EPSILON);
+
+        self.pool.refill(cap, burst, now);
+        let assured = self.assured.entry(stream).or_insert_with(|| Bucket::new(burst));
+        assured.refill(share, burst, now);
+        let needed = (size_bytes as f64).min(burst);
+        if assured.tokens >= needed || self.pool.tokens >= needed {
+            return 0.0;
+        }
+        ((needed - assured.tokens) / share).min((needed - self.pool.tokens) / cap)
+    }
+
+    fn on_sent(&mut self, stream: usize, size_bytes: usize, now: f64) {
+        if self.cap_mbps > 0.0 {
+            self.pool.tokens -= size_bytes as f64;
+            if let Some(assured) = self.assured.get_mut(&stream) {
+                assured.tokens -= size_bytes as f64;
+            }
+        }
+        self.sent.push_back((now, size_bytes));
+        while self.sent.front().is_some_and(|&(time, _)| now - time > RATE_WINDOW) {
+            self.sent.pop_front();
+        }
+    }
+
+    fn statistics(&self, now: f64) -> AggregateStatistics {
+        let bytes: usize = self.sent.iter().filter(|&&(time, _)| now - time <= RATE_WINDOW).map(|&(_, size)| size).sum();
+        AggregateStatistics { rate_mbps: bytes as f64 * 8.0 / 1e6 / RATE_WINDOW, cap_mbps: self.cap_mbps }
+    }
+}
+
+/// Hierarchical shaping shared by the streams: a process-wide cap above a cap per link,
+/// links being told apart by their tx address.
+#[derive(Debug)]
+pub struct Shaper {
+    params: ShapingParams,
+    total: Node,
+    links: HashMap<String, Node>,
+    weights: Vec<f64>, // by stream id
+}
+
+impl Shaper {
+    pub fn new(params: ShapingParams) -> Self {
+        let total = Node::new(params.total_mbps, params.burst_bytes as f64);
+        Shaper { params, total, links: HashMap::new(), weights: Vec::new() }
+    }
+
+    /// Add a stream with its `share_weight`, returning its id.
+    pub fn register(&mut self, weight: f64) -> usize {
+        self.weights.push(if weight > 0.0 { weight } else { 1.0 });
+        self.weights.len() - 1
+    }
+
+    /// Seconds before `stream` may send `size_bytes` on the link from `tx_ipaddr`, zero if it may now.
+    pub fn wait(&mut self, stream: usize, tx_ipaddr: &str, size_bytes: usize, now: f64) -> f64 {
+        let weights: Vec<f64> = match self.params.sharing {
+            Sharing::Fair => Vec::new(),
+            Sharing::Weighted => self.weights.clone(),
+        };
+        let burst = self.params.burst_bytes as f64;
+        let link = self.links.entry(tx_ipaddr.to_string())
+            .or_insert_with(|| Node::new(self.params.links_mbps.get(tx_ipaddr).copied().unwrap_or(0.0), burst));
+        let link_wait = link.wait(stream, size_bytes, now, &weights, burst);
+        link_wait.max(self.total.wait(stream, size_bytes, now, &weights, burst))
+    }
+
+    pub fn on_sent(&mut self, stream: usize, tx_ipaddr: &str, size_bytes: usize, now: f64) {
+        if let Some(link) = self.links.get_mut(tx_ipaddr) {
+            link.on_sent(stream, size_bytes, now);
+        }
+        self.total.on_sent(stream, size_bytes, now);
+    }
+
+    /// Aggregate rate and cap of every link used so far, and of the process.
+    pub fn statistics(&self, now: f64) -> (HashMap<String, AggregateStatistics>, AggregateStatistics) {
+        let links = self.links.iter().map(|(tx_ipaddr, link)| (tx_ipaddr.clone(), link.statistics(now))).collect();
+        (links, self.total.statistics(now))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Time step of the simulated senders (s)
+    const STEP: f64 = 0.0001;
+
+    // 8 Mbps on link "a" is 1e6 bytes per second
+    fn capped(sharing: Sharing) -> ShapingParams {
+        ShapingParams { links_mbps: HashMap::from([("a".to_string(), 8.0)]), sharing, ..Default::default() }
+    }
+
+    // Bytes each of `streams` sends on `tx_ipaddr` over `duration`, sending 1000 bytes whenever let
+    fn run(shaper: &mut Shaper, streams: &[usize], tx_ipaddr: &str, duration: f64) -> Vec<usize> {
+        let mut sent = vec![0; streams.len()];
+        for step in 0..(duration / STEP) as usize {
+            let now = step as f64 * STEP;
+            for (bytes, &stream) in sent.iter_mut().zip(streams) {
+                if shaper.wait(stream, tx_ipaddr, 1000, now) == 0.0 {
+                    shaper.on_sent(stream, tx_ipaddr, 1000, now);
+                    *bytes += 1000;
+                }
+            }
+        }
+        sent
+    }
+
+    fn assert_near(actual: usize, expected: f64) {
+        assert!((actual as f64 - expected).abs() <= 0.05 * expected, "{} not near {}", actual, expected);
+    }
+
+    #[test]
+    fn fair_sharing_splits_a_cap_evenly() {
+        let mut shaper = Shaper::new(capped(Sharing::Fair));
+        let streams = [shaper.register(1.0), shaper.register(3.0)];
+        let sent = run(&mut shaper, &streams, "a", 1.0);
+        // Each on top of its initial burst
+        let burst = _default_burst_bytes() as f64;
+        assert_near(sent[0], 0.5e6 + burst);
+        assert_near(sent[1], 0.5e6 + burst);
+    }
+
+    #[test]
+    fn weighted_sharing_follows_the_share_weights() {
+        let mut shaper = Shaper::new(capped(Sharing::Weighted));
+        let streams = [shaper.register(1.0), shaper.register(3.0)];
+        let sent = run(&mut shaper, &streams, "a", 1.0);
+        let burst = _default_burst_bytes() as f64;
+        assert_near(sent[0], 0.25e6 + burst);
+        assert_near(sent[1], 0.75e6 + burst);
+    }
+
+    #[test]
+    fn a_lone_stream_borrows_the_idle_shares() {
+        let mut shaper = Shaper::new(capped(Sharing::Weighted));
+        let streams = [shaper.register(1.0), shaper.register(3.0)];
+        // Both active, then the heavier one goes idle
+        run(&mut shaper, &streams, "a", 0.5);
+        let sent = run(&mut shaper, &streams[..1], "a", 1.0);
+        assert_near(sent[0], 1e6);
+    }
+
+    #[test]
+    fn the_total_cap_binds_across_links() {
+        let params = ShapingParams { total_mbps: 8.0, ..Default::default() };
+        let mut shaper = Shaper::new(params);
+        let stream = shaper.register(1.0);
+        let on_a = run(&mut shaper, &[stream], "a", 0.5);
+        let on_b = run(&mut shaper, &[stream], "b", 0.5);
+        assert_near(on_a[0] + on_b[0], 1e6);
+
+        let (links, total) = shaper.statistics(0.5);
+        assert_eq!(links["b"].cap_mbps, 0.0);
+        assert_eq!(total.cap_mbps, 8.0);
+    }
+
+    #[test]
+    fn uncapped_links_never_wait() {
+        let mut shaper = Shaper::new(capped(Sharing::Fair));
+        let stream = shaper.register(1.0);
+        let sent = run(&mut shaper, &[stream], "b", 0.1);
+        assert_eq!(sent[0], 1000 * 1000);
+    }
+}
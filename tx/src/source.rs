@@ -4,20 +4,25 @@ use std::io::Write;
 use std::sync::{Arc, Mutex};
 use std::thread::{self, JoinHandle};
 use std::time::{Duration, SystemTime};
-use std::net::{UdpSocket};
 use std::vec;
 use log::info;
 use ndarray::prelude::*;
 use ndarray_npy::read_npy;
 
+use core::fec::{FecHeader, FecScheme, MAX_RS_FRAGMENTS};
 use core::packet::*;
-use crate::conf::{StreamParam, ConnParams};
+use core::transport::{LinkStats, Transport};
+use crate::conf::{StreamParam, ConnParams, FecParams};
 use crate::dispatcher::dispatch;
+use crate::statistic::mac_emulator::MacEmulator;
 use crate::statistic::mac_queue::{LatestBus, MACQueuesSnapshot};
 use crate::throttle::RateThrottler;
+use crate::arq::ArqBuffer;
 use crate::rtt::{RttRecorder, GuardedRttRecords, now_secs_f64};
+use crate::shaper::GuardedShaper;
 use crate::ipc::FlowStatistics;
 use crate::policies::{PolicyParameter};
+use crate::statistic::link_health::LinkHealthMonitor;
 use crate::tx_part_ctl::TxPartCtler;
 use crate::utils::trace_reader::read_packets;
 use crate::version_manager::VersionManager;
@@ -26,9 +31,11 @@ type GuardedThrottler = Arc<Mutex<RateThrottler>>;
 type GuardedTxPartCtler = Arc<Mutex<TxPartCtler>>;
 type GuardedVersionManager = Arc<Mutex<Option<VersionManager>>>;
 
-pub type SocketInfo = HashMap<usize, (UdpSocket, String)>;
+pub type SocketInfo = HashMap<usize, Box<dyn Transport>>;
 
 pub const STREAM_PROTO: &str = "stream://";
+// Longest sleep while the aggregate caps hold a packet back (s)
+static MAX_SHAPING_WAIT: f64 = 0.001;
 
 fn generate_packets(
     _remains: usize, 
@@ -53,68 +60,189 @@ fn generate_packets(
         if let Some(buf) = buffer {
             template.set_payload(&buf[(offset as usize * MAX_PAYLOAD_LEN)..(offset as usize * MAX_PAYLOAD_LEN) + length as usize]);
         };
-        packets.push(template.clone());
+        packets.push(*template);
     }
     packets
 }
 
+// Append the frame's parity fragments; every fragment then counts them in `num`
+fn add_parity(packets: &mut Vec<PacketWithMeta>, fec: &FecParams) {
+    let source_num = packets.len();
+    // Nothing to protect in an empty frame
+    if source_num == 0 {
+        return;
+    }
+    let parity_num = match fec.scheme {
+        FecScheme::XOR => (fec.parity as usize).min(source_num),
+        FecScheme::ReedSolomon => (fec.parity as usize).min(MAX_RS_FRAGMENTS.saturating_sub(source_num)),
+    };
+    if parity_num == 0 {
+        return;
+    }
+    let header = FecHeader {
+        scheme: Some(fec.scheme),
+        source_num: source_num as u16,
+        parity_num: parity_num as u8,
+        last_len: packets[source_num - 1].length,
+    };
+    let sources: Vec<&[u8]> = packets.iter().map(|packet| &packet.payload[..packet.length as usize]).collect();
+    let parity = header.encode(&sources);
+
+    let mut template = packets[source_num - 1];
+    for (i, shard) in parity.iter().enumerate() {
+        template.set_length(shard.len() as u16);
+        template.set_payload(shard);
+        template.set_offset((source_num + i) as u16);
+        packets.push(template);
+    }
+    for packet in packets.iter_mut() {
+        packet.num = header.fragment_num();
+        packet.fec = header;
+        packet.set_offset(packet.offset);
+    }
+}
+
+// Send a copy of `packet` on each of `links`, stamped with that link
+fn send_copies(packet: &PacketWithMeta, links: &[usize], socket_infos: &mut SocketInfo, controller: &mut TxPartCtler) {
+    let mut buf = [0u8; UDP_MAX_LENGTH];
+    for &link in links {
+        let Some(sender) = socket_infos.get_mut(&link) else { continue };
+        // Copies count against the aggregate caps like any fragment, and are skipped beyond them
+        if controller.shaped_out(link, packet.length as usize, now_secs_f64()).is_some() {
+            continue;
+        }
+        let mut copy = *packet;
+        copy.set_indicator(PacketType::new(link, packet.last_one));
+        if packet.is_retransmission() {
+            copy.set_retransmission();
+        }
+        copy.send_time = now_secs_f64();
+        let length = match copy.encode(&mut buf) {
+            Ok(length) => length,
+            Err(e) => panic!("Failed to encode packet {}: {}", copy.seq, e),
+        };
+        match sender.send(&buf[..length], copy.last_one.then_some(copy.seq)) {
+            Ok(_) => controller.on_link_sent(copy.seq as usize, link, copy.length as usize, copy.send_time),
+            Err(e) => controller.on_send_error(link, e.kind(), now_secs_f64()),
+        }
+    }
+}
+
 fn process_queue(
     throttler: &GuardedThrottler, 
     tx_part_ctler: &GuardedTxPartCtler, 
-    socket_infos: &SocketInfo, 
+    socket_infos: &mut SocketInfo, 
     stop_time: &SystemTime,
     recorder: Option<&File>,
+    send_log: Option<&File>,
+    next_frame: Option<&BufferReceiver>, // stop waiting for retransmissions once a frame is here
 ) { 
-    // Precompute unix epoch once
+    let arq = tx_part_ctler.lock().unwrap().arq.clone();
     while SystemTime::now() < *stop_time {
-        // Compute current time once per iteration
-        if let Some(_) = throttler.lock().unwrap().try_consume(|mut packet| {       
-            // Get IP address with minimal lock time
-            match tx_part_ctler.lock() {
-                Ok(mut controller) => {
-                    match controller.determine_schedule_info(packet) {
-                        Some(params) => {
-                            let packet_type = controller.get_packet_state(params);
-                            packet.set_indicator(packet_type);
-                        }
-                        None => {
-                            return false;
-                        }
+        // Fragments reported lost go out ahead of the queue
+        if let Some(ref arq) = arq {
+            let due = arq.lock().unwrap().take_due(now_secs_f64());
+            if !due.is_empty() {
+                throttler.lock().unwrap().prepare_retransmit(due);
+            }
+        }
+
+        let mut shaped = None;
+        let consumed = throttler.lock().unwrap().try_consume(|mut packet| {
+            // One controller lock per fragment, for its link, the send and what follows it
+            let Ok(mut controller) = tx_part_ctler.lock() else { return false };
+            let now = now_secs_f64();
+            controller.refresh_health(now);
+
+            // Hold back while the aggregate caps leave no link to send on
+            if let Some(wait) = controller.shape(packet.length as usize, now) {
+                shaped = Some(wait);
+                return false;
+            }
+            if packet.is_retransmission() {
+                let packet_type = controller.get_retransmit_state(&packet);
+                packet.set_indicator(packet_type);
+                packet.set_retransmission();
+            } else if packet.is_parity() {
+                packet.set_indicator(controller.get_parity_state(&packet));
+            } else {
+                match controller.determine_schedule_info(packet) {
+                    Some(params) => {
+                        let packet_type = controller.get_packet_state(params);
+                        packet.set_indicator(packet_type);
                     }
-                    if controller.mac_info_bus.is_mon {
-                        info!("{:?}, {:?}", packet.channel, packet.seq as u32);
+                    None => {
+                        return false;
                     }
-                },
-                Err(_) => return false,
-            };
+                }
+                if controller.mac_info_bus.is_mon {
+                    info!("{:?}, {:?}", packet.channel, packet.seq);
+                }
+            }
+            // A link picked beyond its cap waits, even while other links have room
+            if let Some(wait) = controller.shaped_out(packet.channel, packet.length as usize, now) {
+                shaped = Some(wait);
+                return false;
+            }
 
-            // Lookup socket without holding controller lock
-            let sender = match socket_infos.get(&packet.channel) {
+            let sender = match socket_infos.get_mut(&packet.channel) {
                 Some(s) => s,
                 None => panic!("No socket found for channel {}", packet.channel),
             };
 
-            // Prepare send parameters outside match
-            let length = APP_HEADER_LENGTH + packet.length as usize;
-            let buf = packet.to_u8_slice();
-            let rx_addr = format!("{}:{}", sender.1, packet.port as usize);
-            
+            packet.send_time = now_secs_f64();
+            let mut buf = [0u8; UDP_MAX_LENGTH];
+            let length = match packet.encode(&mut buf) {
+                Ok(length) => length,
+                Err(e) => panic!("Failed to encode packet {}: {}", packet.seq, e),
+            };
+
             // Attempt to send
-            match sender.0.send_to(&buf[..length], &rx_addr) {
-                Ok(_) => true,
-                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
-                    tx_part_ctler.lock().unwrap().blocked_signals[packet.channel] = true;
-                    false
+            match sender.send(&buf[..length], packet.last_one.then_some(packet.seq)) {
+                Ok(_) => {
+                    if let Some(ref arq) = arq {
+                        match packet.is_retransmission() {
+                            true => arq.lock().unwrap().on_retransmitted(&packet, packet.send_time),
+                            false => arq.lock().unwrap().on_sent(&packet, packet.send_time),
+                        }
+                    }
+                    controller.on_link_sent(packet.seq as usize, packet.channel, packet.length as usize, packet.send_time);
+                    controller.policy.on_sent(packet.seq as usize, packet.offset as usize, packet.channel);
+                    // Down links get a copy every so often, to find out when they are back
+                    let copies = controller.copies(packet.channel, packet.send_time);
+                    if !copies.is_empty() {
+                        send_copies(&packet, &copies, socket_infos, &mut controller);
+                    }
+                    true
                 },
-                Err(_) => { tx_part_ctler.lock().unwrap().blocked_signals[packet.channel] = true; 
-                    false }
+                Err(e) => {
+                    controller.on_send_error(packet.channel, e.kind(), now_secs_f64());
+                    false
+                }
             }
-        }) {
+        });
+        if let Some(wait) = shaped {
+            // Wait with the throttler free for new frames, throttle changes and statistics
+            std::thread::sleep(Duration::from_secs_f64(wait.min(MAX_SHAPING_WAIT)));
+        } else if consumed.is_some() {
             // Continue processing next packet
+        } else if arq.is_some() && next_frame.is_none_or(|dest| dest.is_empty()) {
+            // Stay around for retransmissions until the next frame
+            std::thread::sleep(Duration::from_nanos(100_000));
         } else {
             break;
         }
     }
+    // Push out partial TCP writes and log the frames they completed
+    for (link_id, socket) in socket_infos.iter_mut() {
+        let _ = socket.flush();
+        for (seq, time) in socket.take_completed() {
+            if let Some(mut send_log) = send_log {
+                send_log.write_all(format!("{} {} {:.6}\n", seq, link_id, time).as_bytes())
+                    .expect("Failed to write to send log file");
+            }
+        }
+    }
     if let Some(mut recorder) = recorder {
         recorder.write_all(tx_part_ctler.lock().unwrap().log_str.as_bytes())
             .expect("Failed to write to recorder file");
@@ -129,10 +257,11 @@ pub fn stream_thread(
     tx_part_ctler: GuardedTxPartCtler, 
     rtt_rec: Option<GuardedRttRecords>,   // ← new
     params: ConnParams, 
-    socket_infos: SocketInfo, 
+    mut socket_infos: SocketInfo, 
+    send_log: Option<File>,
     dest: BufferReceiver,
 ) {
-    let mut template = PacketWithMeta::new(params.port);
+    let mut template = PacketWithMeta::new(params.port, params.header_version);
     let stop_time = SystemTime::now().checked_add(Duration::from_secs_f64(params.duration[1])).unwrap();
 
     while SystemTime::now() <= stop_time {
@@ -144,7 +273,10 @@ pub fn stream_thread(
         template.next_seq(num);
 
         // Generate packets
-        let packets = generate_packets( _remains, &mut template, Some(&buffer));
+        let mut packets = generate_packets( _remains, &mut template, Some(&buffer));
+        if let Some(ref fec) = params.fec {
+            add_parity(&mut packets, fec);
+        }
 
         // Append to application-layer queue
         throttler.lock().unwrap().prepare(packets);
@@ -157,7 +289,7 @@ pub fn stream_thread(
         }
 
         // Process queue
-        process_queue(&throttler, &tx_part_ctler, &socket_infos, &stop_time, None);
+        process_queue(&throttler, &tx_part_ctler, &mut socket_infos, &stop_time, None, send_log.as_ref(), Some(&dest));
     }
 
     // Reset throttler
@@ -170,14 +302,15 @@ pub fn video_thread(
     version_manager: GuardedVersionManager,
     rtt_rec: Option<GuardedRttRecords>,   // ← was Option<RttSender>
     params: ConnParams, 
-    socket_infos: SocketInfo
+    mut socket_infos: SocketInfo,
+    send_log: Option<File>,
 ) {
     let mut trace = read_packets(version_manager.lock().unwrap().as_mut().unwrap().next()).expect("loading failed.");
     let mut reload = false;
     let (start_offset, duration) = (params.start_offset, params.duration);
     let spin_sleeper = spin_sleep::SpinSleeper::new(100_000).with_spin_strategy(spin_sleep::SpinStrategy::YieldThread);
     let stop_time = SystemTime::now().checked_add(Duration::from_secs_f64(duration[1])).unwrap();
-    let mut template = PacketWithMeta::new(params.port);
+    let mut template = PacketWithMeta::new(params.port, params.header_version);
     let recorder = File::create("logs/recorder.txt").expect("Failed to create recorder file");
     let mut idx = start_offset;
     spin_sleeper.sleep(Duration::from_secs_f64(duration[0]));
@@ -193,7 +326,7 @@ pub fn video_thread(
             let size_bytes = trace[idx].1.len();
             let buffer = &trace[idx].1;
 
-            idx = idx + 1;
+            idx += 1;
             if idx == trace.len() {
                 reload = true;
             }
@@ -203,7 +336,10 @@ pub fn video_thread(
             template.next_seq(num);
 
             // Generate packets
-            let packets = generate_packets(_remains, &mut template,  Some(buffer));
+            let mut packets = generate_packets(_remains, &mut template,  Some(buffer));
+            if let Some(ref fec) = params.fec {
+                add_parity(&mut packets, fec);
+            }
 
             // Append to application-layer queue
             throttler.lock().unwrap().prepare(packets);
@@ -219,7 +355,7 @@ pub fn video_thread(
         };
 
         // Process queue
-        process_queue(&throttler, &tx_part_ctler, &socket_infos, &deadline, Some(&recorder));
+        process_queue(&throttler, &tx_part_ctler, &mut socket_infos, &deadline, Some(&recorder), send_log.as_ref(), None);
 
         // Sleep until next arrival
         if let Ok(remaining_time) = deadline.duration_since(SystemTime::now()) {
@@ -236,11 +372,12 @@ pub fn source_thread(
     tx_part_ctler: GuardedTxPartCtler, 
     rtt_rec: Option<GuardedRttRecords>,   // ← was Option<RttSender>
     params: ConnParams, 
-    socket_infos: SocketInfo
+    mut socket_infos: SocketInfo,
+    send_log: Option<File>,
 ) {
     let trace: Array2<u64> = read_npy(&params.npy_file).expect("loading failed.");
     let (start_offset, duration) = (params.start_offset, params.duration);
-    let mut template = PacketWithMeta::new(params.port);
+    let mut template = PacketWithMeta::new(params.port, params.header_version);
     let spin_sleeper = spin_sleep::SpinSleeper::new(100_000).with_spin_strategy(spin_sleep::SpinStrategy::YieldThread);
     let mut loops = 0;
     let mut idx = start_offset;
@@ -261,7 +398,10 @@ pub fn source_thread(
             template.next_seq(num);
 
             // Generate packets
-            let packets = generate_packets( _remains, &mut template, None);
+            let mut packets = generate_packets( _remains, &mut template, None);
+            if let Some(ref fec) = params.fec {
+                add_parity(&mut packets, fec);
+            }
 
             // Append to application-layer queue
             throttler.lock().unwrap().prepare(packets);
@@ -280,7 +420,7 @@ pub fn source_thread(
         };
 
         // Process queue
-        process_queue(&throttler, &tx_part_ctler, &socket_infos, &deadline, None);
+        process_queue(&throttler, &tx_part_ctler, &mut socket_infos, &deadline, None, send_log.as_ref(), None);
 
         // Sleep until next arrival
         if let Ok(remaining_time) = deadline.duration_since(SystemTime::now()) {
@@ -309,23 +449,43 @@ pub struct SourceManager{
     mac_info_bus: LatestBus,
     //
     socket_infos: Vec<SocketInfo>,
+    link_stats: Vec<(usize, Box<dyn LinkStats>)>, // by link id
+    send_log: Option<File>,
 }
 
 impl SourceManager {
-    pub fn new(stream: StreamParam, window_size:usize, mac_info_bus: LatestBus) -> Self {
+    /// Open the stream's links and build it over them, failing if any link cannot be opened.
+    pub fn new(stream: StreamParam, window_size:usize, mac_info_bus: LatestBus, emulated_mac: &MacEmulator) -> std::io::Result<Self> {
+        let (StreamParam::UDP(ref params) | StreamParam::TCP(ref params)) = stream;
+        let tcp = matches!(stream, StreamParam::TCP(_));
+        let socket_infos = dispatch(params.links.clone(), params.tos, params.port, tcp, emulated_mac)?;
+        Ok(Self::with_transports(stream, window_size, mac_info_bus, socket_infos))
+    }
+
+    /// Build the stream over caller-provided transports, keyed by link index.
+    pub fn with_transports(stream: StreamParam, window_size:usize, mac_info_bus: LatestBus, socket_infos: SocketInfo) -> Self {
         let (StreamParam::UDP(ref params) | StreamParam::TCP(ref params)) = stream;
         let mut name = stream.name();
 
-        let socket_infos = vec![dispatch(params.links.clone(), params.tos)].into();
+        // Handles to sample the links while the source thread owns them
+        let link_stats = socket_infos.iter()
+            .filter_map(|(&link_id, transport)| Some((link_id, transport.stats_handle()?)))
+            .collect();
+        let socket_infos = vec![socket_infos];
+        let tcp = matches!(stream, StreamParam::TCP(_));
         let link_num = params.links.len();
         let target_rtt = params.target_rtt;
 
-        let throttler = Arc::new(Mutex::new(
-            RateThrottler::new(name.clone(), params.throttle, window_size, params.no_logging, false)
-        ));
+        let mut throttler = RateThrottler::new(name.clone(), params.throttle, params.limiter, params.aqm, window_size, params.no_logging, false);
+        throttler.set_expiry(params.expiry);
+        let throttler = Arc::new(Mutex::new(throttler));
+        let tx_ipaddrs: Vec<String> = params.links.iter().map(|link| link.tx_ipaddr.clone()).collect();
+        let health = LinkHealthMonitor::new(params.link_health, &tx_ipaddrs,
+            (!params.no_logging).then(|| format!("logs/link-health-{}.txt", name.replace('/', "_"))).as_deref());
         let tx_part_ctler = Arc::new(Mutex::new(
-            TxPartCtler::new(params.policy, params.policy_parameters, mac_info_bus.clone())
+            TxPartCtler::new(&params.policy, params.policy_parameters.clone(), params.links.clone(), health, mac_info_bus.clone())
         ));
+        tx_part_ctler.lock().unwrap().duplicate = params.duplicate;
         let version_manager = Arc::new(Mutex::new( 
             if params.npy_file.ends_with(".json") {
                 Some(VersionManager::new(&params.npy_file))
@@ -334,10 +494,20 @@ impl SourceManager {
             })
         );
 
-        let rtt =  match params.calc_rtt {
+        let mut rtt =  match params.calc_rtt {
             false => None,
-            true => Some( RttRecorder::new( &name, params.port, link_num, target_rtt) )
+            true => Some( RttRecorder::new( &name, params.port, link_num, target_rtt,
+                params.links.first().map(|link| link.rx_ipaddr.as_str()), params.sync_interval) )
         };
+        if let Some(ref mut rtt) = rtt {
+            let mut tx_part_ctler = tx_part_ctler.lock().unwrap();
+            tx_part_ctler.rtt_records = Some(Arc::clone(&rtt.rtt_records));
+            tx_part_ctler.link_records = Some(Arc::clone(&rtt.link_records));
+            if params.arq {
+                rtt.arq = Some(Arc::new(Mutex::new(ArqBuffer::new(target_rtt))));
+                tx_part_ctler.arq = rtt.arq.clone();
+            }
+        }
 
         let start_timestamp = SystemTime::now();
         let stop_timestamp = SystemTime::now();
@@ -350,18 +520,31 @@ impl SourceManager {
             (vec![], vec![])
         };
 
-        Self{ name, stream, throttler, rtt, tx_part_ctler, version_manager, socket_infos, start_timestamp, stop_timestamp, source, mac_info_bus, dest }
+        let send_log = tcp.then(|| {
+            File::create(format!("logs/send-{}.txt", name.replace('/', "_"))).expect("Failed to create send log file")
+        });
+
+        Self{ name, stream, throttler, rtt, tx_part_ctler, version_manager, socket_infos, link_stats, send_log, start_timestamp, stop_timestamp, source, mac_info_bus, dest }
     }
 
+    /// Set the throttle from now on; a throttle profile stops being followed.
     pub fn throttle(&self, throttle:f64) {
         if let Ok(ref mut throttler) = self.throttler.lock() {
             throttler.throttle = throttle;
+            throttler.set_profile(None, 0.0);
         }
     }
 
+    /// Draw from the aggregate caps of `shaper` with the stream's `share_weight`.
+    pub fn set_shaper(&self, shaper: &GuardedShaper) {
+        let (StreamParam::UDP(ref params) | StreamParam::TCP(ref params)) = self.stream;
+        let stream = shaper.lock().unwrap().register(params.share_weight);
+        self.tx_part_ctler.lock().unwrap().shaper = Some((Arc::clone(shaper), stream));
+    }
+
     pub fn set_policy_parameters(&self, parameters: PolicyParameter) {
         if let Ok(ref mut tx_part_ctler) = self.tx_part_ctler.lock() {
-            tx_part_ctler.policy_parameters = parameters;
+            tx_part_ctler.set_policy_parameters(parameters);
         };
     }
 
@@ -377,15 +560,43 @@ impl SourceManager {
             return None;
         }
     
-        let (throughput, throttle, app_buff, frame_count) = self.throttler.lock().ok()?.snapshot();
+        let (instant_throughput, throughput, throttle, app_buff, frame_count, drops) = {
+            let throttler = self.throttler.lock().ok()?;
+            let (instant_throughput, throughput, throttle, app_buff, frame_count) = throttler.snapshot();
+            (instant_throughput, throughput, throttle, app_buff, frame_count, throttler.drops())
+        };
     
         let (rtt,  outage_rate) = if let Some(ref rtt) = self.rtt {
             rtt.rtt_records.lock().ok()?.statistic()
         } else {
             (0.0, 0.0)
         };
-        let bitrate = self.version_manager.lock().ok()?.as_ref()?.get_bitrate();
-        Some(FlowStatistics { rtt, outage_rate, throughput, throttle, bitrate, app_buff, frame_count })
+        let (clock_offset, clock_drift) = match self.rtt {
+            Some(ref rtt) => {
+                let clock = rtt.clock_sync.lock().ok()?;
+                (clock.offset_at(now_secs_f64()).unwrap_or(0.0), clock.drift)
+            }
+            None => (0.0, 0.0)
+        };
+        let bitrate = self.version_manager.lock().ok()?.as_ref().map_or(0, |vm| vm.get_bitrate());
+        let tcp_info = self.link_stats.iter().filter_map(|(link_id, link)| Some((*link_id, link.tcp_info()?))).collect();
+        let links = match self.rtt {
+            Some(ref rtt) => rtt.link_records.lock().ok()?.estimates(),
+            None => Vec::new(),
+        };
+        let (retransmissions, retransmit_throughput) = match self.rtt.as_ref().and_then(|rtt| rtt.arq.as_ref()) {
+            Some(arq) => {
+                let mut arq = arq.lock().ok()?;
+                (arq.retransmissions, arq.rate_mbps(now_secs_f64()))
+            }
+            None => (0, 0.0),
+        };
+        let (link_health, link_events) = {
+            let controller = self.tx_part_ctler.lock().ok()?;
+            (controller.health.states(), controller.health.events())
+        };
+        Some(FlowStatistics { rtt, outage_rate, throughput, instant_throughput, throttle, bitrate, app_buff, frame_count, drops, clock_offset, clock_drift, tcp_info, links,
+            retransmissions, retransmit_throughput, link_health, link_events })
     }
 
     pub fn device_statistics(&self) -> MACQueuesSnapshot {
@@ -396,6 +607,7 @@ impl SourceManager {
         let throttler = Arc::clone(&self.throttler);
         let tx_part_ctler = Arc::clone(&self.tx_part_ctler);
         let version_manager = Arc::clone(&self.version_manager);
+        tx_part_ctler.lock().unwrap().policy.reset();
 
         let rtt_rec = match self.rtt {
             Some(ref mut rtt) => {
@@ -411,19 +623,21 @@ impl SourceManager {
         let _now = SystemTime::now();
         self.start_timestamp = _now + Duration::from_secs_f64( params.duration[0] );
         self.stop_timestamp = _now + Duration::from_secs_f64( params.duration[1] );
+        throttler.lock().unwrap().set_profile(params.throttle_profile.clone(), now_secs_f64() + params.duration[0]);
 
         let dest = self.dest.pop();
         let socket_infos = self.socket_infos.pop().unwrap();
+        let send_log = self.send_log.take();
         let source = thread::spawn(move || {
             if params.npy_file.starts_with(STREAM_PROTO) {
                 let dest = dest.unwrap();
-                stream_thread(throttler, tx_part_ctler, rtt_rec, params, socket_infos, dest)
+                stream_thread(throttler, tx_part_ctler, rtt_rec, params, socket_infos, send_log, dest)
             }
             else if params.npy_file.ends_with(".npy") {
-                source_thread(throttler, tx_part_ctler, rtt_rec, params, socket_infos); 
+                source_thread(throttler, tx_part_ctler, rtt_rec, params, socket_infos, send_log);
             }
             else {
-                video_thread(throttler, tx_part_ctler, version_manager, rtt_rec, params, socket_infos);
+                video_thread(throttler, tx_part_ctler, version_manager, rtt_rec, params, socket_infos, send_log);
             }
         });
 
@@ -431,3 +645,37 @@ impl SourceManager {
         source
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Fragments of a `size_bytes` frame as the sources cut them
+    fn frame(size_bytes: usize) -> Vec<PacketWithMeta> {
+        let mut template = PacketWithMeta::new(0, HeaderVersion::default());
+        let (num, remains) = (size_bytes / MAX_PAYLOAD_LEN, size_bytes % MAX_PAYLOAD_LEN);
+        template.next_seq(num + usize::from(remains > 0));
+        generate_packets(remains, &mut template, Some(&vec![7u8; size_bytes]))
+    }
+
+    #[test]
+    fn add_parity_leaves_empty_frames_alone() {
+        for scheme in [FecScheme::XOR, FecScheme::ReedSolomon] {
+            let mut packets = frame(0);
+            add_parity(&mut packets, &FecParams { scheme, parity: 2 });
+            assert!(packets.is_empty());
+        }
+    }
+
+    #[test]
+    fn add_parity_appends_parity_fragments() {
+        for scheme in [FecScheme::XOR, FecScheme::ReedSolomon] {
+            let mut packets = frame(3 * MAX_PAYLOAD_LEN + 10);
+            add_parity(&mut packets, &FecParams { scheme, parity: 2 });
+            assert_eq!(packets.len(), 6);
+            assert!(packets.iter().all(|packet| packet.num == 6 && packet.source_num() == 4));
+            assert!(packets[4..].iter().all(|packet| packet.is_parity()));
+            assert_eq!(packets[3].fec.last_len, 10);
+        }
+    }
+}
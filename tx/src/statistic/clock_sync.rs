@@ -0,0 +1,77 @@
+use std::collections::VecDeque;
+
+// Samples whose round trip exceeds the best one by this much are treated as queued
+static DELAY_TOLERANCE: f64 = 0.5e-3;
+
+#[derive(Debug, Clone, Copy)]
+struct SyncSample {
+    time: f64,   // local (tx) receive time of the response
+    offset: f64, // rx clock - tx clock
+    delay: f64,  // round trip minus rx processing time
+}
+
+/// Continuous NTP-style estimate of the rx clock relative to the tx clock.
+#[derive(Debug)]
+pub struct ClockSync {
+    samples: VecDeque<SyncSample>,
+    max_samples: usize,
+    pub offset: f64,
+    pub drift: f64,
+    pub delay: f64,
+    ref_time: f64,
+    valid: bool,
+}
+
+impl ClockSync {
+    pub fn new(max_samples: usize) -> Self {
+        ClockSync {
+            samples: VecDeque::with_capacity(max_samples),
+            max_samples,
+            offset: 0.0,
+            drift: 0.0,
+            delay: 0.0,
+            ref_time: 0.0,
+            valid: false,
+        }
+    }
+
+    /// Feed one exchange: `t1` tx send, `t2` rx receive, `t3` rx reply, `t4` tx receive.
+    pub fn update(&mut self, t1: f64, t2: f64, t3: f64, t4: f64) {
+        let offset = ((t2 - t1) + (t3 - t4)) / 2.0;
+        let delay = (t4 - t1) - (t3 - t2);
+        if delay < 0.0 {
+            return;
+        }
+        if self.samples.len() == self.max_samples {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(SyncSample { time: t4, offset, delay });
+        self.estimate();
+    }
+
+    fn estimate(&mut self) {
+        // Keep the exchanges close to the minimum round trip (clock filter)
+        let min_delay = self.samples.iter().map(|s| s.delay).fold(f64::INFINITY, f64::min);
+        let filtered: Vec<_> = self.samples.iter()
+            .filter(|s| s.delay <= min_delay + DELAY_TOLERANCE)
+            .collect();
+
+        // Drift is the least-squares slope of offset over time
+        let n = filtered.len() as f64;
+        let mean_t = filtered.iter().map(|s| s.time).sum::<f64>() / n;
+        let mean_o = filtered.iter().map(|s| s.offset).sum::<f64>() / n;
+        let var_t: f64 = filtered.iter().map(|s| (s.time - mean_t).powi(2)).sum();
+        let cov: f64 = filtered.iter().map(|s| (s.time - mean_t) * (s.offset - mean_o)).sum();
+        self.drift = if var_t > 0.0 { cov / var_t } else { 0.0 };
+
+        self.ref_time = mean_t;
+        self.offset = mean_o;
+        self.delay = min_delay;
+        self.valid = true;
+    }
+
+    /// Estimated offset at local time `t`, `None` before the first exchange.
+    pub fn offset_at(&self, t: f64) -> Option<f64> {
+        self.valid.then_some(self.offset + self.drift * (t - self.ref_time))
+    }
+}
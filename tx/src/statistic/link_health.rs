@@ -0,0 +1,267 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{ErrorKind, LineWriter, Write};
+use log::info;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::ip_helper::get_dev_from_ip;
+
+const fn _default_congestion_hold() -> f64 { 0.05 }
+const fn _default_ack_timeout() -> f64 { 0.5 }
+const fn _default_probe_interval() -> f64 { 0.2 }
+// Latest events kept for IPC
+const MAX_EVENTS: usize = 32;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct LinkHealthParams {
+    #[serde(default = "_default_congestion_hold")] //default:
+    pub congestion_hold: f64,                      //         0.05 s a link stays congested after EAGAIN
+    #[serde(default = "_default_ack_timeout")]     //default:
+    pub ack_timeout: f64,                          //         0.5 s without acked delivery takes a link down
+    #[serde(default = "_default_probe_interval")]  //default:
+    pub probe_interval: f64,                       //         0.2 s between probes of a down link
+}
+
+impl Default for LinkHealthParams {
+    fn default() -> Self {
+        LinkHealthParams {
+            congestion_hold: _default_congestion_hold(),
+            ack_timeout: _default_ack_timeout(),
+            probe_interval: _default_probe_interval(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LinkHealth {
+    #[default]
+    Up,
+    Congested,
+    Down,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LinkEvent {
+    pub time: f64,
+    pub link: usize,
+    pub from: LinkHealth,
+    pub to: LinkHealth,
+    pub reason: String,
+}
+
+#[derive(Debug, Default)]
+struct LinkStatus {
+    health: LinkHealth,
+    changed_at: f64,
+    congested_until: f64,
+    unacked_since: Option<f64>, // first send not followed by an acked delivery
+    next_probe: f64,
+    dev: Option<String>,
+    dev_down: bool,
+}
+
+/// Health of every link of a stream, from send errors, fragment acks and interface state.
+/// Links not `Up` are reported blocked to the policies; down links are probed until they deliver.
+#[derive(Debug)]
+pub struct LinkHealthMonitor {
+    params: LinkHealthParams,
+    links: Vec<LinkStatus>,
+    acks: bool, // whether any delivery has been acked yet, as with calc_rtt on both ends
+    next_dev_check: f64,
+    events: VecDeque<LinkEvent>,
+    log: Option<LineWriter<File>>,
+}
+
+impl LinkHealthMonitor {
+    pub fn new(params: LinkHealthParams, tx_ipaddrs: &[String], log_file: Option<&str>) -> Self {
+        let links = tx_ipaddrs.iter()
+            .map(|ip| LinkStatus { dev: get_dev_from_ip(ip), ..Default::default() })
+            .collect();
+        let log = log_file.and_then(|path| File::create(path).ok()).map(LineWriter::new);
+        LinkHealthMonitor { params, links, acks: false, next_dev_check: 0.0, events: VecDeque::new(), log }
+    }
+
+    pub fn blocked_signals(&self) -> Vec<bool> {
+        self.links.iter().map(|link| link.health != LinkHealth::Up).collect()
+    }
+
+    pub fn states(&self) -> Vec<LinkHealth> {
+        self.links.iter().map(|link| link.health).collect()
+    }
+
+    pub fn events(&self) -> Vec<LinkEvent> {
+        self.events.iter().cloned().collect()
+    }
+
+    pub fn on_sent(&mut self, link: usize, now: f64) {
+        let Some(status) = self.links.get_mut(link) else { return };
+        status.unacked_since.get_or_insert(now);
+        // Without acks a send going through is all the evidence there is
+        if !self.acks && status.health == LinkHealth::Down && !status.dev_down {
+            self.transition(link, LinkHealth::Up, now, "probe sent");
+        }
+    }
+
+    pub fn on_send_error(&mut self, link: usize, kind: ErrorKind, now: f64) {
+        let Some(status) = self.links.get_mut(link) else { return };
+        if kind == ErrorKind::WouldBlock {
+            status.congested_until = now + self.params.congestion_hold;
+            if status.health == LinkHealth::Up {
+                self.transition(link, LinkHealth::Congested, now, "send would block");
+            }
+        } else if status.health != LinkHealth::Down {
+            self.transition(link, LinkHealth::Down, now, &format!("send failed: {:?}", kind));
+        }
+    }
+
+    /// Advance every link to `now`, given the latest acked delivery per link.
+    pub fn update(&mut self, now: f64, delivered_at: &[Option<f64>]) {
+        self.acks |= delivered_at.iter().any(Option::is_some);
+        if now >= self.next_dev_check {
+            self.next_dev_check = now + self.params.probe_interval;
+            self.check_devices(now);
+        }
+        for link in 0..self.links.len() {
+            let status = &mut self.links[link];
+            let delivered = delivered_at.get(link).copied().flatten();
+            if delivered.is_some_and(|t| status.unacked_since.is_some_and(|since| t >= since)) {
+                status.unacked_since = None;
+            }
+            match status.health {
+                LinkHealth::Congested if now >= status.congested_until => {
+                    self.transition(link, LinkHealth::Up, now, "congestion cleared");
+                }
+                LinkHealth::Down if self.acks && !status.dev_down && delivered.is_some_and(|t| t > status.changed_at) => {
+                    self.transition(link, LinkHealth::Up, now, "delivery acked");
+                }
+                LinkHealth::Up | LinkHealth::Congested if self.acks
+                    && status.unacked_since.is_some_and(|since| now - since > self.params.ack_timeout) => {
+                    self.transition(link, LinkHealth::Down, now, "no acked delivery");
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Down links due for a probe; each gets a copy of the next fragment sent.
+    pub fn take_probes(&mut self, now: f64) -> Vec<usize> {
+        let interval = self.params.probe_interval;
+        self.links.iter_mut().enumerate()
+            .filter(|(_, status)| status.health == LinkHealth::Down && !status.dev_down && now >= status.next_probe)
+            .map(|(link, status)| {
+                status.next_probe = now + interval;
+                link
+            })
+            .collect()
+    }
+
+    fn check_devices(&mut self, now: f64) {
+        for link in 0..self.links.len() {
+            let status = &mut self.links[link];
+            let Some(dev) = status.dev.as_ref() else { continue };
+            let operstate = std::fs::read_to_string(format!("/sys/class/net/{}/operstate", dev)).unwrap_or_default();
+            let dev_down = operstate.trim() == "down";
+            if dev_down == status.dev_down {
+                continue;
+            }
+            status.dev_down = dev_down;
+            match dev_down {
+                true if status.health != LinkHealth::Down => self.transition(link, LinkHealth::Down, now, "interface down"),
+                // Probe straight away
+                false => status.next_probe = now,
+                _ => {}
+            }
+        }
+    }
+
+    fn transition(&mut self, link: usize, to: LinkHealth, now: f64, reason: &str) {
+        let status = &mut self.links[link];
+        let event = LinkEvent { time: now, link, from: status.health, to, reason: reason.to_string() };
+        status.health = to;
+        status.changed_at = now;
+        status.unacked_since = None;
+        status.next_probe = now + self.params.probe_interval;
+
+        info!("Link {} {:?} -> {:?}: {}", link, event.from, event.to, reason);
+        if let Some(ref mut log) = self.log {
+            let _ = writeln!(log, "{:.6} {} {:?} {:?} {}", now, link, event.from, event.to, reason);
+        }
+        if self.events.len() == MAX_EVENTS {
+            self.events.pop_front();
+        }
+        self.events.push_back(event);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two links on documentation addresses, so no interface state is read
+    fn monitor() -> LinkHealthMonitor {
+        LinkHealthMonitor::new(LinkHealthParams::default(), &["192.0.2.1".to_string(), "192.0.2.2".to_string()], None)
+    }
+
+    #[test]
+    fn a_link_goes_congested_down_and_back_up_through_a_probe() {
+        let mut health = monitor();
+        health.on_sent(0, 0.0);
+        health.update(0.01, &[Some(0.01), None]);
+        assert_eq!(health.states(), vec![LinkHealth::Up, LinkHealth::Up]);
+
+        health.on_send_error(0, ErrorKind::WouldBlock, 0.1);
+        assert_eq!(health.blocked_signals(), vec![true, false]);
+        health.update(0.12, &[Some(0.01), None]);
+        assert_eq!(health.states()[0], LinkHealth::Congested);
+
+        // Still congested when its sends go unacked for `ack_timeout`
+        health.on_sent(0, 0.13);
+        health.on_send_error(0, ErrorKind::WouldBlock, 0.6);
+        health.update(0.62, &[Some(0.01), None]);
+        assert_eq!(health.states()[0], LinkHealth::Congested);
+        health.update(0.64, &[Some(0.01), None]);
+        assert_eq!(health.states()[0], LinkHealth::Down);
+
+        // Probed every `probe_interval`, a sent probe alone does not bring it back with acks
+        assert!(health.take_probes(0.7).is_empty());
+        assert_eq!(health.take_probes(0.85), vec![0]);
+        assert!(health.take_probes(0.9).is_empty());
+        health.on_sent(0, 0.85);
+        health.update(0.86, &[Some(0.01), None]);
+        assert_eq!(health.states()[0], LinkHealth::Down);
+        health.update(0.9, &[Some(0.87), None]);
+        assert_eq!(health.states()[0], LinkHealth::Up);
+
+        let transitions: Vec<_> = health.events().iter().map(|event| (event.from, event.to)).collect();
+        assert_eq!(transitions, vec![
+            (LinkHealth::Up, LinkHealth::Congested),
+            (LinkHealth::Congested, LinkHealth::Down),
+            (LinkHealth::Down, LinkHealth::Up),
+        ]);
+    }
+
+    #[test]
+    fn congestion_clears_after_the_hold() {
+        let mut health = monitor();
+        health.on_send_error(1, ErrorKind::WouldBlock, 0.0);
+        health.update(0.04, &[]);
+        assert_eq!(health.states()[1], LinkHealth::Congested);
+        health.update(0.05, &[]);
+        assert_eq!(health.states()[1], LinkHealth::Up);
+    }
+
+    #[test]
+    fn without_acks_a_sent_probe_brings_a_link_back() {
+        let mut health = monitor();
+        health.on_send_error(0, ErrorKind::ConnectionRefused, 0.0);
+        assert_eq!(health.states()[0], LinkHealth::Down);
+        // No ack timeout without acks
+        health.on_sent(1, 0.0);
+        health.update(1.0, &[None, None]);
+        assert_eq!(health.states()[1], LinkHealth::Up);
+
+        assert_eq!(health.take_probes(1.0), vec![0]);
+        health.on_sent(0, 1.0);
+        assert_eq!(health.states()[0], LinkHealth::Up);
+    }
+}
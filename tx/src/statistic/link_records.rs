@@ -0,0 +1,111 @@
+use core::ack::FragmentAck;
+use crate::policies::LinkState;
+
+// RFC 6298 smoothing gains
+static SRTT_GAIN: f64 = 0.125;
+static RTTVAR_GAIN: f64 = 0.25;
+static RTO_RTTVAR_GAIN: f64 = 4.0;
+// Weight of one frame in the per-link loss rate
+static LOSS_GAIN: f64 = 0.125;
+
+#[derive(Debug, Clone)]
+struct SentFrame {
+    seq: usize,
+    sent: Vec<u16>,     // fragments sent per link, retransmissions included
+    sent_at: Vec<f64>,  // latest send per link
+    acked: bool,
+}
+
+/// Per-link RTT and loss, estimated from the fragment acks of every frame.
+#[derive(Debug)]
+pub struct LinkRecords {
+    frames: Vec<Option<SentFrame>>,
+    max_length: usize,
+    links: Vec<LinkState>,
+    delivered_at: Vec<Option<f64>>, // latest ack reporting a fragment received per link
+}
+
+impl LinkRecords {
+    pub fn new(max_length: usize, link_num: usize) -> Self {
+        LinkRecords {
+            frames: vec![None; max_length],
+            max_length,
+            links: vec![LinkState::default(); link_num],
+            delivered_at: vec![None; link_num],
+        }
+    }
+
+    pub fn estimates(&self) -> Vec<LinkState> {
+        self.links.clone()
+    }
+
+    pub fn delivered_at(&self) -> Vec<Option<f64>> {
+        self.delivered_at.clone()
+    }
+
+    /// Count a fragment of frame `seq` sent on `link` at `now`.
+    pub fn on_sent(&mut self, seq: usize, link: usize, now: f64) {
+        let link_num = self.links.len();
+        let slot = &mut self.frames[seq % self.max_length];
+        if slot.as_ref().is_none_or(|frame| frame.seq != seq) {
+            *slot = Some(SentFrame { seq, sent: vec![0; link_num], sent_at: vec![0.0; link_num], acked: false });
+        }
+        if let Some(frame) = slot.as_mut().filter(|_| link < link_num) {
+            frame.sent[link] = frame.sent[link].saturating_add(1);
+            frame.sent_at[link] = now;
+        }
+    }
+
+    /// Fold in an ack received at `now`. Loss is counted on the first ack of a frame that is
+    /// not early, from what each link sent and delivered; a link sent on within its
+    /// retransmission timeout may still have fragments in flight, as when the frame completed
+    /// over another link, and is left out.
+    pub fn on_ack(&mut self, ack: &FragmentAck, now: f64) {
+        for (link, arrival) in ack.links.iter().enumerate().take(self.links.len()) {
+            if arrival.received == 0 {
+                continue;
+            }
+            self.delivered_at[link] = Some(now);
+            if arrival.send_time <= 0.0 {
+                continue;
+            }
+            let sample = now - arrival.send_time - arrival.hold;
+            if sample < 0.0 {
+                continue;
+            }
+            let state = &mut self.links[link];
+            (state.srtt, state.rttvar) = match (state.srtt, state.rttvar) {
+                (Some(srtt), Some(rttvar)) => (
+                    Some(srtt + SRTT_GAIN * (sample - srtt)),
+                    Some(rttvar + RTTVAR_GAIN * ((srtt - sample).abs() - rttvar)),
+                ),
+                _ => (Some(sample), Some(sample / 2.0)),
+            };
+        }
+
+        let seq = ack.seq as usize;
+        let frame = match self.frames[seq % self.max_length] {
+            Some(ref mut frame) if frame.seq == seq && !frame.acked && !ack.early => frame,
+            _ => return,
+        };
+        frame.acked = true;
+        // Retransmission timeout of every link, the slowest one standing in for links not measured yet
+        let timeouts: Vec<Option<f64>> = self.links.iter()
+            .map(|state| Some(state.srtt? + RTO_RTTVAR_GAIN * state.rttvar.unwrap_or(0.0)))
+            .collect();
+        let slowest = timeouts.iter().flatten().copied().fold(0.0, f64::max);
+        for (link, state) in self.links.iter_mut().enumerate() {
+            let sent = frame.sent[link];
+            let received = ack.links.get(link).map_or(0, |arrival| arrival.received).min(sent);
+            let in_flight = timeouts[link].unwrap_or(slowest);
+            if sent == 0 || (received < sent && now - frame.sent_at[link] < in_flight) {
+                continue;
+            }
+            let sample = (sent - received) as f64 / sent as f64;
+            state.loss = Some(match state.loss {
+                Some(loss) => loss + LOSS_GAIN * (sample - loss),
+                None => sample,
+            });
+        }
+    }
+}
@@ -0,0 +1,347 @@
+use std::fmt::Debug;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::process::Command;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+use crate::statistic::mac_queue::{LinkInfo, MACQueueInfo};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use crate::utils::nl80211::Nl80211;
+
+/// Where the per-AC MAC backlog of a device is read from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum QueueBackend {
+    #[default]
+    Realtek,         // /proc/net/rtl88XXau/<dev>/mac_qinfo
+    Mac80211Debugfs, // per-station TXQ stats under /sys/kernel/debug/ieee80211
+    Qdisc,           // `tc -s qdisc` backlog, per AC on multiqueue devices
+    None,
+}
+
+/// Where the PHY rate and signal of a device are read from.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub enum LinkBackend {
+    #[default]
+    Iw,      // `iw dev <dev> link`
+    Nl80211, // nl80211 station info over netlink
+    None,
+}
+
+/// A source of MAC statistics for one device; `None` keeps the previous sample.
+pub trait MacStatsBackend: Send + Debug {
+    fn queue_info(&mut self) -> Option<MACQueueInfo> {
+        None
+    }
+
+    fn link_info(&mut self) -> Option<LinkInfo> {
+        None
+    }
+}
+
+#[derive(Debug)]
+struct NoStats;
+impl MacStatsBackend for NoStats {}
+
+pub fn queue_backend(kind: QueueBackend, dev: &str) -> Box<dyn MacStatsBackend> {
+    match kind {
+        QueueBackend::Realtek => Box::new(RealtekProc::new(dev)),
+        QueueBackend::Mac80211Debugfs => Box::new(Mac80211Debugfs::new(dev)),
+        QueueBackend::Qdisc => Box::new(Qdisc::new(dev)),
+        QueueBackend::None => Box::new(NoStats),
+    }
+}
+
+pub fn link_backend(kind: LinkBackend, dev: &str) -> Box<dyn MacStatsBackend> {
+    match kind {
+        LinkBackend::Iw => Box::new(IwLink::new(dev)),
+        LinkBackend::Nl80211 => Box::new(Nl80211Station::new(dev)),
+        LinkBackend::None => Box::new(NoStats),
+    }
+}
+
+fn read_stats_file<P: AsRef<Path>>(path: P) -> Option<String> {
+    match fs::read_to_string(path.as_ref()) {
+        Ok(s) => Some(s),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            // device not up or driver not exposing the file yet — just pass
+            None
+        }
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path.as_ref().display(), e);
+            None
+        }
+    }
+}
+
+fn parse_digits(s: &str, start: usize) -> Option<usize> {
+    let bytes = s.as_bytes();
+    let mut num: usize = 0;
+    let mut found = false;
+
+    // Process each byte from start position
+    for &b in bytes.iter().skip(start) {
+        match b {
+            b'0'..=b'9' => {
+                found = true;
+                // Check for overflow during calculation
+                num = num
+                    .checked_mul(10)?
+                    .checked_add((b - b'0') as usize)?;
+            }
+            _ => break, // Stop at first non-digit
+        }
+    }
+
+    found.then_some(num)
+}
+
+#[derive(Debug)]
+pub struct RealtekProc {
+    proc_file: String,
+}
+
+impl RealtekProc {
+    pub fn new(dev: &str) -> Self {
+        RealtekProc { proc_file: format!("/proc/net/rtl88XXau/{}/mac_qinfo", dev) }
+    }
+}
+
+impl MacStatsBackend for RealtekProc {
+    fn queue_info(&mut self) -> Option<MACQueueInfo> {
+        let data = read_stats_file(&self.proc_file)?;
+        let mut queue_info = MACQueueInfo::new();
+
+        // Regex to match lines with "pkt_num" and "ac" (excluding BCN)
+        // let re = Regex::new(r"head:[^,]+,\s+tail:[^,]+,\s+pkt_num:(\d+),\s+macid:\d+,?\s*ac:(\d+)").unwrap();
+
+        for line in data.lines() {
+            // Fast skip BCN lines (as per comment requirement)
+            if line.contains("BCN") {
+                continue;
+            }
+
+            // First find pkt_num marker
+            if let Some(p_pos) = line.find("pkt_num:") {
+                // Only look for ac AFTER pkt_num
+                let after_pkt = &line[p_pos + 8..];
+                if let Some(a_pos) = after_pkt.find("ac:") {
+                    // Calculate absolute position in line
+                    let a_abs = p_pos + 8 + a_pos;
+
+                    // Extract both values with direct byte parsing
+                    if let (Some(pkt_num), Some(ac_val)) = (
+                        parse_digits(line, p_pos + 8),
+                        parse_digits(line, a_abs + 3)
+                    ) {
+                        // Handle u8 overflow same as original (unwrap_or(0))
+                        let ac_val = if ac_val > usize::from(u8::MAX) { 0 } else { ac_val as u8 };
+                        *queue_info.entry(ac_val).or_insert(0) += pkt_num;
+                    }
+                }
+            }
+        }
+        Some(queue_info)
+    }
+}
+
+/// mac80211 drivers (mt76, ath10k, ath11k, ...) expose per-station TXQs in debugfs:
+/// `tid ac backlog-bytes backlog-packets ...` rows in `stations/<mac>/aqm`.
+#[derive(Debug)]
+pub struct Mac80211Debugfs {
+    dev: String,
+}
+
+impl Mac80211Debugfs {
+    pub fn new(dev: &str) -> Self {
+        Mac80211Debugfs { dev: dev.to_string() }
+    }
+
+    fn stations_dir(&self) -> Option<String> {
+        let phy = fs::read_to_string(format!("/sys/class/net/{}/phy80211/name", self.dev)).ok()?;
+        Some(format!("/sys/kernel/debug/ieee80211/{}/netdev:{}/stations", phy.trim(), self.dev))
+    }
+}
+
+fn parse_txq_table(data: &str, queue_info: &mut MACQueueInfo) {
+    let mut columns: Option<(usize, usize)> = None;
+    for line in data.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.first() == Some(&"tid") {
+            let ac = fields.iter().position(|f| *f == "ac");
+            let backlog = fields.iter().position(|f| *f == "backlog-packets");
+            columns = ac.zip(backlog);
+            continue;
+        }
+        if let Some((ac, backlog)) = columns {
+            if let (Some(Ok(ac)), Some(Ok(pkts))) = (fields.get(ac).map(|v| v.parse::<u8>()), fields.get(backlog).map(|v| v.parse::<usize>())) {
+                *queue_info.entry(ac).or_insert(0) += pkts;
+            }
+        }
+    }
+}
+
+impl MacStatsBackend for Mac80211Debugfs {
+    fn queue_info(&mut self) -> Option<MACQueueInfo> {
+        let stations = fs::read_dir(self.stations_dir()?).ok()?;
+        let mut queue_info: MACQueueInfo = (0..4).map(|ac| (ac, 0)).collect();
+        for station in stations.flatten() {
+            if let Some(data) = read_stats_file(station.path().join("aqm")) {
+                parse_txq_table(&data, &mut queue_info);
+            }
+        }
+        Some(queue_info)
+    }
+}
+
+/// Backlog of the qdiscs on a device. mac80211 registers one tx queue per AC (VO, VI, BE, BK)
+/// under an `mq` root, so children `parent :1`..`:4` map to AC 0..3; with a single queue every
+/// AC waits behind the same backlog.
+#[derive(Debug)]
+pub struct Qdisc {
+    dev: String,
+}
+
+impl Qdisc {
+    pub fn new(dev: &str) -> Self {
+        Qdisc { dev: dev.to_string() }
+    }
+
+    fn tx_queues(&self) -> usize {
+        fs::read_dir(format!("/sys/class/net/{}/queues", self.dev))
+            .map(|dir| dir.flatten().filter(|e| e.file_name().to_string_lossy().starts_with("tx-")).count())
+            .unwrap_or(1)
+    }
+}
+
+// (parent queue, backlog packets) per qdisc, `None` for the root
+fn parse_tc_backlog(s: &str) -> Vec<(Option<usize>, usize)> {
+    let re_parent = Regex::new(r"\bparent\s+[0-9a-fA-F]*:([0-9a-fA-F]+)").unwrap();
+    let re_backlog = Regex::new(r"^\s*backlog\s+\S+\s+(\d+)p\b").unwrap();
+    let mut backlog = Vec::new();
+    let mut parent = None;
+    for line in s.lines() {
+        if line.starts_with("qdisc") {
+            parent = Some(re_parent.captures(line).and_then(|c| usize::from_str_radix(&c[1], 16).ok()));
+        }
+        else if let (Some(qdisc), Some(c)) = (parent, re_backlog.captures(line)) {
+            backlog.push((qdisc, c[1].parse().unwrap_or(0)));
+            parent = None;
+        }
+    }
+    backlog
+}
+
+impl MacStatsBackend for Qdisc {
+    fn queue_info(&mut self) -> Option<MACQueueInfo> {
+        let out = Command::new("tc").args(["-s", "qdisc", "show", "dev", &self.dev]).output().ok()?;
+        if !out.status.success() {
+            return None;
+        }
+        let backlog = parse_tc_backlog(&String::from_utf8_lossy(&out.stdout));
+
+        let mut queue_info: MACQueueInfo = (0..4).map(|ac| (ac, 0)).collect();
+        if self.tx_queues() >= 4 && backlog.iter().any(|(parent, _)| parent.is_some()) {
+            backlog.iter()
+                .filter_map(|(parent, pkts)| parent.filter(|q| (1..=4).contains(q)).map(|q| (q - 1, pkts)))
+                .for_each(|(ac, pkts)| *queue_info.get_mut(&(ac as u8)).unwrap() += pkts);
+        }
+        else {
+            let total = backlog.iter().filter(|(parent, _)| parent.is_none()).map(|(_, pkts)| pkts).sum::<usize>();
+            queue_info.values_mut().for_each(|pkts| *pkts = total);
+        }
+        Some(queue_info)
+    }
+}
+
+#[derive(Debug)]
+pub struct IwLink {
+    dev: String,
+}
+
+impl IwLink {
+    pub fn new(dev: &str) -> Self {
+        IwLink { dev: dev.to_string() }
+    }
+}
+
+fn run_iw_link(iface: &str) -> Option<String> {
+    let out = Command::new("iw")
+        .arg("dev")
+        .arg(iface)
+        .arg("link")
+        .output().ok()?;
+
+    if !out.status.success() {
+        None
+    }
+    else{
+        Some(String::from_utf8_lossy(&out.stdout).to_string())
+    }
+}
+
+fn parse_link_info(s: &str) -> LinkInfo {
+    let re_bssid = Regex::new(r"(?m)^\s*Connected to\s+([0-9a-fA-F:]{17})\b").unwrap();
+    let re_ssid  = Regex::new(r"(?m)^\s*SSID:\s*(.+)\s*$").unwrap();
+    let re_freq  = Regex::new(r"(?m)^\s*freq:\s*(\d+)\s*$").unwrap();
+    let re_sig   = Regex::new(r"(?m)^\s*signal:\s*(-?\d+)\s*dBm\b").unwrap();
+    let re_tx    = Regex::new(r"(?m)^\s*tx bitrate:\s*([0-9]+(?:\.[0-9]+)?)\s*MBit/s\b").unwrap();
+
+    let bssid = re_bssid.captures(s).map(|c| c[1].to_string());
+    let ssid  = re_ssid.captures(s).map(|c| c[1].trim().to_string());
+    let freq_mhz = re_freq
+        .captures(s)
+        .and_then(|c| c[1].parse::<u32>().ok());
+    let signal_dbm = re_sig
+        .captures(s)
+        .and_then(|c| c[1].parse::<i32>().ok());
+    let tx_mbit_s = re_tx
+        .captures(s)
+        .and_then(|c| c[1].parse::<f32>().ok());
+
+    LinkInfo { bssid, ssid, freq_mhz, signal_dbm, tx_mbit_s }
+}
+
+impl MacStatsBackend for IwLink {
+    fn link_info(&mut self) -> Option<LinkInfo> {
+        run_iw_link(&self.dev).map(|output| parse_link_info(&output))
+    }
+}
+
+/// Station info of the associated AP straight from nl80211, without forking `iw`.
+#[derive(Debug)]
+pub struct Nl80211Station {
+    dev: String,
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    socket: Option<Nl80211>,
+}
+
+impl Nl80211Station {
+    pub fn new(dev: &str) -> Self {
+        Nl80211Station {
+            dev: dev.to_string(),
+            #[cfg(any(target_os = "linux", target_os = "android"))]
+            socket: None,
+        }
+    }
+}
+
+impl MacStatsBackend for Nl80211Station {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    fn link_info(&mut self) -> Option<LinkInfo> {
+        if self.socket.is_none() {
+            self.socket = Nl80211::connect().ok();
+        }
+        match self.socket.as_mut()?.station(&self.dev) {
+            Ok(station) => station.map(|sta| LinkInfo {
+                bssid: sta.mac, signal_dbm: sta.signal_dbm, tx_mbit_s: sta.tx_mbit_s, ..Default::default()
+            }),
+            Err(_) => {
+                // reconnect on the next sample
+                self.socket = None;
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,192 @@
+use std::collections::{HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use log::trace;
+use rand::prelude::*;
+use rand::rngs::StdRng;
+use serde::{Deserialize, Serialize};
+
+use core::clock::now_secs_f64;
+use core::packet::tos2ac;
+use core::transport::Transport;
+use crate::statistic::mac_queue::{LinkInfo, MACQueueInfo, MACQueuesSnapshot, SnapshotPublisher};
+
+const fn _default_queue_packets() -> usize { 256 }
+
+// 802.11 OFDM timing and default EDCA parameters, indexed by `tos2ac` (VO, VI, BE, BK)
+const SLOT_TIME: f64 = 9e-6;
+const SIFS: f64 = 16e-6;
+const AIFSN: [u32; 4] = [2, 2, 3, 7];
+const CW_MIN: [u32; 4] = [3, 7, 15, 15];
+// PHY preamble, MAC header and ACK exchange charged to every frame
+const FRAME_OVERHEAD: f64 = 100e-6;
+const SERVICE_POLL: Duration = Duration::from_micros(200);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MacEmulationParams {
+    pub phy_rate_mbps: f64,
+    #[serde(default = "_default_queue_packets")]
+    pub queue_packets: usize,                   //default: 256 per access category
+    #[serde(default)] pub seed: Option<u64>,    //default: from entropy
+}
+
+struct MacFrame {
+    output: usize,
+    buf: Vec<u8>,
+    frame_end: Option<u32>,
+}
+
+struct MacState {
+    queues: [VecDeque<MacFrame>; 4],
+    in_air: Option<(f64, usize, MacFrame)>, // (completion time, ac, frame)
+    busy_until: f64,
+    outputs: Vec<Box<dyn Transport>>,
+    rng: StdRng,
+}
+
+/// Simulated Wi-Fi device: per-AC queues served one frame at a time under EDCA contention.
+pub struct EmulatedMac {
+    params: MacEmulationParams,
+    state: Mutex<MacState>,
+}
+
+impl EmulatedMac {
+    fn new(params: MacEmulationParams) -> Self {
+        let rng = match params.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let state = MacState { queues: Default::default(), in_air: None, busy_until: 0.0, outputs: Vec::new(), rng };
+        EmulatedMac { params, state: Mutex::new(state) }
+    }
+
+    fn enqueue(&self, ac: usize, frame: MacFrame) -> std::io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.queues[ac].len() >= self.params.queue_packets {
+            return Err(ErrorKind::WouldBlock.into());
+        }
+        state.queues[ac].push_back(frame);
+        Ok(())
+    }
+
+    /// Packets waiting per access category, the frame on air included.
+    fn queue_info(&self) -> MACQueueInfo {
+        let state = self.state.lock().unwrap();
+        let mut info: MACQueueInfo = (0..4).map(|ac| (ac as u8, state.queues[ac].len())).collect();
+        if let Some((_, ac, _)) = state.in_air {
+            *info.entry(ac as u8).or_insert(0) += 1;
+        }
+        info
+    }
+
+    /// Deliver every frame whose transmission completed by `now`, returning when the next one does.
+    fn service(&self, now: f64) -> Option<f64> {
+        let mut guard = self.state.lock().unwrap();
+        let state = &mut *guard;
+        loop {
+            if let Some((done, _, _)) = state.in_air {
+                if done > now {
+                    return Some(done);
+                }
+                let (done, _, frame) = state.in_air.take().unwrap();
+                state.busy_until = done;
+                if let Err(e) = state.outputs[frame.output].send(&frame.buf, frame.frame_end) {
+                    trace!("Emulated MAC dropped a frame: {}", e);
+                }
+            }
+
+            // Each backlogged AC draws AIFS plus a random backoff; the internal collision goes to the higher priority
+            let rng = &mut state.rng;
+            let contention = state.queues.iter().enumerate()
+                .filter(|(_, queue)| !queue.is_empty())
+                .map(|(ac, _)| (ac, SIFS + AIFSN[ac] as f64 * SLOT_TIME + rng.gen_range(0..=CW_MIN[ac]) as f64 * SLOT_TIME))
+                .min_by(|a, b| a.1.total_cmp(&b.1));
+            let (ac, backoff) = contention?;
+
+            let frame = state.queues[ac].pop_front().unwrap();
+            let airtime = FRAME_OVERHEAD + (frame.buf.len() * 8) as f64 / (self.params.phy_rate_mbps * 1e6);
+            // An idle medium starts contending on arrival
+            let start = state.busy_until.max(now - SERVICE_POLL.as_secs_f64());
+            state.in_air = Some((start + backoff + airtime, ac, frame));
+        }
+    }
+}
+
+/// Sends through an `EmulatedMac`, queueing under the access category of the stream's ToS.
+pub struct MacTransport {
+    mac: Arc<EmulatedMac>,
+    output: usize,
+    ac: usize,
+}
+
+impl Transport for MacTransport {
+    fn send(&mut self, buf: &[u8], frame_end: Option<u32>) -> std::io::Result<()> {
+        self.mac.enqueue(self.ac, MacFrame { output: self.output, buf: buf.to_vec(), frame_end })
+    }
+}
+
+/// Emulated devices keyed by tx address, configured by `emulated_mac` in the manifest.
+#[derive(Clone, Default)]
+pub struct MacEmulator {
+    devices: HashMap<String, Arc<EmulatedMac>>,
+}
+
+impl MacEmulator {
+    pub fn new(params: &HashMap<String, MacEmulationParams>) -> Self {
+        let devices = params.iter()
+            .map(|(ip, params)| (ip.clone(), Arc::new(EmulatedMac::new(params.clone()))))
+            .collect();
+        MacEmulator { devices }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Route `inner` through the device at `tx_ipaddr`, if that device is emulated.
+    pub fn attach(&self, tx_ipaddr: &str, tos: u8, inner: Box<dyn Transport>) -> Box<dyn Transport> {
+        match self.devices.get(tx_ipaddr) {
+            Some(mac) => {
+                let mut state = mac.state.lock().unwrap();
+                state.outputs.push(inner);
+                let output = state.outputs.len() - 1;
+                Box::new(MacTransport { mac: Arc::clone(mac), output, ac: tos2ac(tos) })
+            }
+            None => inner,
+        }
+    }
+
+    pub fn snapshot(&self) -> MACQueuesSnapshot {
+        MACQueuesSnapshot {
+            seq: 0,
+            taken_at: SystemTime::now(),
+            queues: self.devices.iter().map(|(ip, mac)| (ip.clone(), mac.queue_info())).collect(),
+            link: self.devices.iter()
+                .map(|(ip, mac)| (ip.clone(), LinkInfo::emulated(mac.params.phy_rate_mbps as f32)))
+                .collect(),
+        }
+    }
+}
+
+pub fn mon_emulated_mac_thread(emulator: MacEmulator, mut publisher: SnapshotPublisher) -> thread::JoinHandle<()> {
+    // One service thread per device
+    for mac in emulator.devices.values() {
+        let mac = Arc::clone(mac);
+        thread::spawn(move || loop {
+            let now = now_secs_f64();
+            let wait = match mac.service(now) {
+                Some(done) => Duration::from_secs_f64((done - now).clamp(0.0, SERVICE_POLL.as_secs_f64())),
+                None => SERVICE_POLL,
+            };
+            thread::sleep(wait);
+        });
+    }
+
+    thread::spawn(move || loop {
+        let started = Instant::now();
+        publisher.publish(emulator.snapshot());
+        publisher.wait(started);
+    })
+}
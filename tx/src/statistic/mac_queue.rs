@@ -1,168 +1,78 @@
 #![allow(dead_code)]
+use std::fs::File;
+use std::io::{LineWriter, Write};
 use std::sync::{Arc, Mutex};
-use std::process::Command;
 use std::thread;
-use std::{fs, io};
 use std::collections::HashMap;
-use std::time::{SystemTime};
+use std::time::{Duration, Instant, SystemTime};
 use arc_swap::ArcSwap;
 use serde::{Deserialize, Serialize};
-use regex::Regex;
 
+use crate::conf::TxDevice;
+use crate::statistic::mac_backend::{link_backend, queue_backend, LinkBackend, MacStatsBackend, QueueBackend};
 use crate::utils::ip_helper::{get_dev_from_ip};
 
 pub type MACQueueInfo = HashMap<u8, usize>;
 pub type GuardedMACMonitor = Arc<Mutex<MACQueueMonitor>>;
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct MACQueueMonitor {
     query: HashMap<String, MACQueueQuery>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MACQueuesSnapshot {
+    #[serde(default)]
+    pub seq: u64,                 // monotonic per publisher, 0 for the initial empty snapshot
     pub taken_at: SystemTime,
     pub queues: HashMap<String, MACQueueInfo>,
     pub link: HashMap<String, LinkInfo>,
 }
 
-fn parse_digits(s: &str, start: usize) -> Option<usize> {
-    let bytes = s.as_bytes();
-    let mut num: usize = 0;
-    let mut found = false;
-    
-    // Process each byte from start position
-    for &b in bytes.iter().skip(start) {
-        match b {
-            b'0'..=b'9' => {
-                found = true;
-                // Check for overflow during calculation
-                num = num
-                    .checked_mul(10)?
-                    .checked_add((b - b'0') as usize)?;
-            }
-            _ => break, // Stop at first non-digit
-        }
-    }
-    
-    found.then_some(num)
-}
-
 #[derive(Debug, Serialize, Deserialize, Clone, Default)]
 pub struct LinkInfo {
-    bssid: Option<String>,
-    ssid: Option<String>,
-    freq_mhz: Option<u32>,
-    signal_dbm: Option<i32>,
+    pub bssid: Option<String>,
+    pub ssid: Option<String>,
+    pub freq_mhz: Option<u32>,
+    pub signal_dbm: Option<i32>,
     pub tx_mbit_s: Option<f32>,
 }
 
-fn run_iw_link(iface: &str) -> Option<String> {
-    let out = Command::new("iw")
-        .arg("dev")
-        .arg(iface)
-        .arg("link")
-        .output().unwrap();
-
-    if !out.status.success() {
-        None
+impl LinkInfo {
+    pub fn emulated(tx_mbit_s: f32) -> Self {
+        LinkInfo { tx_mbit_s: Some(tx_mbit_s), ..Default::default() }
     }
-    else{
-        Some(String::from_utf8_lossy(&out.stdout).to_string())
-    }
-}
-
-fn parse_link_info(s: &str) -> LinkInfo {
-    let re_bssid = Regex::new(r"(?m)^\s*Connected to\s+([0-9a-fA-F:]{17})\b").unwrap();
-    let re_ssid  = Regex::new(r"(?m)^\s*SSID:\s*(.+)\s*$").unwrap();
-    let re_freq  = Regex::new(r"(?m)^\s*freq:\s*(\d+)\s*$").unwrap();
-    let re_sig   = Regex::new(r"(?m)^\s*signal:\s*(-?\d+)\s*dBm\b").unwrap();
-    let re_tx    = Regex::new(r"(?m)^\s*tx bitrate:\s*([0-9]+(?:\.[0-9]+)?)\s*MBit/s\b").unwrap();
-
-    let bssid = re_bssid.captures(s).map(|c| c[1].to_string());
-    let ssid  = re_ssid.captures(s).map(|c| c[1].trim().to_string());
-    let freq_mhz = re_freq
-        .captures(s)
-        .and_then(|c| c[1].parse::<u32>().ok());
-    let signal_dbm = re_sig
-        .captures(s)
-        .and_then(|c| c[1].parse::<i32>().ok());
-    let tx_mbit_s = re_tx
-        .captures(s)
-        .and_then(|c| c[1].parse::<f32>().ok());
-
-    LinkInfo { bssid, ssid, freq_mhz, signal_dbm, tx_mbit_s }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug)]
 pub struct MACQueueQuery {
     dev: String,
-    proc_file: String,
+    queue_backend: Box<dyn MacStatsBackend>,
+    link_backend: Box<dyn MacStatsBackend>,
     queue_info: MACQueueInfo,
     link_info: LinkInfo,
 }
 
 impl MACQueueQuery {
-    pub fn new(dev: &str) -> Self {
+    pub fn new(dev: &str, queue: QueueBackend, link: LinkBackend) -> Self {
         Self {
             dev: dev.to_string(),
-            proc_file: format!("/proc/net/rtl88XXau/{}/mac_qinfo", dev),
+            queue_backend: queue_backend(queue, dev),
+            link_backend: link_backend(link, dev),
             queue_info: HashMap::new(),
             link_info: LinkInfo::default(),
         }
     }
 
     fn update_queue_info(&mut self){
-        // Read the contents of the proc file
-        let data = match fs::read_to_string(&self.proc_file) {
-            Ok(s) => s,
-            Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                // device not up or driver not exposing the file yet — just pass
-                return;
-            }
-            Err(e) => {
-                eprintln!("Failed to read {}: {}", self.proc_file, e);
-                return;
-            }
-        };
-
-        // Clear the existing queue info
-        self.queue_info.clear();
-
-        // Regex to match lines with "pkt_num" and "ac" (excluding BCN)
-        // let re = Regex::new(r"head:[^,]+,\s+tail:[^,]+,\s+pkt_num:(\d+),\s+macid:\d+,?\s*ac:(\d+)").unwrap();
-    
-        for line in data.lines() {
-            // Fast skip BCN lines (as per comment requirement)
-            if line.contains("BCN") {
-                continue;
-            }
-        
-            // First find pkt_num marker
-            if let Some(p_pos) = line.find("pkt_num:") {
-                // Only look for ac AFTER pkt_num
-                let after_pkt = &line[p_pos + 8..];
-                if let Some(a_pos) = after_pkt.find("ac:") {
-                    // Calculate absolute position in line
-                    let a_abs = p_pos + 8 + a_pos;
-                    
-                    // Extract both values with direct byte parsing
-                    if let (Some(pkt_num), Some(ac_val)) = (
-                        parse_digits(&line, p_pos + 8),
-                        parse_digits(&line, a_abs + 3)
-                    ) {
-                        // Handle u8 overflow same as original (unwrap_or(0))
-                        let ac_val = if ac_val > usize::from(u8::MAX) { 0 } else { ac_val as u8 };
-                        *self.queue_info.entry(ac_val).or_insert(0) += pkt_num;
-                    }
-                }
-            }
+        if let Some(queue_info) = self.queue_backend.queue_info() {
+            self.queue_info = queue_info;
         }
     }
 
     fn update_link_info(&mut self) {
-        if let Some(output) = run_iw_link(&self.dev) {
-            self.link_info = parse_link_info(&output);
+        if let Some(link_info) = self.link_backend.link_info() {
+            self.link_info = link_info;
         }
     }
 
@@ -173,11 +83,11 @@ impl MACQueueQuery {
 
 
 impl MACQueueMonitor {
-    pub fn new(ips: &Vec<String>) -> Self {
+    pub fn new(devices: &[TxDevice]) -> Self {
         let mut query = HashMap::new();
-        ips.into_iter().for_each(|ip| {
-            if let Some(dev) = get_dev_from_ip(ip) {
-                query.insert(ip.clone(), MACQueueQuery::new(&dev));
+        devices.iter().for_each(|device| {
+            if let Some(dev) = get_dev_from_ip(&device.ipaddr) {
+                query.insert(device.ipaddr.clone(), MACQueueQuery::new(&dev, device.queue_backend, device.link_backend));
             }
         });
 
@@ -189,7 +99,7 @@ impl MACQueueMonitor {
     pub fn get_queue_info_by_ip(&mut self, ip: &str) -> Option<&MACQueueInfo> {
         if let Some(dev) = get_dev_from_ip(ip) {
             if !self.query.contains_key(ip) {
-                self.query.insert(ip.to_string(), MACQueueQuery::new(&dev));
+                self.query.insert(ip.to_string(), MACQueueQuery::new(&dev, QueueBackend::default(), LinkBackend::default()));
                 self.query.get_mut(ip).unwrap().update_queue_info();
             }
             self.query.get_mut(ip).map(|query| {
@@ -221,6 +131,7 @@ impl LatestBus {
     pub fn new(is_mon: bool) -> Self {
         // Start with an empty snapshot if you like:
         let init = Arc::new(MACQueuesSnapshot {
+            seq: 0,
             taken_at: std::time::SystemTime::now(),
             queues: std::collections::HashMap::new(),
             link: std::collections::HashMap::new(),
@@ -240,11 +151,46 @@ impl LatestBus {
 }
 
 
+/// Publishes MAC samples at a fixed interval, numbering them and optionally logging each one
+/// as a line of JSON.
+pub struct SnapshotPublisher {
+    bus: LatestBus,
+    interval: Duration,
+    seq: u64,
+    log: Option<LineWriter<File>>,
+}
+
+impl SnapshotPublisher {
+    pub fn new(bus: LatestBus, interval: Duration, log_file: Option<&str>) -> Self {
+        let log = log_file.map(|path| LineWriter::new(File::create(path).expect("Failed to create MAC snapshot log")));
+        SnapshotPublisher { bus, interval, seq: 0, log }
+    }
+
+    pub fn publish(&mut self, mut snap: MACQueuesSnapshot) {
+        self.seq += 1;
+        snap.seq = self.seq;
+        if let Some(ref mut log) = self.log {
+            if let Ok(line) = serde_json::to_string(&snap) {
+                let _ = writeln!(log, "{}", line);
+            }
+        }
+        self.bus.publish(snap);
+    }
+
+    /// Sleep out the rest of the sampling interval that began at `started`.
+    pub fn wait(&self, started: Instant) {
+        if let Some(remaining) = self.interval.checked_sub(started.elapsed()) {
+            thread::sleep(remaining);
+        }
+    }
+}
+
 pub fn mon_mac_thread(
-    mut mac_mon: MACQueueMonitor, bus: LatestBus
+    mut mac_mon: MACQueueMonitor, mut publisher: SnapshotPublisher
 ) -> thread::JoinHandle<()> {
     let mon_thread = thread::spawn(move || {
         loop {
+            let started = Instant::now();
             let mut all: HashMap<String, MACQueueInfo> = HashMap::new();
             let mut link: HashMap<String, LinkInfo> = HashMap::new();
 
@@ -256,12 +202,14 @@ pub fn mon_mac_thread(
             });
 
 
-            bus.publish(MACQueuesSnapshot {
+            publisher.publish(MACQueuesSnapshot {
+                seq: 0,
                 taken_at: SystemTime::now(),
                 queues: all,
                 link,
             });
+            publisher.wait(started);
         }
     });
     mon_thread
-}
\ No newline at end of file
+}
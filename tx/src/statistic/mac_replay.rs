@@ -0,0 +1,134 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, ErrorKind};
+use std::path::Path;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::statistic::mac_queue::{LatestBus, MACQueuesSnapshot};
+
+/// Recorded MAC snapshots, as written by `--mon-log`: one JSON `MACQueuesSnapshot` per line,
+/// ordered by `taken_at`. Snapshots are replayed at their offset from the first one.
+#[derive(Debug)]
+pub struct MacReplay {
+    snapshots: Vec<MACQueuesSnapshot>,
+    next: usize,
+}
+
+impl MacReplay {
+    pub fn load<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let reader = BufReader::new(File::open(path)?);
+        let mut snapshots = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let snap: MACQueuesSnapshot = serde_json::from_str(&line)
+                .map_err(|e| std::io::Error::new(ErrorKind::InvalidData, e))?;
+            snapshots.push(snap);
+        }
+        if snapshots.is_empty() {
+            return Err(std::io::Error::new(ErrorKind::InvalidData, "no MAC snapshots recorded"));
+        }
+        snapshots.sort_by_key(|snap| snap.taken_at);
+        Ok(MacReplay { snapshots, next: 0 })
+    }
+
+    fn offset(&self, index: usize) -> f64 {
+        self.snapshots[index].taken_at
+            .duration_since(self.snapshots[0].taken_at)
+            .unwrap_or_default()
+            .as_secs_f64()
+    }
+
+    /// Length of the recording (seconds).
+    pub fn duration(&self) -> f64 {
+        self.offset(self.snapshots.len() - 1)
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.next >= self.snapshots.len()
+    }
+
+    /// Virtual clock: publish the newest snapshot recorded by `elapsed` seconds into the recording,
+    /// skipping older ones. It is stamped as taken now, so its age on the bus is that of the
+    /// replay rather than of the recording. Returns whether a snapshot was published.
+    pub fn advance_to(&mut self, bus: &LatestBus, elapsed: f64) -> bool {
+        let mut latest = None;
+        while !self.is_finished() && self.offset(self.next) <= elapsed {
+            latest = Some(self.next);
+            self.next += 1;
+        }
+        match latest {
+            Some(index) => {
+                bus.publish(MACQueuesSnapshot { taken_at: SystemTime::now(), ..self.snapshots[index].clone() });
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Seconds into the recording at which the next snapshot is due.
+    pub fn next_offset(&self) -> Option<f64> {
+        (!self.is_finished()).then(|| self.offset(self.next))
+    }
+}
+
+/// Wall clock: replay the recording in real time, scaled by `speed`, in place of `mon_mac_thread`.
+/// The last snapshot stays on the bus once the recording ends.
+pub fn mon_replay_thread(mut replay: MacReplay, bus: LatestBus, speed: f64) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        let start = SystemTime::now();
+        while let Some(due) = replay.next_offset() {
+            let elapsed = start.elapsed().unwrap_or_default().as_secs_f64() * speed;
+            if due > elapsed {
+                thread::sleep(Duration::from_secs_f64((due - elapsed) / speed));
+            }
+            let elapsed = start.elapsed().unwrap_or_default().as_secs_f64() * speed;
+            replay.advance_to(&bus, elapsed);
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use super::*;
+
+    // Snapshots recorded an hour ago, 10 ms apart
+    fn replay(count: u64) -> MacReplay {
+        let recorded = SystemTime::now() - Duration::from_secs(3600);
+        let snapshots = (0..count).map(|seq| MACQueuesSnapshot {
+            seq,
+            taken_at: recorded + Duration::from_millis(10 * seq),
+            queues: HashMap::new(),
+            link: HashMap::new(),
+        }).collect();
+        MacReplay { snapshots, next: 0 }
+    }
+
+    #[test]
+    fn advance_to_publishes_the_newest_due_snapshot() {
+        let bus = LatestBus::new(true);
+        let mut replay = replay(5);
+        assert!((replay.duration() - 0.04).abs() < 1e-9);
+        assert!(replay.advance_to(&bus, 0.025));
+        assert_eq!(bus.latest().seq, 2);
+        assert_eq!(replay.next_offset().map(|offset| (offset * 1000.0).round()), Some(30.0));
+        assert!(!replay.advance_to(&bus, 0.025));
+        assert!(replay.advance_to(&bus, 1.0));
+        assert_eq!(bus.latest().seq, 4);
+        assert!(replay.is_finished());
+    }
+
+    #[test]
+    fn replayed_snapshots_are_fresh() {
+        let bus = LatestBus::new(true);
+        let mut replay = replay(2);
+        replay.advance_to(&bus, 0.0);
+        let age = SystemTime::now().duration_since(bus.latest().taken_at).unwrap_or_default();
+        assert!(age < Duration::from_secs(1));
+        // The recorded times still pace the replay
+        assert_eq!(replay.next_offset().map(|offset| (offset * 1000.0).round()), Some(10.0));
+    }
+}
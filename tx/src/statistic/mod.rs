@@ -1,2 +1,8 @@
 pub mod rtt_records;
-pub mod mac_queue;
\ No newline at end of file
+pub mod link_records;
+pub mod link_health;
+pub mod mac_queue;
+pub mod mac_backend;
+pub mod mac_emulator;
+pub mod mac_replay;
+pub mod clock_sync;
\ No newline at end of file
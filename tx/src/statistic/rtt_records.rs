@@ -1,4 +1,8 @@
 use std::time::SystemTime;
+use log::warn;
+
+// Weight of a new sample in the smoothed RTT, as in TCP
+static SRTT_GAIN: f64 = 0.125;
 
 // use std::cmp::Ordering;
 #[derive(Debug, Clone)]
@@ -8,31 +12,36 @@ struct RTTEntry {
     arrival_time: f64,
     last_outage_time: f64,
     pong_time: Option<f64>,
-    delta: f64,
+    deltas: Vec<f64>, // per extra link, relative to link 0
+    reported: bool,   // deadline outcome already handed out
 }
 
 impl RTTEntry {
     fn new(seq: usize, arrival_time: f64, last_outage_time: f64) -> Self {
         RTTEntry {
             seq,
-            arrival_time: arrival_time,
-            last_outage_time: last_outage_time,
+            arrival_time,
+            last_outage_time,
             pong_time: None,
-            delta: 0.0,
+            deltas: Vec::new(),
+            reported: false,
         }
     }
 
-    fn update_value(&mut self, value: f64, delta : f64) -> f64 {
+    fn update_value(&mut self, value: f64, deltas: Vec<f64>) -> f64 {
         self.pong_time = Some(value);
-        self.delta = delta;
-        return value - self.arrival_time;
+        self.deltas = deltas;
+        value - self.arrival_time
     }
 }
 
+#[derive(Debug)]
 pub struct RttRecords {
     queue: Vec<Option<RTTEntry>>,
     target_rtt: f64,
     max_length: usize,
+    pub srtt: Option<f64>,
+    outcomes: Vec<(usize, bool)>, // (seq, met target_rtt) not yet taken
 }
 
 impl RttRecords {
@@ -41,6 +50,8 @@ impl RttRecords {
             queue: vec![None; max_length],
             target_rtt,
             max_length,
+            srtt: None,
+            outcomes: Vec::new(),
         }
     }
 
@@ -49,20 +60,40 @@ impl RttRecords {
         self.queue[index] = Some(RTTEntry::new(seq, arrival_time, arrival_time + self.target_rtt));
     }
 
-    pub fn update(&mut self, seq: usize, rtt: f64, delta: f64) -> f64 {
+    /// Record the pong of frame `seq`, returning its RTT; `None` once its slot is reused.
+    pub fn update(&mut self, seq: usize, rtt: f64, deltas: Vec<f64>) -> Option<f64> {
         let index = seq % self.max_length;
-        match &mut self.queue[index] {
-            Some(entry) => {
-                if entry.seq == seq {
-                    return entry.update_value(rtt, delta);
-                } else {
-                    panic!();
+        let rtt = match &mut self.queue[index] {
+            Some(entry) if entry.seq == seq => {
+                let rtt = entry.update_value(rtt, deltas);
+                if !entry.reported {
+                    entry.reported = true;
+                    self.outcomes.push((seq, rtt <= self.target_rtt));
                 }
+                rtt
+            }
+            _ => {
+                warn!("Dropped late pong of frame {}", seq);
+                return None;
             }
-            None => {
-                panic!();
+        };
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => srtt + SRTT_GAIN * (rtt - srtt),
+            None => rtt,
+        });
+        Some(rtt)
+    }
+
+    /// Frames whose deadline outcome is known since the last call: answered by a pong,
+    /// or still unanswered `target_rtt` after their arrival.
+    pub fn take_outcomes(&mut self, now: f64) -> Vec<(usize, bool)> {
+        for entry in self.queue.iter_mut().flatten() {
+            if !entry.reported && entry.pong_time.is_none() && now - entry.arrival_time > self.target_rtt {
+                entry.reported = true;
+                self.outcomes.push((entry.seq, false));
             }
         }
+        std::mem::take(&mut self.outcomes)
     }
 
     pub fn statistic(&mut self) -> (f64, f64) {
@@ -1,14 +1,18 @@
 use log::trace;
-use std::time::SystemTime;
+use std::time::Duration;
+use spin_sleep::{SpinSleeper, SpinStrategy};
 use std::collections::VecDeque;
-use core::packet::{PacketWithMeta,UDP_MAX_LENGTH};
+use core::clock::now_secs_f64;
+use core::packet::PacketWithMeta;
+use crate::aqm::{Aqm, AqmParams, DropReason, QueueDrops};
+use crate::conf::ExpiryParams;
+use crate::limiter::{create_limiter, LimiterParams, RateLimiter};
+use crate::utils::throttle_profile::ThrottleProfile;
 // use std::sync::{Arc, Mutex};
 
-type TIME = SystemTime;
-type SIZE = usize;
-
-static MAX_ERR_RATIO: f64 = 0.01;
 pub static CYCLED_RATIO: usize = 50;
+// Longest sleep while the limiter holds a packet back, so retransmissions and throttle changes are picked up (s)
+static MAX_WAIT: f64 = 0.001;
 
 struct CycledVecDequeue<T> {
     size: usize,
@@ -23,20 +27,13 @@ where T:Sized + Copy
         Self{ size, fifo }
     }
 
-    pub fn push(&mut self, item: T) -> Option<T> {
-        self.fifo.push_back(item);
-        if self.size>0 && self.fifo.len()==self.size {
-            self.fifo.pop_front()
-        } else{ None }
-    }
-
     pub fn try_push(&mut self, item: T) -> bool {
         if self.size>0 && self.fifo.len()==self.size {
-            return false;
+            false
         }
         else {
             self.fifo.push_back(item);
-            return true;
+            true
         }
     }
 
@@ -44,6 +41,10 @@ where T:Sized + Copy
         self.fifo.len()
     }
 
+    pub fn fits(&self, count: usize) -> bool {
+        self.size == 0 || self.fifo.len() + count <= self.size
+    }
+
     pub fn front(&self) -> Option<&T> {
         self.fifo.front()
     }
@@ -60,86 +61,163 @@ where T:Sized + Copy
 pub struct RateThrottler {
     pub name: String,
     is_log: bool,
-    window: CycledVecDequeue<(TIME, SIZE)>,
+    limiter: Box<dyn RateLimiter>,
+    sleeper: SpinSleeper,
     buffer: CycledVecDequeue<PacketWithMeta>,
-    sum_bytes: usize,
-    acc_error: usize,
-    max_error: usize,
+    retransmit: VecDeque<PacketWithMeta>, // served ahead of `buffer`
+    aqm: Aqm,
+    drops: QueueDrops,
+    expiry: Option<ExpiryParams>,
+    profile: Option<(ThrottleProfile, f64)>, // and when the stream started
+    started: Option<u32>, // frame the latest fragment sent from `buffer` belongs to
     frame_count: usize,
     //
     pub throttle: f64,
-    pub last_rate:f64,
 }
 
 impl RateThrottler {
-    pub fn new(name:String, throttle: f64, window_size:usize, no_logging:bool, infinite_buffer:bool) -> Self {
+    pub fn new(name:String, throttle: f64, limiter: LimiterParams, aqm: AqmParams, window_size:usize, no_logging:bool, infinite_buffer:bool) -> Self {
         let buffer = match infinite_buffer {
             true  => CycledVecDequeue::new(0),
             false => CycledVecDequeue::new(CYCLED_RATIO * window_size)
         };
         let is_log = !no_logging;
-        let window = CycledVecDequeue::new(window_size);
-        let max_error = (MAX_ERR_RATIO * window_size as f64) as usize * UDP_MAX_LENGTH;
+        let limiter = create_limiter(limiter, window_size);
 
-        // let last_rate = Arc::new(Mutex::new( 0.0 ));
         // let throttle = Arc::new(Mutex::new( throttle ));
 
-        Self{ name, is_log: is_log, window, buffer, throttle, last_rate:0.0,
-                sum_bytes:0, acc_error:0, max_error, frame_count:0 }
+        let sleeper = SpinSleeper::new(100_000).with_spin_strategy(SpinStrategy::YieldThread);
+
+        Self{ name, is_log, limiter, sleeper, buffer, retransmit: VecDeque::new(), aqm: Aqm::new(aqm), drops: QueueDrops::default(),
+                expiry: None, profile: None, started: None, throttle, frame_count:0 }
+    }
+
+    /// Follow `profile` from `start` on, or stop following one with `None`.
+    pub fn set_profile(&mut self, profile: Option<ThrottleProfile>, start: f64) {
+        self.profile = profile.map(|profile| (profile, start));
+    }
+
+    pub fn set_expiry(&mut self, expiry: Option<ExpiryParams>) {
+        self.expiry = expiry;
     }
 
     pub fn reset(&mut self) {
-        self.last_rate = 0.0;
-        (self.sum_bytes,self.acc_error,self.max_error) = (0,0,0);
-        self.window.reset();
+        self.limiter.reset();
+        self.aqm.reset();
+        self.started = None;
         self.buffer.reset();
+        self.retransmit.clear();
     }
 
-    pub fn current_rate_mbps(&mut self, extra_bytes:Option<usize>) -> Option<f64> {
-        if self.acc_error < self.max_error {
-            return Some(self.last_rate);
-            // if let Ok(rate)=self.last_rate.try_lock() { return Some(rate.clone()); }
-        } else {
-            self.acc_error = 0;
+    pub fn prepare(&mut self, packets: Vec<PacketWithMeta>) {
+        let timestamp = now_secs_f64();
+        let (_, _rate_mbps) = self.limiter.rates_mbps(timestamp);
+        if self.is_log {
+            trace!("Name {}, Time {:.9}, Buffer length {}, Rate, {:.6}\n", self.name, timestamp, self.buffer.len(), _rate_mbps);
+        }
+        self.frame_count += 1;
+
+        let qdelay = self.buffer.front().map_or(0.0, |head| timestamp - head.arrival_time);
+        let early_drop = self.aqm.drop_on_enqueue(qdelay, timestamp);
+        let reason = match self.aqm.params {
+            _ if early_drop => Some(DropReason::Pie),
+            AqmParams::WholeFrame if !self.buffer.fits(packets.len()) => Some(DropReason::Frame),
+            _ => None,
+        };
+        if let Some(reason) = reason {
+            self.drop_fragments(&packets, reason);
+            return;
         }
 
-        let acc_size = self.sum_bytes + extra_bytes.unwrap_or(0);
+        for packet in packets.into_iter() {
+            if self.buffer.try_push(packet) {
+                continue;
+            }
+            match self.aqm.params {
+                AqmParams::DropHead => {
+                    let head = self.buffer.pop_front();
+                    self.drop_fragments(head.as_slice(), DropReason::Head);
+                    self.buffer.try_push(packet);
+                }
+                _ => self.drop_fragments(&[packet], DropReason::Tail),
+            }
+        }
+    }
 
-        let _last_time = self.window.front()?.0;
-        let acc_time = SystemTime::now().duration_since( _last_time ).unwrap();
-        let acc_time = acc_time.as_nanos();
+    // Past its deadline, and either not started or to be cut short
+    fn expired(&self, packet: &PacketWithMeta, now: f64) -> bool {
+        self.expiry.is_some_and(|expiry| {
+            now > packet.arrival_time + expiry.deadline && (expiry.partial || self.started != Some(packet.seq))
+        })
+    }
 
-        let average_rate_mbps = 8.0 * (acc_size as f64/1e6) / (acc_time as f64*1e-9);
-        self.last_rate = average_rate_mbps;
-        // if let Ok(mut ref_rate) = self.last_rate.try_lock() {
-        //     *ref_rate = average_rate_mbps;
-        // }
-        Some(average_rate_mbps)
+    // Drop what is left of frame `seq` at the head of the buffer
+    fn drop_head_frame(&mut self, seq: u32, reason: DropReason) {
+        let mut frame = Vec::new();
+        while self.buffer.front().is_some_and(|head| head.seq == seq) {
+            frame.extend(self.buffer.pop_front());
+        }
+        if reason == DropReason::Expired {
+            self.drops.expired_frames += 1;
+        }
+        self.drop_fragments(&frame, reason);
     }
 
-    pub fn prepare(&mut self, packets: Vec<PacketWithMeta>) {
-        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
-        let _rate_mbps = self.current_rate_mbps(None).unwrap_or(0.0);
-        if self.is_log {
-            trace!("Name {}, Time {:.9}, Buffer length {}, Rate, {:.6}\n", self.name, timestamp, self.buffer.len(), _rate_mbps);
+    fn drop_fragments(&mut self, packets: &[PacketWithMeta], reason: DropReason) {
+        for packet in packets {
+            self.drops.count(reason, packet.length as usize);
+            if packet.last_one {
+                self.frame_count -= 1;
+            }
         }
-        for packet in packets.into_iter() {
-            self.buffer.try_push(packet);
+        if self.is_log {
+            if let Some(packet) = packets.first() {
+                trace!("Name {}, Time {:.9}, Dropped {} fragments of frame {}, Reason {:?}\n", self.name, now_secs_f64(), packets.len(), packet.seq, reason);
+            }
         }
-        self.frame_count += 1;
     }
 
-    pub fn try_consume<T>(&mut self, callback:T) -> Option<bool>
-    where T: Fn(PacketWithMeta) -> bool {
-        match self.buffer.front().cloned() {
+    /// Queue retransmitted fragments ahead of the frames waiting to be sent.
+    pub fn prepare_retransmit(&mut self, packets: Vec<PacketWithMeta>) {
+        self.retransmit.extend(packets);
+    }
+
+    pub fn try_consume<T>(&mut self, mut callback:T) -> Option<bool>
+    where T: FnMut(PacketWithMeta) -> bool {
+        let retransmit = !self.retransmit.is_empty();
+        let front = if retransmit { self.retransmit.front() } else { self.buffer.front() };
+        match front.cloned() {
             None => None,
             Some(packet) => {
-                if self.exceeds_with(packet.length as usize) {
-                    std::thread::sleep( std::time::Duration::from_nanos(100_000) );
+                let now = now_secs_f64();
+                if let Some(throttle) = self.profile.as_ref().and_then(|(profile, start)| profile.throttle_at(now - start)) {
+                    self.throttle = throttle;
+                }
+                if !retransmit && self.expired(&packet, now) {
+                    self.drop_head_frame(packet.seq, DropReason::Expired);
+                    return Some(false);
+                }
+                let wait = self.limiter.wait(packet.length as usize, self.throttle, now);
+                if wait > 0.0 {
+                    self.sleeper.sleep( Duration::from_secs_f64(wait.min(MAX_WAIT)) );
                     return Some(false);
                 }
-                match callback(packet) {
+                // What is left of a frame queued too long goes as a whole
+                if !retransmit && self.aqm.drop_on_dequeue(now - packet.arrival_time, now) {
+                    self.drop_head_frame(packet.seq, DropReason::CoDel);
+                    return Some(false);
+                }
+                let sent = callback(packet);
+                if sent {
+                    self.limiter.on_sent(packet.length as usize, self.throttle, now);
+                }
+                match sent {
+                    true if retransmit => {
+                        self.retransmit.pop_front();
+                        Some(true)
+                    }
                     true => {
+                        self.started = Some(packet.seq);
                         if packet.last_one {
                             self.frame_count -= 1;
                         }
@@ -153,43 +231,183 @@ impl RateThrottler {
     }
 
     pub fn consume(&mut self) -> Option<PacketWithMeta> {
-        let timestamp = SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_secs_f64();
-        let _rate_mbps = self.current_rate_mbps(None).unwrap_or(0.0);
+        let timestamp = now_secs_f64();
+        let (_, _rate_mbps) = self.limiter.rates_mbps(timestamp);
         if self.is_log {
             trace!("Name {}, Time {:.9}, Buffer length {}, Rate, {:.6}\n", self.name, timestamp, self.buffer.len(), _rate_mbps);
         }
         self.buffer.pop_front()
     }
 
-    pub fn exceeds_with(&mut self, size_bytes:usize) -> bool {
-        let _throttle = self.throttle;//.lock().unwrap().clone();
+    pub fn drops(&self) -> QueueDrops {
+        self.drops
+    }
 
-        if _throttle==0.0 || self.window.len()==0 {
-            self.sum_bytes += size_bytes;
-            if let Some(item) = self.window.push(( SystemTime::now(), size_bytes )) {
-                self.sum_bytes -= item.1 as usize;
-                self.acc_error += item.1;
-            }
-            return false;
+    /// (instantaneous rate, averaged rate, throttle, buffer length, frame count)
+    pub fn snapshot(&self) -> (f64, f64, f64, usize, usize) {
+        let (instant, average) = self.limiter.rates_mbps(now_secs_f64());
+        (instant, average, self.throttle, self.buffer.fifo.len(), self.frame_count)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::packet::HeaderVersion;
+
+    // Fragments of frame `seq` as the source hands them over, `offset` counting down to the last one
+    fn frame(seq: u32, num: usize, arrival_time: f64) -> Vec<PacketWithMeta> {
+        (0..num).map(|i| {
+            let mut packet = PacketWithMeta::new(0, HeaderVersion::default());
+            packet.seq = seq;
+            packet.offset = (num - 1 - i) as u16;
+            packet.length = 100;
+            packet.arrival_time = arrival_time;
+            packet.num = num;
+            packet.last_one = i == num - 1;
+            packet
+        }).collect()
+    }
+
+    // Unthrottled, with room for `CYCLED_RATIO` fragments unless the buffer is infinite
+    fn throttler(aqm: AqmParams, infinite_buffer: bool) -> RateThrottler {
+        RateThrottler::new("test".into(), 0.0, LimiterParams::default(), aqm, 1, true, infinite_buffer)
+    }
+
+    // Send everything left, returning the fragments in the order they went out
+    fn drain(throttler: &mut RateThrottler) -> Vec<(u32, u16)> {
+        let mut sent = Vec::new();
+        while throttler.try_consume(|packet| { sent.push((packet.seq, packet.offset)); true }).is_some() {}
+        sent
+    }
+
+    // Every fragment is queued, sent or counted as dropped, and `frame_count` counts the last fragments still queued
+    fn assert_accounted(throttler: &RateThrottler, queued: usize, sent: usize) {
+        let drops = throttler.drops();
+        let dropped = drops.tail + drops.head + drops.frame + drops.codel + drops.pie + drops.expired;
+        assert_eq!(throttler.buffer.len() + sent + dropped, queued);
+        assert_eq!(drops.bytes, dropped * 100);
+        let last_ones = throttler.buffer.fifo.iter().filter(|packet| packet.last_one).count();
+        assert_eq!(throttler.snapshot().4, last_ones);
+    }
+
+    #[test]
+    fn drop_tail_drops_incoming_fragments() {
+        let mut throttler = throttler(AqmParams::DropTail, false);
+        for seq in 0..3 {
+            throttler.prepare(frame(seq, 20, 0.0));
         }
+        assert_eq!(throttler.drops().tail, 10);
+        assert_eq!(throttler.snapshot().4, 2);
+        assert_accounted(&throttler, 60, 0);
 
-        self.acc_error += size_bytes;
+        let sent = drain(&mut throttler);
+        assert_eq!(sent.last(), Some(&(2, 10)));
+        assert_accounted(&throttler, 60, sent.len());
+        assert_eq!(throttler.snapshot().4, 0);
+    }
 
-        let average_rate_mbps = self.current_rate_mbps( Some(size_bytes) );
-        if average_rate_mbps.unwrap() < _throttle {
-            self.sum_bytes += size_bytes;
-            if let Some(item) = self.window.push(( SystemTime::now(), size_bytes )) {
-                self.sum_bytes -= item.1 as usize;
-                self.acc_error += item.1;
-            }
-            false
+    #[test]
+    fn drop_head_evicts_the_oldest_fragments() {
+        let mut throttler = throttler(AqmParams::DropHead, false);
+        for seq in 0..3 {
+            throttler.prepare(frame(seq, 20, 0.0));
         }
-        else {
-            true
+        assert_eq!(throttler.drops().head, 10);
+        assert_eq!(throttler.buffer.front().map(|head| (head.seq, head.offset)), Some((0, 9)));
+        assert_eq!(throttler.snapshot().4, 3);
+        assert_accounted(&throttler, 60, 0);
+
+        // Evicting the rest of frame 0, its last fragment included
+        throttler.prepare(frame(3, 20, 0.0));
+        assert_eq!(throttler.drops().head, 30);
+        assert_eq!(throttler.buffer.front().map(|head| (head.seq, head.offset)), Some((1, 9)));
+        assert_eq!(throttler.snapshot().4, 3);
+        assert_accounted(&throttler, 80, 0);
+
+        let sent = drain(&mut throttler);
+        assert_accounted(&throttler, 80, sent.len());
+        assert_eq!(throttler.snapshot().4, 0);
+    }
+
+    #[test]
+    fn whole_frame_drops_the_entire_frame() {
+        let mut throttler = throttler(AqmParams::WholeFrame, false);
+        for seq in 0..3 {
+            throttler.prepare(frame(seq, 20, 0.0));
+        }
+        assert_eq!(throttler.drops().frame, 20);
+        assert_eq!(throttler.drops().tail, 0);
+        assert_eq!(throttler.snapshot().4, 2);
+        assert_accounted(&throttler, 60, 0);
+
+        let sent = drain(&mut throttler);
+        assert_eq!(sent.len(), 40);
+        assert!(sent.iter().all(|&(seq, _)| seq < 2));
+        assert_accounted(&throttler, 60, sent.len());
+        assert_eq!(throttler.snapshot().4, 0);
+    }
+
+    #[test]
+    fn codel_drops_the_head_frame_past_its_target() {
+        let aqm = AqmParams::CoDel { target: 0.001, interval: 0.01 };
+        let mut throttler = throttler(aqm, true);
+        let arrival = now_secs_f64() - 1.0;
+        throttler.prepare(frame(0, 5, arrival));
+        throttler.prepare(frame(1, 5, arrival));
+
+        // Above the target for less than an interval, the head still goes out
+        assert_eq!(throttler.try_consume(|_| true), Some(true));
+        assert_eq!(throttler.drops().codel, 0);
+
+        std::thread::sleep(Duration::from_secs_f64(0.02));
+        assert_eq!(throttler.try_consume(|_| true), Some(false));
+        assert_eq!(throttler.drops().codel, 4);
+        assert_eq!(throttler.buffer.front().map(|head| (head.seq, head.offset)), Some((1, 4)));
+        assert_eq!(throttler.snapshot().4, 1);
+        assert_accounted(&throttler, 10, 1);
+    }
+
+    #[test]
+    fn codel_keeps_frames_below_its_target() {
+        let aqm = AqmParams::CoDel { target: 10.0, interval: 0.001 };
+        let mut throttler = throttler(aqm, true);
+        let arrival = now_secs_f64();
+        for seq in 0..3 {
+            throttler.prepare(frame(seq, 5, arrival));
         }
+        std::thread::sleep(Duration::from_secs_f64(0.01));
+        let sent = drain(&mut throttler);
+        assert_eq!(sent.len(), 15);
+        assert_eq!(throttler.drops().codel, 0);
+        assert_accounted(&throttler, 15, sent.len());
     }
 
-    pub fn snapshot(&self) -> (f64, f64, usize, usize) {
-        (self.last_rate, self.throttle, self.buffer.fifo.len(), self.frame_count)
+    #[test]
+    fn pie_drops_incoming_frames_past_its_target() {
+        // Updating the probability on every frame, with the head queued for a second
+        let aqm = AqmParams::Pie { target: 0.015, tupdate: 0.0, alpha: 0.125, beta: 1.25 };
+        let mut throttler = throttler(aqm, true);
+        let arrival = now_secs_f64() - 1.0;
+        for seq in 0..100 {
+            throttler.prepare(frame(seq, 3, arrival));
+        }
+        let drops = throttler.drops();
+        assert!(drops.pie > 0 && drops.pie.is_multiple_of(3));
+        // By then the probability has saturated
+        assert_eq!(throttler.buffer.fifo.back().map(|packet| packet.seq < 90), Some(true));
+        assert_accounted(&throttler, 300, 0);
+
+        let sent = drain(&mut throttler);
+        assert_accounted(&throttler, 300, sent.len());
+        assert_eq!(throttler.snapshot().4, 0);
+    }
+
+    #[test]
+    fn pie_keeps_frames_below_its_target() {
+        let mut aqm = Aqm::new(AqmParams::Pie { target: 0.015, tupdate: 0.015, alpha: 0.125, beta: 1.25 });
+        for i in 0..1000 {
+            assert!(!aqm.drop_on_enqueue(0.005, i as f64 * 0.015));
+        }
     }
 }
@@ -1,12 +1,31 @@
-use core::packet::{PacketType};
+use std::io::ErrorKind;
+use std::time::SystemTime;
+use log::warn;
+use core::packet::{PacketType, PacketWithMeta};
+use crate::arq::GuardedArqBuffer;
+use crate::conf::Link;
+use crate::rtt::{now_secs_f64, GuardedLinkRecords, GuardedRttRecords};
+use crate::shaper::GuardedShaper;
+use crate::statistic::link_health::LinkHealthMonitor;
 use crate::statistic::mac_queue::{LatestBus};
-use crate::policies::{Policy, PolicyParameter, SchedulingMessage};
+use crate::policies::{create_policy, PolicyContext, PolicyParameter, SchedulingMessage, SchedulingPolicy};
 
+// Shortest time between two link health refreshes (s)
+static HEALTH_REFRESH: f64 = 0.005;
 
 #[derive(Debug)]
 pub struct TxPartCtler {
-    pub policy: Policy,
-    pub blocked_signals: Vec<bool>,
+    pub policy: Box<dyn SchedulingPolicy>,
+    pub rtt_records: Option<GuardedRttRecords>,
+    pub link_records: Option<GuardedLinkRecords>,
+    pub arq: Option<GuardedArqBuffer>,
+    last_link: usize, // link of the latest source fragment
+    pub links: Vec<Link>,
+    pub health: LinkHealthMonitor,
+    health_refreshed_at: f64,
+    pub blocked_signals: Vec<bool>, // links not up, or out of their aggregate share
+    pub shaper: Option<(GuardedShaper, usize)>, // and the stream id in it
+    pub duplicate: bool, // send every fragment on all links
     pub log_str: String,
     pub policy_parameters: PolicyParameter,
     pub mac_info_bus: LatestBus,
@@ -14,10 +33,20 @@ pub struct TxPartCtler {
 }
 
 impl TxPartCtler {
-    pub fn new(policy: Policy, policy_parameters: PolicyParameter, mac_info_bus: LatestBus) -> Self {
+    pub fn new(policy: &str, policy_parameters: PolicyParameter, links: Vec<Link>, health: LinkHealthMonitor, mac_info_bus: LatestBus) -> Self {
+        let policy = create_policy(policy, &policy_parameters, links.len()).expect("Failed to create scheduling policy");
         TxPartCtler {
             policy,
-            blocked_signals: vec![false; 2],
+            rtt_records: None,
+            link_records: None,
+            arq: None,
+            shaper: None,
+            duplicate: false,
+            last_link: 0,
+            blocked_signals: health.blocked_signals(),
+            health,
+            health_refreshed_at: 0.0,
+            links,
             mac_info_bus,
             policy_parameters,
             log_str: String::new(),
@@ -25,17 +54,134 @@ impl TxPartCtler {
         }
     }
 
+    /// Replace the policy parameters, keeping the old ones if the policy rejects them.
+    pub fn set_policy_parameters(&mut self, parameters: PolicyParameter) {
+        match self.policy.configure(&parameters) {
+            Ok(()) => self.policy_parameters = parameters,
+            Err(e) => {
+                warn!("Rejected policy parameters: {}", e);
+                let _ = self.policy.configure(&self.policy_parameters);
+            }
+        }
+    }
+
+    /// Bring the link health up to date with the acks and interfaces, and block links not up.
+    /// Calls within `HEALTH_REFRESH` of the last refresh do nothing.
+    pub fn refresh_health(&mut self, now: f64) {
+        if now - self.health_refreshed_at < HEALTH_REFRESH {
+            return;
+        }
+        self.health_refreshed_at = now;
+        let delivered_at = self.link_records.as_ref()
+            .and_then(|rec| Some(rec.lock().ok()?.delivered_at()))
+            .unwrap_or_default();
+        self.health.update(now, &delivered_at);
+        self.blocked_signals = self.health.blocked_signals();
+    }
+
+    pub fn on_send_error(&mut self, link: usize, kind: ErrorKind, now: f64) {
+        self.health.on_send_error(link, kind, now);
+        self.blocked_signals = self.health.blocked_signals();
+    }
+
+    /// Fragment of frame `seq` with `size` bytes has left on `link` at `now`.
+    pub fn on_link_sent(&mut self, seq: usize, link: usize, size: usize, now: f64) {
+        if let Some(ref rec) = self.link_records {
+            rec.lock().unwrap().on_sent(seq, link, now);
+        }
+        self.health.on_sent(link, now);
+        self.blocked_signals = self.health.blocked_signals();
+        if let Some((ref shaper, stream)) = self.shaper {
+            shaper.lock().unwrap().on_sent(stream, &self.links[link].tx_ipaddr, size, now);
+        }
+    }
+
+    /// Links to send a copy of a fragment sent on `link` to: all others when duplicating,
+    /// else the down links due for a probe.
+    pub fn copies(&mut self, link: usize, now: f64) -> Vec<usize> {
+        let links = match self.duplicate {
+            true => (0..self.links.len()).collect(),
+            false => self.health.take_probes(now),
+        };
+        links.into_iter().filter(|&other| other != link).collect()
+    }
+
+    /// How long the aggregate caps hold `size` bytes back on `link`, `None` if it may go now.
+    pub fn shaped_out(&mut self, link: usize, size: usize, now: f64) -> Option<f64> {
+        let (shaper, stream) = self.shaper.as_ref()?;
+        Some(shaper.lock().unwrap().wait(*stream, &self.links[link].tx_ipaddr, size, now)).filter(|&wait| wait > 0.0)
+    }
+
+    /// Block the links the aggregate caps hold `size` bytes back on, returning how long to
+    /// wait when that is all of them.
+    pub fn shape(&mut self, size: usize, now: f64) -> Option<f64> {
+        let (shaper, stream) = self.shaper.as_ref()?;
+        let mut shaper = shaper.lock().unwrap();
+        self.blocked_signals = self.health.blocked_signals();
+        let waits: Vec<f64> = self.links.iter().map(|link| shaper.wait(*stream, &link.tx_ipaddr, size, now)).collect();
+        for (blocked, &wait) in self.blocked_signals.iter_mut().zip(&waits) {
+            *blocked |= wait > 0.0;
+        }
+        waits.into_iter().min_by(f64::total_cmp).filter(|&wait| wait > 0.0)
+    }
+
+    fn context(&self, params: &SchedulingMessage) -> PolicyContext {
+        let frame_rtt = self.rtt_records.as_ref().and_then(|rec| rec.lock().ok()?.srtt);
+        let mac_age = self.mac_info_bus.is_mon.then(|| {
+            SystemTime::now().duration_since(params.current_time).unwrap_or_default().as_secs_f64()
+        });
+        let links = self.link_records.as_ref()
+            .and_then(|rec| Some(rec.lock().ok()?.estimates()))
+            .unwrap_or_default();
+        PolicyContext { now: now_secs_f64(), frame_rtt, links, mac_age }
+    }
+
     pub fn get_packet_state(&mut self, params: SchedulingMessage) -> PacketType {
-        let packet_type = self.policy.get_packet_state(params, &self.policy_parameters);
-        self.schedule_message
-        .as_mut()
-        .filter(|_| self.mac_info_bus.is_mon)
-        .map(|sm| sm.update_sended_counter(&packet_type));
+        // Hand the policy the deadline outcomes once per frame
+        if params.offset == 0 {
+            let outcomes = self.rtt_records.as_ref()
+                .and_then(|rec| Some(rec.lock().ok()?.take_outcomes(now_secs_f64())))
+                .unwrap_or_default();
+            for (seq, hit) in outcomes {
+                self.policy.on_feedback(seq, hit);
+            }
+        }
+        let ctx = self.context(&params);
+        let packet_type = self.policy.get_packet_state(&params, &ctx);
+        self.last_link = packet_type.link();
+        if let Some(sm) = self.schedule_message.as_mut().filter(|_| self.mac_info_bus.is_mon) {
+            sm.update_sended_counter(&packet_type);
+        }
 
         packet_type
     }
 
-    pub fn determine_schedule_info(&mut self, packet: core::packet::PacketWithMeta) -> Option<SchedulingMessage>{
+    /// Retransmit a fragment on the unblocked link with the lowest RTT other than the one it
+    /// was last sent on, else on that same link.
+    pub fn get_retransmit_state(&mut self, packet: &PacketWithMeta) -> PacketType {
+        let links = self.link_records.as_ref()
+            .and_then(|rec| Some(rec.lock().ok()?.estimates()))
+            .unwrap_or_default();
+        let srtt = |link: usize| links.get(link).and_then(|state| state.srtt).unwrap_or(f64::INFINITY);
+        let link = (0..self.links.len())
+            .filter(|&link| link != packet.channel && !self.blocked_signals[link])
+            .min_by(|&a, &b| srtt(a).total_cmp(&srtt(b)))
+            .unwrap_or(packet.channel);
+        PacketType::new(link, packet.last_one)
+    }
+
+    /// Spread parity fragments round robin over the unblocked links, starting after the link of
+    /// the frame's last source fragment.
+    pub fn get_parity_state(&mut self, packet: &PacketWithMeta) -> PacketType {
+        let link_num = self.links.len();
+        let next: Vec<usize> = (1..=link_num).map(|i| (self.last_link + i) % link_num).collect();
+        let unblocked: Vec<usize> = next.iter().copied().filter(|&link| !self.blocked_signals[link]).collect();
+        let links = if unblocked.is_empty() { next } else { unblocked };
+        let link = links[(packet.offset as usize - packet.source_num()) % links.len()];
+        PacketType::new(link, packet.last_one)
+    }
+
+    pub fn determine_schedule_info(&mut self, packet: PacketWithMeta) -> Option<SchedulingMessage>{
         let mac_info = self.mac_info_bus.latest().as_ref().to_owned();
         if self.mac_info_bus.is_mon && ( mac_info.queues.is_empty() || mac_info.link.is_empty() ) {
             return None;
@@ -46,17 +192,72 @@ impl TxPartCtler {
                 sm.update(packet, self.blocked_signals.clone());
             }
             _ => {
+                // MAC statistics are keyed by tx address; lay them out in link order
                 self.schedule_message = Some(SchedulingMessage::new(
                     packet, 
                     mac_info.taken_at,
                     self.blocked_signals.clone(),
-                    mac_info.queues.values()
-                    .map(|qinfo| qinfo.get(&1).cloned().unwrap_or(0))
+                    self.links.iter()
+                    .map(|link| mac_info.queues.get(&link.tx_ipaddr).and_then(|qinfo| qinfo.get(&1).cloned()).unwrap_or(0))
+                    .collect(),
+                    self.links.iter()
+                    .map(|link| mac_info.link.get(&link.tx_ipaddr).and_then(|info| info.tx_mbit_s))
                     .collect(),
-                    mac_info.link.values().map(|link| link.tx_mbit_s).collect(),
                 ));
             }
         };
         self.schedule_message.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+    use core::packet::HeaderVersion;
+    use crate::shaper::{Shaper, ShapingParams};
+    use crate::statistic::link_health::LinkHealthParams;
+    use super::*;
+
+    fn link(tx_ipaddr: &str) -> Link {
+        Link { tx_ipaddr: tx_ipaddr.to_string(), rx_ipaddr: "127.0.0.1".to_string(), emulation: None }
+    }
+
+    #[test]
+    fn a_picked_link_is_held_to_its_cap_while_others_have_room() {
+        let links = vec![link("192.0.2.1"), link("192.0.2.2")];
+        let tx_ipaddrs: Vec<String> = links.iter().map(|link| link.tx_ipaddr.clone()).collect();
+        let health = LinkHealthMonitor::new(LinkHealthParams::default(), &tx_ipaddrs, None);
+        // HardThreshold keeping every fragment on the first link, capped at 1e6 bytes per second
+        let params = PolicyParameter { theta_1: 1.0, ..Default::default() };
+        let mut controller = TxPartCtler::new("HardThreshold", params, links, health, LatestBus::new(false));
+        let shaping = ShapingParams { links_mbps: HashMap::from([("192.0.2.1".to_string(), 8.0)]), ..Default::default() };
+        let mut shaper = Shaper::new(shaping);
+        let stream = shaper.register(1.0);
+        controller.shaper = Some((Arc::new(Mutex::new(shaper)), stream));
+
+        let mut packet = PacketWithMeta::new(0, HeaderVersion::default());
+        (packet.num, packet.last_one, packet.length) = (1, true, 1000);
+        let (mut sent, mut held) = (0, 0);
+        for step in 0..1000 {
+            let now = step as f64 * 0.0001;
+            packet.seq = step;
+            // The second link has room, so the packet is not held before the pick
+            assert_eq!(controller.shape(1000, now), None);
+            let params = controller.determine_schedule_info(packet).unwrap();
+            let link = controller.get_packet_state(params).link();
+            assert_eq!(link, 0);
+            match controller.shaped_out(link, 1000, now) {
+                Some(_) => held += 1,
+                None => {
+                    controller.on_link_sent(step as usize, link, 1000, now);
+                    sent += 1000;
+                }
+            }
+        }
+        // 0.1 s at the cap on top of the burst
+        let burst = ShapingParams::default().burst_bytes;
+        assert!(held > 0);
+        assert!((100_000..=100_000 + burst + 1000).contains(&sent), "{} bytes sent", sent);
+    }
+}
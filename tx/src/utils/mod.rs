@@ -1,2 +1,5 @@
 pub mod trace_reader;
-pub mod ip_helper;
\ No newline at end of file
+pub mod ip_helper;
+pub mod throttle_profile;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub mod nl80211;
\ No newline at end of file
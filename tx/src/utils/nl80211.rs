@@ -0,0 +1,189 @@
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+
+// netlink / generic netlink framing, see <linux/netlink.h> and <linux/genetlink.h>
+const NLMSG_HDRLEN: usize = 16;
+const GENL_HDRLEN: usize = 4;
+const NLA_HDRLEN: usize = 4;
+const NLA_TYPE_MASK: u16 = 0x3fff;
+const NLM_F_REQUEST: u16 = 0x1;
+const NLM_F_DUMP: u16 = 0x300;
+const NLMSG_ERROR: u16 = 2;
+const NLMSG_DONE: u16 = 3;
+const GENL_ID_CTRL: u16 = 0x10;
+const CTRL_CMD_GETFAMILY: u8 = 3;
+const CTRL_ATTR_FAMILY_ID: u16 = 1;
+const CTRL_ATTR_FAMILY_NAME: u16 = 2;
+
+// <linux/nl80211.h>
+const NL80211_CMD_GET_STATION: u8 = 17;
+const NL80211_ATTR_IFINDEX: u16 = 3;
+const NL80211_ATTR_MAC: u16 = 6;
+const NL80211_ATTR_STA_INFO: u16 = 21;
+const NL80211_STA_INFO_SIGNAL: u16 = 7;
+const NL80211_STA_INFO_TX_BITRATE: u16 = 8;
+const NL80211_RATE_INFO_BITRATE: u16 = 1;    // u16, 100 kbit/s
+const NL80211_RATE_INFO_BITRATE32: u16 = 5;  // u32, 100 kbit/s
+
+const RECV_TIMEOUT_US: libc::suseconds_t = 200_000;
+
+#[derive(Debug, Clone, Default)]
+pub struct StationInfo {
+    pub mac: Option<String>,
+    pub signal_dbm: Option<i32>,
+    pub tx_mbit_s: Option<f32>,
+}
+
+fn align(len: usize) -> usize {
+    (len + 3) & !3
+}
+
+fn put_attr(buf: &mut Vec<u8>, kind: u16, payload: &[u8]) {
+    buf.extend_from_slice(&((NLA_HDRLEN + payload.len()) as u16).to_ne_bytes());
+    buf.extend_from_slice(&kind.to_ne_bytes());
+    buf.extend_from_slice(payload);
+    buf.resize(align(buf.len()), 0);
+}
+
+// Iterate `(type, payload)` over a run of netlink attributes
+fn attrs(mut buf: &[u8]) -> impl Iterator<Item = (u16, &[u8])> {
+    std::iter::from_fn(move || {
+        if buf.len() < NLA_HDRLEN {
+            return None;
+        }
+        let len = u16::from_ne_bytes([buf[0], buf[1]]) as usize;
+        let kind = u16::from_ne_bytes([buf[2], buf[3]]) & NLA_TYPE_MASK;
+        if len < NLA_HDRLEN || len > buf.len() {
+            return None;
+        }
+        let payload = &buf[NLA_HDRLEN..len];
+        buf = &buf[align(len).min(buf.len())..];
+        Some((kind, payload))
+    })
+}
+
+fn find_attr(buf: &[u8], kind: u16) -> Option<&[u8]> {
+    attrs(buf).find(|(k, _)| *k == kind).map(|(_, payload)| payload)
+}
+
+/// Minimal generic netlink client for nl80211 station queries.
+#[derive(Debug)]
+pub struct Nl80211 {
+    fd: OwnedFd,
+    family: u16,
+    seq: u32,
+}
+
+impl Nl80211 {
+    pub fn connect() -> io::Result<Self> {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW | libc::SOCK_CLOEXEC, libc::NETLINK_GENERIC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let timeout = libc::timeval { tv_sec: 0, tv_usec: RECV_TIMEOUT_US };
+        unsafe {
+            libc::setsockopt(fd.as_raw_fd(), libc::SOL_SOCKET, libc::SO_RCVTIMEO,
+                &timeout as *const _ as *const libc::c_void, std::mem::size_of::<libc::timeval>() as libc::socklen_t);
+        }
+
+        let mut nl = Nl80211 { fd, family: 0, seq: 0 };
+        let mut request = Vec::new();
+        put_attr(&mut request, CTRL_ATTR_FAMILY_NAME, b"nl80211\0");
+        nl.family = nl.request(GENL_ID_CTRL, 0, CTRL_CMD_GETFAMILY, &request)?
+            .iter()
+            .find_map(|reply| find_attr(reply, CTRL_ATTR_FAMILY_ID))
+            .filter(|id| id.len() >= 2)
+            .map(|id| u16::from_ne_bytes([id[0], id[1]]))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "nl80211 family not found"))?;
+        Ok(nl)
+    }
+
+    /// The first station of `dev`, i.e. the AP for an interface in managed mode.
+    pub fn station(&mut self, dev: &str) -> io::Result<Option<StationInfo>> {
+        let name = std::ffi::CString::new(dev).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+        let ifindex = unsafe { libc::if_nametoindex(name.as_ptr()) };
+        if ifindex == 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut request = Vec::new();
+        put_attr(&mut request, NL80211_ATTR_IFINDEX, &ifindex.to_ne_bytes());
+        let replies = self.request(self.family, NLM_F_DUMP, NL80211_CMD_GET_STATION, &request)?;
+
+        Ok(replies.iter().find_map(|reply| {
+            let sta_info = find_attr(reply, NL80211_ATTR_STA_INFO)?;
+            let mac = find_attr(reply, NL80211_ATTR_MAC)
+                .map(|mac| mac.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().join(":"));
+            let signal_dbm = find_attr(sta_info, NL80211_STA_INFO_SIGNAL)
+                .and_then(|v| v.first())
+                .map(|&v| v as i8 as i32);
+            let tx_mbit_s = find_attr(sta_info, NL80211_STA_INFO_TX_BITRATE).and_then(|rate| {
+                match (find_attr(rate, NL80211_RATE_INFO_BITRATE32), find_attr(rate, NL80211_RATE_INFO_BITRATE)) {
+                    (Some(v), _) if v.len() >= 4 => Some(u32::from_ne_bytes([v[0], v[1], v[2], v[3]]) as f32 / 10.0),
+                    (_, Some(v)) if v.len() >= 2 => Some(u16::from_ne_bytes([v[0], v[1]]) as f32 / 10.0),
+                    _ => None,
+                }
+            });
+            Some(StationInfo { mac, signal_dbm, tx_mbit_s })
+        }))
+    }
+
+    // Send one generic netlink request, returning the attribute payload of every reply
+    fn request(&mut self, family: u16, flags: u16, cmd: u8, attrs: &[u8]) -> io::Result<Vec<Vec<u8>>> {
+        self.seq = self.seq.wrapping_add(1);
+        let mut msg = Vec::with_capacity(NLMSG_HDRLEN + GENL_HDRLEN + attrs.len());
+        msg.extend_from_slice(&((NLMSG_HDRLEN + GENL_HDRLEN + attrs.len()) as u32).to_ne_bytes());
+        msg.extend_from_slice(&family.to_ne_bytes());
+        msg.extend_from_slice(&(NLM_F_REQUEST | flags).to_ne_bytes());
+        msg.extend_from_slice(&self.seq.to_ne_bytes());
+        msg.extend_from_slice(&0u32.to_ne_bytes());
+        msg.extend_from_slice(&[cmd, 1, 0, 0]);
+        msg.extend_from_slice(attrs);
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        let sent = unsafe {
+            libc::sendto(self.fd.as_raw_fd(), msg.as_ptr() as *const libc::c_void, msg.len(), 0,
+                &addr as *const _ as *const libc::sockaddr, std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t)
+        };
+        if sent < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut replies = Vec::new();
+        let mut buf = vec![0u8; 32 * 1024];
+        loop {
+            let len = unsafe { libc::recv(self.fd.as_raw_fd(), buf.as_mut_ptr() as *mut libc::c_void, buf.len(), 0) };
+            if len < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut rest = &buf[..len as usize];
+            while rest.len() >= NLMSG_HDRLEN {
+                let msg_len = u32::from_ne_bytes(rest[0..4].try_into().unwrap()) as usize;
+                let msg_type = u16::from_ne_bytes([rest[4], rest[5]]);
+                let msg_seq = u32::from_ne_bytes(rest[8..12].try_into().unwrap());
+                if msg_len < NLMSG_HDRLEN || msg_len > rest.len() {
+                    break;
+                }
+                let body = &rest[NLMSG_HDRLEN..msg_len];
+                rest = &rest[align(msg_len).min(rest.len())..];
+                if msg_seq != self.seq {
+                    continue;
+                }
+                match msg_type {
+                    NLMSG_DONE => return Ok(replies),
+                    NLMSG_ERROR => {
+                        let code = body.get(0..4).map_or(0, |v| i32::from_ne_bytes(v.try_into().unwrap()));
+                        return if code == 0 { Ok(replies) } else { Err(io::Error::from_raw_os_error(-code)) };
+                    }
+                    _ if body.len() >= GENL_HDRLEN => replies.push(body[GENL_HDRLEN..].to_vec()),
+                    _ => {}
+                }
+            }
+            // A plain request is answered in one datagram, a dump ends with NLMSG_DONE
+            if flags & NLM_F_DUMP == 0 && !replies.is_empty() {
+                return Ok(replies);
+            }
+        }
+    }
+}
@@ -0,0 +1,118 @@
+use std::io;
+use ndarray::prelude::*;
+use ndarray_npy::read_npy;
+use serde::{Deserialize, Serialize};
+
+/// Throttle the source applies by itself over the run, from `(time, Mbps)` points with
+/// the time in seconds since the stream started. Before the first point the stream's
+/// `throttle` holds.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ThrottleProfile {
+    #[serde(default)] pub points: Vec<[f64; 2]>, //default: []
+    #[serde(default)] pub file: Option<String>,  //default: none, CSV `time,mbps` rows or an N×2 NPY array replacing `points`
+    #[serde(default)] pub linear: bool,          //default: false, piecewise constant
+    #[serde(default)] pub repeat: bool,          //default: false, the last point holds instead of starting over
+}
+
+impl ThrottleProfile {
+    /// Read the points from `file`, if any, and put them in time order.
+    pub fn load(&mut self) -> io::Result<()> {
+        if let Some(ref file) = self.file {
+            self.points = match file.ends_with(".npy") {
+                true => {
+                    let array: Array2<f64> = read_npy(file).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                    if array.ncols() < 2 {
+                        return Err(io::Error::new(io::ErrorKind::InvalidData, "throttle profile needs time and Mbps columns"));
+                    }
+                    array.rows().into_iter().map(|row| [row[0], row[1]]).collect()
+                }
+                // Lines that do not parse, such as a header, are skipped
+                false => std::fs::read_to_string(file)?.lines()
+                    .filter_map(|line| {
+                        let mut fields = line.split([',', ' ', '\t']).filter(|f| !f.is_empty()).map(|f| f.parse::<f64>());
+                        Some([fields.next()?.ok()?, fields.next()?.ok()?])
+                    })
+                    .collect(),
+            };
+        }
+        if self.points.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "throttle profile has no points"));
+        }
+        self.points.sort_by(|a, b| a[0].total_cmp(&b[0]));
+        Ok(())
+    }
+
+    /// Throttle `elapsed` seconds after the stream started, `None` before the first point.
+    pub fn throttle_at(&self, elapsed: f64) -> Option<f64> {
+        let (first, last) = (self.points.first()?, self.points.last()?);
+        let t = match self.repeat && last[0] > first[0] && elapsed >= last[0] {
+            true => first[0] + (elapsed - first[0]) % (last[0] - first[0]),
+            false => elapsed,
+        };
+        // The latest point at or before `t`
+        let i = self.points.partition_point(|point| point[0] <= t).checked_sub(1)?;
+        let [t0, rate0] = self.points[i];
+        match self.points.get(i + 1) {
+            Some(&[t1, rate1]) if self.linear && t1 > t0 => Some(rate0 + (rate1 - rate0) * (t - t0) / (t1 - t0)),
+            _ => Some(rate0),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile(points: &[[f64; 2]], linear: bool, repeat: bool) -> ThrottleProfile {
+        ThrottleProfile { points: points.to_vec(), file: None, linear, repeat }
+    }
+
+    fn assert_close(value: Option<f64>, expected: f64) {
+        let value = value.unwrap();
+        assert!((value - expected).abs() < 1e-9, "{} != {}", value, expected);
+    }
+
+    #[test]
+    fn nothing_holds_before_the_first_point() {
+        let profile = profile(&[[1.0, 10.0], [2.0, 20.0]], true, true);
+        assert_eq!(profile.throttle_at(0.5), None);
+        assert_close(profile.throttle_at(1.0), 10.0);
+    }
+
+    #[test]
+    fn points_hold_until_the_next_one() {
+        let profile = profile(&[[0.0, 10.0], [2.0, 20.0]], false, false);
+        assert_close(profile.throttle_at(1.9), 10.0);
+        assert_close(profile.throttle_at(2.0), 20.0);
+        assert_close(profile.throttle_at(100.0), 20.0);
+    }
+
+    #[test]
+    fn linear_profiles_interpolate_between_points() {
+        let profile = profile(&[[0.0, 10.0], [2.0, 20.0], [4.0, 0.0]], true, false);
+        assert_close(profile.throttle_at(1.0), 15.0);
+        assert_close(profile.throttle_at(3.5), 5.0);
+        assert_close(profile.throttle_at(5.0), 0.0);
+    }
+
+    #[test]
+    fn repeating_profiles_start_over_after_the_last_point() {
+        // A period of two seconds, from the first point to the last
+        let profile = profile(&[[1.0, 10.0], [2.0, 20.0], [3.0, 30.0]], false, true);
+        assert_close(profile.throttle_at(3.0), 10.0);
+        assert_close(profile.throttle_at(4.5), 20.0);
+        let profile = ThrottleProfile { linear: true, ..profile };
+        assert_close(profile.throttle_at(5.5), 15.0);
+    }
+
+    #[test]
+    fn csv_files_skip_headers_and_are_sorted() {
+        let path = std::env::temp_dir().join(format!("throttle-profile-{}.csv", std::process::id()));
+        std::fs::write(&path, "time,mbps\n2,20\n0,10\n").unwrap();
+        let mut profile = ThrottleProfile { file: Some(path.to_str().unwrap().to_string()), ..Default::default() };
+        let loaded = profile.load();
+        let _ = std::fs::remove_file(&path);
+        loaded.unwrap();
+        assert_eq!(profile.points, vec![[0.0, 10.0], [2.0, 20.0]]);
+    }
+}
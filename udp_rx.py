@@ -26,14 +26,38 @@ def to_rate(x:str) -> float:
     else:
         raise argparse.ArgumentTypeError('Rate should ends with [B|KB|MB].')
 
+HEADER_MAGIC = 0xA0
+MAGIC_MASK = 0xF0
+LINK_ID_MASK = 0x3F
+LAST_PACKET_FLAG = 0x80
+HEADER_FORMATS = {
+    # version: (struct format after the magic/version byte, header length)
+    1: ('<IHHB', 10),
+    2: ('<IHHBdd', 26),     # + frame arrival time, fragment send time
+    3: ('<IHHBddBHBH', 32), # + fec scheme, source and parity fragments, last source length
+}
+
 def extract(buffer):
-    # seq, offset, _length, _port, num, timestamp = struct.unpack(
-    #     '<IHHHHd', buffer[:20])
-    seq, offset, _length, _port, num, indicator , timestamp = struct.unpack(
-    '<IHHHHbd', buffer[:21])
-    # seq, offset, _length, _port, timestamp = struct.unpack(
-    #     '<IHHHd', buffer[:18])
-    return (timestamp, seq, offset, num, indicator)
+    if len(buffer) < 1 or (buffer[0] & MAGIC_MASK) != HEADER_MAGIC:
+        raise ValueError('bad header magic')
+    version = buffer[0] & ~MAGIC_MASK & 0xFF
+    if version not in HEADER_FORMATS:
+        raise ValueError(f'unsupported header version {version}')
+    fmt, header_len = HEADER_FORMATS[version]
+    if len(buffer) < header_len:
+        raise ValueError(f'buffer too short: {len(buffer)} < {header_len}')
+    seq, offset, length, indicator, *timestamps = struct.unpack(fmt, buffer[1:header_len])
+    if len(buffer) < header_len + length:
+        raise ValueError(f'buffer too short: {len(buffer)} < {header_len + length}')
+    # sender frame arrival time if the header carries it
+    timestamp = timestamps[0] if timestamps else None
+    return (version, seq, offset, length, indicator, timestamp)
+
+def make_pong(buffer, deltas):
+    _, header_len = HEADER_FORMATS[buffer[0] & ~MAGIC_MASK & 0xFF]
+    pong = bytearray(buffer[:header_len])
+    pong[7:9] = struct.pack('<H', 8 * len(deltas))
+    return bytes(pong) + struct.pack(f'<{len(deltas)}d', *deltas)
 
 
 # def process_packet(args, _buffer, addr, pong_port, pong_sock, seq_offset):
@@ -47,36 +71,36 @@ def recv_thread(args, sock, pong_port, pong_sock, trigger):
     global received_length
     trigger.acquire()  # block until first started
     print('started.')
-    seq_offset = []
+    frames = {}
     while True:
         _buffer, addr = sock.recvfrom(2048)
         received_length += len(_buffer)
         if args.calc_jitter:
-            timestamp, seq, offset, num, indicator = extract(_buffer)
+            try:
+                version, seq, offset, length, indicator, timestamp = extract(_buffer)
+            except ValueError as e:
+                print(f'drop malformed packet: {e}')
+                continue
             if seq not in received_record:
-                received_record[seq] = (timestamp, time.time())
-            while seq >= len(seq_offset):
-                seq_offset.append([[num, -1], [0,0]])
-            if type(received_record[seq]) == tuple:
-                if indicator % 10 == 0 and offset < seq_offset[seq][0][indicator % 10]:
-                    seq_offset[seq][0][indicator % 10] = offset
-                elif indicator % 10 == 1 and offset > seq_offset[seq][0][indicator % 10]:
-                    seq_offset[seq][0][indicator % 10] = offset
-                #print(offset, seq_offset[seq][0])
-                if (args.calc_rtt and indicator >= 10):
-                    seq_offset[seq][1][indicator % 10] = seq_offset[seq][1][1 - indicator % 10] + 1
-                    # seq_offset[seq][1][indicator % 10] = time.time() - received_record[seq][1]
-                if seq_offset[seq][0][0] - seq_offset[seq][0][1] == 1:  #end of packet
-                    if args.calc_rtt:
-                        duration = time.time() - received_record[seq][1]
-                        _buffer = bytearray(_buffer)
-                        _buffer[10:18] = struct.pack('d', duration) 
-                        _buffer[18:26] = struct.pack('d', seq_offset[seq][1][0])
-                        _buffer[26:34] = struct.pack('d', seq_offset[seq][1][1])
-                        pong_addr = (addr[0], pong_port)
-                        pong_sock.sendto(_buffer, pong_addr)
-                    received_record[seq] = time.time() - received_record[seq][0]
-                    seq_offset[seq] = []
+                # one-way delay against the sender stamp, else time to complete the frame
+                received_record[seq] = (timestamp or time.time(), )
+            if type(received_record[seq]) != tuple:
+                continue
+            # frame: [received offsets, last offset, per-link rx time]
+            frame = frames.setdefault(seq, [set(), None, {}])
+            frame[0].add(offset)
+            frame[2][indicator & LINK_ID_MASK] = time.time()
+            if indicator & LAST_PACKET_FLAG:
+                frame[1] = offset
+            if frame[1] is not None and len(frame[0]) == frame[1] + 1:  #end of packet
+                if args.calc_rtt:
+                    link_times = frame[2]
+                    deltas = [link_times.get(link, 0.0) - link_times.get(0, 0.0)
+                              for link in range(1, max(link_times) + 1)]
+                    pong_addr = (addr[0], pong_port)
+                    pong_sock.sendto(make_pong(_buffer, deltas), pong_addr)
+                received_record[seq] = time.time() - received_record[seq][0]
+                del frames[seq]
 
 
 def main(args):
@@ -125,10 +149,6 @@ def main(args):
         t.start()
         
         _buf = sock.recv(10240)
-        if args.calc_jitter:
-            # timestamp, init_seq, _, __ = extract( _buf )
-            timestamp, init_seq, _, __, ___ = extract( _buf )
-            received_record[init_seq] = ( timestamp, time.time() )
         init_time = time.time()
 
     # waiting for fixed duration / length